@@ -1,9 +1,10 @@
 #!/usr/bin/env python3
-import asyncio, json, ssl, time, websockets, random, statistics, sys
+import asyncio, json, os, ssl, time, websockets, random, statistics, sys
 
 # === Configuration ===
-TOKEN=""
-URL  = f"wss://olta-vm.load.network/ws/J1TjLzI8U8tXbd4SGm2AkdHUcnMSbwMNwgSXdJzkvpo?token={TOKEN}"
+# session token from a previous `Authenticated`, creating documents needs one
+SESSION = os.environ.get("OLTA_SESSION", "")
+URL  = "wss://olta-vm.load.network/ws/J1TjLzI8U8tXbd4SGm2AkdHUcnMSbwMNwgSXdJzkvpo"
 RUNS = 3
 COLL = "vertices"
 
@@ -22,12 +23,20 @@ def make_doc(i):
         "cameraZ": "10n",
     }
 
-async def send_once(ws, payload):
+async def reply_to(ws, request_id):
+    """Wait for the Ack, Error or Authenticated answering request_id."""
+    while True:
+        msg = json.loads(await ws.recv())
+        for kind in ("Ack", "Error", "Authenticated"):
+            if kind in msg and msg[kind].get("request_id") == request_id:
+                return kind, msg[kind]
+
+async def send_once(ws, payload, request_id):
     """Send one message and measure round-trip in microseconds."""
     t0 = time.perf_counter_ns()
-    await ws.send(json.dumps(payload))
+    await ws.send(json.dumps({**payload, "request_id": request_id}))
     try:
-        await asyncio.wait_for(ws.recv(), timeout=3)
+        await asyncio.wait_for(reply_to(ws, request_id), timeout=3)
     except asyncio.TimeoutError:
         pass
     t1 = time.perf_counter_ns()
@@ -40,10 +49,16 @@ async def main():
     latencies = []
 
     async with websockets.connect(URL, ssl=sslctx, ping_interval=None, max_size=None) as ws:
+        auth = {"Authenticate": {"credentials": {"Session": {"token": SESSION}}}, "request_id": "auth"}
+        await ws.send(json.dumps(auth))
+        kind, reply = await reply_to(ws, "auth")
+        if kind != "Authenticated":
+            sys.exit(f"authentication failed: {reply['message']}")
+
         for i in range(RUNS + 1):
             doc = make_doc(i)
             payload = {"CreateDocument": {"collection_name": COLL, "document": doc}}
-            rtt = await send_once(ws, payload)
+            rtt = await send_once(ws, payload, f"bench-{i}")
             if i > 0:
                 latencies.append(rtt)
                 avg = statistics.mean(latencies)
@@ -0,0 +1,394 @@
+use anyhow::{Error, anyhow, bail};
+use dotenvy::dotenv;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    Method, Request, Response, Uri,
+    body::{Bytes, Incoming},
+    header::{AUTHORIZATION, CONTENT_TYPE, HOST},
+};
+use hyper_util::rt::TokioIo;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use std::{env, fs, io::Read, sync::Arc};
+use storage::Database;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{
+        ClientConfig, RootCertStore,
+        crypto::ring,
+        pki_types::{CertificateDer, ServerName, pem::PemObject},
+    },
+};
+use vm::Lobby;
+
+const USAGE: &str = "usage: olta-admin <command> [args]
+
+commands on the database (DATABASE_URL):
+  list                             processes with size and last activity
+  dump <process_id> [file|-]       write a lobby as json, to <process_id>.json by default
+  import <file|-> [process_id] [--force]
+                                   store a dumped lobby, under another id if given
+  delete <process_id>
+  archive <process_id>             move a process to archived_processes
+  rename <process_id> <new_id>
+  fork <process_id> <new_id>       copy the last saved state under a new id
+
+commands on a running server (ADMIN_URL, ADMIN_TOKEN, ADMIN_CA_CERT for a private ca):
+  evict <process_id>               save a live lobby and unload it
+  reload <process_id>              drop a live lobby's unsaved changes and load it again
+  preview <process_id>             render and store a lobby's preview image
+
+with ADMIN_TOKEN set, import, delete, archive, rename and fork go through the server, which
+keeps the lobby unloaded meanwhile; without it a server with the lobby loaded may write it back";
+
+/// where the server's admin api listens when ADMIN_URL is unset
+const DEFAULT_ADMIN_URL: &str = "http://127.0.0.1:8080";
+
+/// what may stay unescaped in a path segment, the unreserved characters of RFC 3986
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+#[derive(Debug, PartialEq)]
+enum Command<'a> {
+    List,
+    Dump { process_id: &'a str, output: String },
+    Import { input: &'a str, process_id: Option<&'a str>, force: bool },
+    Delete { process_id: &'a str },
+    Archive { process_id: &'a str },
+    Rename { process_id: &'a str, new_id: &'a str },
+    Fork { process_id: &'a str, new_id: &'a str },
+    // evict, reload or preview
+    Live { action: &'a str, process_id: &'a str },
+}
+
+/// the command line after the program name, `--force` may come anywhere
+fn parse(args: &[String]) -> Result<Command<'_>, Error> {
+    let force = args.iter().any(|arg| arg == "--force");
+    let args: Vec<&str> = args.iter().map(String::as_str).filter(|arg| *arg != "--force").collect();
+
+    Ok(match args[..] {
+        ["list"] => Command::List,
+        ["dump", process_id] => Command::Dump { process_id, output: format!("{process_id}.json") },
+        ["dump", process_id, output] => Command::Dump { process_id, output: output.into() },
+        ["import", input] => Command::Import { input, process_id: None, force },
+        ["import", input, process_id] => {
+            Command::Import { input, process_id: Some(process_id), force }
+        }
+        ["delete", process_id] => Command::Delete { process_id },
+        ["archive", process_id] => Command::Archive { process_id },
+        ["rename", process_id, new_id] => Command::Rename { process_id, new_id },
+        ["fork", process_id, new_id] => Command::Fork { process_id, new_id },
+        [action @ ("evict" | "reload" | "preview"), process_id] => {
+            Command::Live { action, process_id }
+        }
+        _ => bail!(USAGE),
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    dotenv().ok();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    match parse(&args)? {
+        Command::List => list(&database().await?).await,
+        Command::Dump { process_id, output } => dump(process_id, &output).await,
+        Command::Import { input, process_id, force } => import(input, process_id, force).await,
+        Command::Delete { process_id } => {
+            if admin_configured(process_id) {
+                admin_request(Method::DELETE, &process_path(process_id, &[]), None).await?;
+            } else if !database().await?.delete_process(process_id).await? {
+                bail!("process {process_id} not found");
+            }
+            println!("deleted {process_id}");
+            Ok(())
+        }
+        Command::Archive { process_id } => {
+            if admin_configured(process_id) {
+                let path = process_path(process_id, &["archive"]);
+                admin_request(Method::POST, &path, None).await?;
+            } else if !database().await?.archive_process(process_id).await? {
+                bail!("process {process_id} not found");
+            }
+            println!("archived {process_id}");
+            Ok(())
+        }
+        Command::Rename { process_id, new_id } => {
+            if admin_configured(process_id) {
+                let path = process_path(process_id, &["rename", new_id]);
+                admin_request(Method::POST, &path, None).await?;
+            } else {
+                database().await?.rename_process(process_id, new_id).await?;
+            }
+            println!("renamed {process_id} to {new_id}");
+            Ok(())
+        }
+        Command::Fork { process_id, new_id } => {
+            if admin_configured(new_id) {
+                let path = process_path(process_id, &["fork", new_id]);
+                admin_request(Method::POST, &path, None).await?;
+            } else {
+                database().await?.fork_process(process_id, new_id).await?;
+            }
+            println!("forked {process_id} to {new_id}");
+            Ok(())
+        }
+        Command::Live { action, process_id } => {
+            let path = process_path(process_id, &[action]);
+            println!("{}", admin_request(Method::POST, &path, None).await?);
+            Ok(())
+        }
+    }
+}
+
+async fn database() -> Result<Database, Error> {
+    let database_url = env::var("DATABASE_URL").map_err(|_| anyhow!("DATABASE_URL is not set"))?;
+    let database = Database::new(&database_url).await?;
+    database.run_migrations().await?;
+    Ok(database)
+}
+
+async fn list(database: &Database) -> Result<(), Error> {
+    let processes = database.list_processes().await?;
+    let width = processes.iter().map(|p| p.process_id.len()).max().unwrap_or(0).max(10);
+    println!("{:<width$}  {:>10}  {:<3}  last activity", "process_id", "size", "hot");
+    for process in &processes {
+        let last_activity = process
+            .last_activity
+            .map(|at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_else(|| "-".into());
+        println!(
+            "{:<width$}  {:>10}  {:<3}  {}",
+            process.process_id,
+            process.size,
+            if process.is_hot { "yes" } else { "no" },
+            last_activity
+        );
+    }
+    eprintln!("{} processes", processes.len());
+    Ok(())
+}
+
+/// the lobby as stored, pretty printed, to a file or stdout for `-`
+async fn dump(process_id: &str, output: &str) -> Result<(), Error> {
+    let state = database()
+        .await?
+        .load_process_state(process_id)
+        .await?
+        .ok_or_else(|| anyhow!("process {process_id} not found"))?;
+    let state: serde_json::Value = serde_json::from_str(&state)?;
+    let json = serde_json::to_string_pretty(&state)?;
+    if output == "-" {
+        println!("{json}");
+    } else {
+        fs::write(output, &json)?;
+        println!("dumped {process_id} to {output} ({} bytes)", json.len());
+    }
+    Ok(())
+}
+
+/// store a dump, which has to read as a lobby; an existing process is only replaced with
+/// `force`
+async fn import(input: &str, process_id: Option<&str>, force: bool) -> Result<(), Error> {
+    let json = if input == "-" {
+        let mut json = String::new();
+        std::io::stdin().read_to_string(&mut json)?;
+        json
+    } else {
+        fs::read_to_string(input).map_err(|e| anyhow!("failed to read {input}: {e}"))?
+    };
+    let mut lobby: Lobby =
+        serde_json::from_str(&json).map_err(|e| anyhow!("{input} is not a lobby: {e}"))?;
+    if let Some(process_id) = process_id {
+        lobby.process_id = process_id.to_string();
+    }
+    if lobby.process_id.is_empty() {
+        bail!("{input} has no process_id, name one");
+    }
+    lobby.hot = false;
+    let process_id = lobby.process_id.clone();
+    let state = serde_json::to_string(&lobby)?;
+
+    if admin_configured(&process_id) {
+        let mut path = process_path(&process_id, &[]);
+        if force {
+            path.push_str("?force");
+        }
+        admin_request(Method::PUT, &path, Some(state)).await?;
+    } else {
+        let database = database().await?;
+        if !force && database.process_exists(&process_id).await? {
+            bail!("process {process_id} already exists, --force replaces it");
+        }
+        database.save_process_state(&process_id, &state, false).await?;
+    }
+    println!("imported {process_id} from {input}");
+    Ok(())
+}
+
+/// whether changes to stored processes go through the server, which keeps their lobbies
+/// unloaded meanwhile; warns when they do not
+fn admin_configured(process_id: &str) -> bool {
+    let configured = env::var("ADMIN_TOKEN").is_ok();
+    if !configured {
+        eprintln!("ADMIN_TOKEN is not set, a server with {process_id} loaded may write it back");
+    }
+    configured
+}
+
+/// `/admin/processes/<process_id>/<segments>`, each escaped
+fn process_path(process_id: &str, segments: &[&str]) -> String {
+    let mut path = String::from("/admin/processes");
+    for segment in std::iter::once(&process_id).chain(segments) {
+        path.push('/');
+        path.extend(utf8_percent_encode(segment, SEGMENT));
+    }
+    path
+}
+
+/// a request to the server's admin api at `path`, its json answer
+async fn admin_request(method: Method, path: &str, body: Option<String>) -> Result<String, Error> {
+    let token = env::var("ADMIN_TOKEN").map_err(|_| anyhow!("ADMIN_TOKEN is not set"))?;
+    let base = env::var("ADMIN_URL").unwrap_or_else(|_| DEFAULT_ADMIN_URL.into());
+    let uri: Uri = format!("{}{}", base.trim_end_matches('/'), path)
+        .parse()
+        .map_err(|e| anyhow!("invalid ADMIN_URL={base}: {e}"))?;
+    let https = match uri.scheme_str() {
+        Some("http") => false,
+        Some("https") => true,
+        _ => bail!("ADMIN_URL has to be an http:// or https:// url"),
+    };
+    let authority = uri.authority().ok_or_else(|| anyhow!("ADMIN_URL has no host"))?.clone();
+    let port = authority.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let address = format!("{}:{}", authority.host(), port);
+
+    let mut request = Request::builder()
+        .method(method.clone())
+        .uri(uri.path_and_query().map(|p| p.as_str()).unwrap_or("/"))
+        .header(HOST, authority.as_str())
+        .header(AUTHORIZATION, format!("Bearer {token}"));
+    if body.is_some() {
+        request = request.header(CONTENT_TYPE, "application/json");
+    }
+    let request = request.body(Full::new(Bytes::from(body.unwrap_or_default())))?;
+
+    let stream = TcpStream::connect(&address)
+        .await
+        .map_err(|e| anyhow!("failed to connect to {address}: {e}"))?;
+    let response = if https {
+        let host = ServerName::try_from(authority.host().to_string())
+            .map_err(|e| anyhow!("invalid ADMIN_URL host: {e}"))?;
+        let stream = tls_connector()?
+            .connect(host, stream)
+            .await
+            .map_err(|e| anyhow!("tls handshake with {address} failed: {e}"))?;
+        send(stream, request).await?
+    } else {
+        send(stream, request).await?
+    };
+
+    let status = response.status();
+    let body = response.into_body().collect().await?.to_bytes();
+    let body = String::from_utf8_lossy(&body).into_owned();
+    if !status.is_success() {
+        bail!("{method} {path} failed with {status}: {body}");
+    }
+    Ok(body)
+}
+
+async fn send<S>(stream: S, request: Request<Full<Bytes>>) -> Result<Response<Incoming>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+    Ok(sender.send_request(request).await?)
+}
+
+/// trusts the usual public roots, and the certificates of ADMIN_CA_CERT when set
+fn tls_connector() -> Result<TlsConnector, Error> {
+    let mut roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    if let Ok(path) = env::var("ADMIN_CA_CERT") {
+        let certs = CertificateDer::pem_file_iter(&path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| anyhow!("failed to read ADMIN_CA_CERT={path}: {e}"))?;
+        for cert in certs {
+            roots.add(cert)?;
+        }
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn commands_parse_with_their_arguments() {
+        assert_eq!(parse(&args(&["list"])).unwrap(), Command::List);
+        assert_eq!(
+            parse(&args(&["dump", "p1"])).unwrap(),
+            Command::Dump { process_id: "p1", output: "p1.json".into() }
+        );
+        assert_eq!(
+            parse(&args(&["dump", "p1", "-"])).unwrap(),
+            Command::Dump { process_id: "p1", output: "-".into() }
+        );
+        assert_eq!(
+            parse(&args(&["rename", "p1", "p2"])).unwrap(),
+            Command::Rename { process_id: "p1", new_id: "p2" }
+        );
+        assert_eq!(
+            parse(&args(&["fork", "p1", "p2"])).unwrap(),
+            Command::Fork { process_id: "p1", new_id: "p2" }
+        );
+        assert_eq!(
+            parse(&args(&["archive", "p1"])).unwrap(),
+            Command::Archive { process_id: "p1" }
+        );
+        assert_eq!(
+            parse(&args(&["reload", "p1"])).unwrap(),
+            Command::Live { action: "reload", process_id: "p1" }
+        );
+    }
+
+    #[test]
+    fn force_goes_anywhere() {
+        let import = Command::Import { input: "a.json", process_id: Some("p1"), force: true };
+        assert_eq!(parse(&args(&["import", "a.json", "p1", "--force"])).unwrap(), import);
+        assert_eq!(parse(&args(&["--force", "import", "a.json", "p1"])).unwrap(), import);
+        assert_eq!(
+            parse(&args(&["import", "-"])).unwrap(),
+            Command::Import { input: "-", process_id: None, force: false }
+        );
+    }
+
+    #[test]
+    fn unknown_commands_and_arities_show_the_usage() {
+        for given in [&[][..], &["nope"], &["delete"], &["delete", "a", "b"], &["rename", "a"]] {
+            assert_eq!(parse(&args(given)).unwrap_err().to_string(), USAGE, "{given:?}");
+        }
+    }
+
+    #[test]
+    fn process_ids_are_escaped_in_paths() {
+        assert_eq!(process_path("p1", &[]), "/admin/processes/p1");
+        assert_eq!(process_path("a/b", &["rename", "c d"]), "/admin/processes/a%2Fb/rename/c%20d");
+        assert_eq!(process_path("x?y#z", &["evict"]), "/admin/processes/x%3Fy%23z/evict");
+        assert_eq!(process_path("ok-._~é", &[]), "/admin/processes/ok-._~%C3%A9");
+        // a path of any id parses as a uri
+        let path = process_path("a b/%?#", &["fork", "..\\"]);
+        assert!(format!("http://localhost{path}").parse::<Uri>().is_ok());
+    }
+}
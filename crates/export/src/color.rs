@@ -0,0 +1,32 @@
+/// fallback for documents without a readable color
+pub const WHITE: [u8; 3] = [255, 255, 255];
+
+/// parse a document color, either a js number ("16711680n", "16711680") or a css hex string
+/// ("#ff0000", "#f00", "0xff0000")
+pub fn parse_color(value: &str) -> Option<[u8; 3]> {
+    let value = value.trim();
+
+    let hex = value.strip_prefix('#').or_else(|| value.strip_prefix("0x"));
+    let rgb = match hex {
+        Some(hex) if hex.len() == 3 => {
+            let short = u32::from_str_radix(hex, 16).ok()?;
+            let (r, g, b) = ((short >> 8) & 0xf, (short >> 4) & 0xf, short & 0xf);
+            ((r * 0x11) << 16) | ((g * 0x11) << 8) | (b * 0x11)
+        }
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => value.strip_suffix('n').unwrap_or(value).parse::<u32>().ok()?,
+    };
+    if rgb > 0xff_ff_ff {
+        return None;
+    }
+
+    Some([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8])
+}
+
+/// linear 0..1 channels, as glTF expects
+pub fn to_linear(color: [u8; 3]) -> [f32; 3] {
+    color.map(|c| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    })
+}
@@ -0,0 +1,496 @@
+//! glTF 2.0 binary (GLB) export of a lobby's cubes and vertex lines, splashes are flat overlays
+//! and are left out
+use crate::{
+    color::to_linear,
+    scene::{Scene, SceneCube},
+};
+use anyhow::{Error, anyhow};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use vm::Lobby;
+
+const GLB_MAGIC: u32 = 0x4654_6C67;
+const GLB_VERSION: u32 = 2;
+const CHUNK_JSON: u32 = 0x4E4F_534A;
+const CHUNK_BIN: u32 = 0x004E_4942;
+
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FLOAT: u32 = 5126;
+const UNSIGNED_SHORT: u32 = 5123;
+
+const MODE_POINTS: u32 = 0;
+const MODE_LINES: u32 = 1;
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    /// edge length of a cube in scene units
+    pub cube_size: f32,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self { cube_size: 1.0 }
+    }
+}
+
+/// export a lobby as a GLB file
+pub fn lobby_to_glb(lobby: &Lobby) -> Result<Vec<u8>, Error> {
+    scene_to_glb(&Scene::from_lobby(lobby), &ExportOptions::default())
+}
+
+pub fn scene_to_glb(scene: &Scene, options: &ExportOptions) -> Result<Vec<u8>, Error> {
+    let mut builder = Builder::default();
+    let mut nodes = Vec::new();
+
+    if !scene.cubes.is_empty() {
+        let cube = builder.cube_geometry(options.cube_size);
+        // one mesh per distinct color, shared by every cube of that color
+        let mut meshes: BTreeMap<[u8; 3], usize> = BTreeMap::new();
+
+        for SceneCube { id, position, rotation, color } in &scene.cubes {
+            let mesh = *meshes.entry(*color).or_insert_with(|| {
+                let material = builder.material(*color);
+                builder.mesh(json!({
+                    "primitives": [{
+                        "attributes": { "POSITION": cube.positions, "NORMAL": cube.normals },
+                        "indices": cube.indices,
+                        "material": material,
+                    }],
+                }))
+            });
+
+            nodes.push(builder.node(json!({
+                "name": format!("cubes/{id}"),
+                "mesh": mesh,
+                "translation": position,
+                "rotation": quaternion(*rotation),
+            })));
+        }
+    }
+
+    if !scene.vertices.is_empty() {
+        let mut primitives = Vec::new();
+
+        // each segment takes the line color of the vertex it starts from
+        let (mut line_positions, mut line_colors) = (Vec::new(), Vec::new());
+        for pair in scene.vertices.windows(2) {
+            let color = to_linear(pair[0].line_color);
+            line_positions.extend([pair[0].position, pair[1].position]);
+            line_colors.extend([color, color]);
+        }
+        if !line_positions.is_empty() {
+            primitives.push(json!({
+                "attributes": {
+                    "POSITION": builder.vec3_accessor(&line_positions, true),
+                    "COLOR_0": builder.vec3_accessor(&line_colors, false),
+                },
+                "mode": MODE_LINES,
+            }));
+        }
+
+        let points: Vec<[f32; 3]> = scene.vertices.iter().map(|v| v.position).collect();
+        let point_colors: Vec<[f32; 3]> =
+            scene.vertices.iter().map(|v| to_linear(v.vertex_color)).collect();
+        primitives.push(json!({
+            "attributes": {
+                "POSITION": builder.vec3_accessor(&points, true),
+                "COLOR_0": builder.vec3_accessor(&point_colors, false),
+            },
+            "mode": MODE_POINTS,
+        }));
+
+        let mesh = builder.mesh(json!({ "primitives": primitives }));
+        nodes.push(builder.node(json!({ "name": "vertices", "mesh": mesh })));
+    }
+
+    builder.finish(nodes)
+}
+
+/// euler XYZ radians to a unit quaternion [x, y, z, w]
+fn quaternion(rotation: [f32; 3]) -> [f32; 4] {
+    let (s1, c1) = (rotation[0] / 2.0).sin_cos();
+    let (s2, c2) = (rotation[1] / 2.0).sin_cos();
+    let (s3, c3) = (rotation[2] / 2.0).sin_cos();
+
+    [
+        s1 * c2 * c3 + c1 * s2 * s3,
+        c1 * s2 * c3 - s1 * c2 * s3,
+        c1 * c2 * s3 + s1 * s2 * c3,
+        c1 * c2 * c3 - s1 * s2 * s3,
+    ]
+}
+
+/// accessor indices of the shared cube geometry
+#[derive(Clone, Copy)]
+struct CubeGeometry {
+    positions: usize,
+    normals: usize,
+    indices: usize,
+}
+
+#[derive(Default)]
+struct Builder {
+    bin: Vec<u8>,
+    buffer_views: Vec<Value>,
+    accessors: Vec<Value>,
+    materials: Vec<Value>,
+    meshes: Vec<Value>,
+    nodes: Vec<Value>,
+}
+
+impl Builder {
+    fn buffer_view(&mut self, bytes: &[u8], target: u32) -> usize {
+        // every accessor here is 4-byte aligned or u16 indices, pad to 4 either way
+        while self.bin.len() % 4 != 0 {
+            self.bin.push(0);
+        }
+        self.buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": self.bin.len(),
+            "byteLength": bytes.len(),
+            "target": target,
+        }));
+        self.bin.extend_from_slice(bytes);
+        self.buffer_views.len() - 1
+    }
+
+    fn vec3_accessor(&mut self, data: &[[f32; 3]], bounds: bool) -> usize {
+        let bytes: Vec<u8> = data.iter().flatten().flat_map(|v| v.to_le_bytes()).collect();
+        let view = self.buffer_view(&bytes, ARRAY_BUFFER);
+
+        let mut accessor = json!({
+            "bufferView": view,
+            "componentType": FLOAT,
+            "count": data.len(),
+            "type": "VEC3",
+        });
+        // required for POSITION attributes
+        if bounds {
+            let mut min = [f32::MAX; 3];
+            let mut max = [f32::MIN; 3];
+            for v in data {
+                for i in 0..3 {
+                    min[i] = min[i].min(v[i]);
+                    max[i] = max[i].max(v[i]);
+                }
+            }
+            accessor["min"] = json!(min);
+            accessor["max"] = json!(max);
+        }
+
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    fn index_accessor(&mut self, data: &[u16]) -> usize {
+        let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let view = self.buffer_view(&bytes, ELEMENT_ARRAY_BUFFER);
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": UNSIGNED_SHORT,
+            "count": data.len(),
+            "type": "SCALAR",
+        }));
+        self.accessors.len() - 1
+    }
+
+    fn cube_geometry(&mut self, size: f32) -> CubeGeometry {
+        let h = size / 2.0;
+        // (normal, two in-plane axes) per face, four corners each so normals stay flat
+        let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+            ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+            ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+            ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]),
+            ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+            ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0]),
+        ];
+
+        let (mut positions, mut normals, mut indices) = (Vec::new(), Vec::new(), Vec::new());
+        for (normal, u, v) in faces {
+            let base = positions.len() as u16;
+            for (su, sv) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                positions.push([0, 1, 2].map(|i| (normal[i] + su * u[i] + sv * v[i]) * h));
+                normals.push(normal);
+            }
+            indices.extend([base, base + 1, base + 2, base, base + 2, base + 3]);
+        }
+
+        CubeGeometry {
+            positions: self.vec3_accessor(&positions, true),
+            normals: self.vec3_accessor(&normals, false),
+            indices: self.index_accessor(&indices),
+        }
+    }
+
+    fn material(&mut self, color: [u8; 3]) -> usize {
+        let [r, g, b] = to_linear(color);
+        self.materials.push(json!({
+            "name": format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2]),
+            "pbrMetallicRoughness": {
+                "baseColorFactor": [r, g, b, 1.0],
+                "metallicFactor": 0.0,
+                "roughnessFactor": 1.0,
+            },
+        }));
+        self.materials.len() - 1
+    }
+
+    fn mesh(&mut self, mesh: Value) -> usize {
+        self.meshes.push(mesh);
+        self.meshes.len() - 1
+    }
+
+    fn node(&mut self, node: Value) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn finish(mut self, scene_nodes: Vec<usize>) -> Result<Vec<u8>, Error> {
+        while self.bin.len() % 4 != 0 {
+            self.bin.push(0);
+        }
+
+        let mut document = json!({ "asset": { "version": "2.0", "generator": "olta-vm" } });
+        // glTF arrays must not be empty when present, an empty lobby has no scene at all
+        if !scene_nodes.is_empty() {
+            document["scene"] = json!(0);
+            document["scenes"] = json!([{ "nodes": scene_nodes }]);
+        }
+        for (key, items) in [
+            ("nodes", self.nodes),
+            ("meshes", self.meshes),
+            ("materials", self.materials),
+            ("accessors", self.accessors),
+            ("bufferViews", self.buffer_views),
+        ] {
+            if !items.is_empty() {
+                document[key] = Value::Array(items);
+            }
+        }
+        if !self.bin.is_empty() {
+            document["buffers"] = json!([{ "byteLength": self.bin.len() }]);
+        }
+
+        let mut json_chunk = serde_json::to_vec(&document)?;
+        while json_chunk.len() % 4 != 0 {
+            json_chunk.push(b' ');
+        }
+
+        let mut total = 12 + 8 + json_chunk.len();
+        if !self.bin.is_empty() {
+            total += 8 + self.bin.len();
+        }
+        let total = u32::try_from(total).map_err(|_| anyhow!("scene too large for GLB"))?;
+
+        let mut glb = Vec::with_capacity(total as usize);
+        glb.extend(GLB_MAGIC.to_le_bytes());
+        glb.extend(GLB_VERSION.to_le_bytes());
+        glb.extend(total.to_le_bytes());
+        glb.extend((json_chunk.len() as u32).to_le_bytes());
+        glb.extend(CHUNK_JSON.to_le_bytes());
+        glb.extend(json_chunk);
+        if !self.bin.is_empty() {
+            glb.extend((self.bin.len() as u32).to_le_bytes());
+            glb.extend(CHUNK_BIN.to_le_bytes());
+            glb.extend(self.bin);
+        }
+        Ok(glb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::SceneVertex;
+    use std::f32::consts::FRAC_PI_2;
+    use vm::transform::{apply, from_euler};
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// the JSON document and BIN chunk of a GLB, checking the framing on the way
+    fn parse(glb: &[u8]) -> (Value, Vec<u8>) {
+        assert_eq!(&glb[..4], b"glTF");
+        assert_eq!(u32_at(glb, 4), 2);
+        assert_eq!(u32_at(glb, 8) as usize, glb.len());
+
+        let json_len = u32_at(glb, 12) as usize;
+        assert_eq!(u32_at(glb, 16), CHUNK_JSON);
+        assert_eq!(json_len % 4, 0);
+        let json = &glb[20..20 + json_len];
+        let document: Value = serde_json::from_slice(json).unwrap();
+        let end = json.iter().rposition(|b| *b != b' ').unwrap() + 1;
+        assert!(json_len - end < 4 && json[end..].iter().all(|b| *b == b' '));
+
+        let rest = &glb[20 + json_len..];
+        if rest.is_empty() {
+            return (document, Vec::new());
+        }
+        let bin_len = u32_at(rest, 0) as usize;
+        assert_eq!(u32_at(rest, 4), CHUNK_BIN);
+        assert_eq!(bin_len % 4, 0);
+        assert_eq!(rest.len(), 8 + bin_len);
+        assert_eq!(document["buffers"][0]["byteLength"], bin_len);
+        (document, rest[8..].to_vec())
+    }
+
+    fn scene() -> Scene {
+        let vertex = |id, position| SceneVertex {
+            id,
+            position,
+            line_color: [255, 0, 0],
+            vertex_color: [0, 0, 255],
+            camera: None,
+        };
+        Scene {
+            cubes: vec![
+                SceneCube { id: 1, position: [1.0, 2.0, 3.0], rotation: [0.0; 3], color: [255; 3] },
+                SceneCube {
+                    id: 2,
+                    position: [-4.0, 0.5, 0.0],
+                    rotation: [0.3; 3],
+                    color: [255; 3],
+                },
+                SceneCube { id: 3, position: [0.0; 3], rotation: [0.0; 3], color: [10, 20, 30] },
+            ],
+            vertices: vec![
+                vertex(4, [0.0, 0.0, 0.0]),
+                vertex(5, [2.0, -1.0, 7.5]),
+                vertex(6, [-3.0, 4.0, 1.0]),
+            ],
+            splashes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn header_and_chunks_are_framed() {
+        let glb = scene_to_glb(&scene(), &ExportOptions::default()).unwrap();
+        let (document, bin) = parse(&glb);
+        assert_eq!(document["asset"]["version"], "2.0");
+        assert_eq!(document["scenes"][0]["nodes"].as_array().unwrap().len(), 4);
+        // cubes of the same color share a mesh
+        assert_eq!(document["meshes"].as_array().unwrap().len(), 3);
+        assert_eq!(document["materials"].as_array().unwrap().len(), 2);
+
+        // padding between and after buffer views is zeros
+        let mut covered = vec![false; bin.len()];
+        for view in document["bufferViews"].as_array().unwrap() {
+            let offset = view["byteOffset"].as_u64().unwrap() as usize;
+            assert_eq!(offset % 4, 0);
+            let length = view["byteLength"].as_u64().unwrap() as usize;
+            covered[offset..offset + length].iter_mut().for_each(|c| *c = true);
+        }
+        assert!(bin.iter().zip(&covered).all(|(byte, covered)| *covered || *byte == 0));
+    }
+
+    #[test]
+    fn accessors_describe_their_data() {
+        let glb = scene_to_glb(&scene(), &ExportOptions::default()).unwrap();
+        let (document, bin) = parse(&glb);
+
+        for accessor in document["accessors"].as_array().unwrap() {
+            let view = &document["bufferViews"][accessor["bufferView"].as_u64().unwrap() as usize];
+            let offset = view["byteOffset"].as_u64().unwrap() as usize;
+            let bytes = &bin[offset..offset + view["byteLength"].as_u64().unwrap() as usize];
+            let count = accessor["count"].as_u64().unwrap() as usize;
+
+            match accessor["componentType"].as_u64().unwrap() as u32 {
+                FLOAT => {
+                    assert_eq!(accessor["type"], "VEC3");
+                    assert_eq!(bytes.len(), count * 12);
+                    let values: Vec<f32> = bytes
+                        .chunks(4)
+                        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                        .collect();
+                    if accessor.get("min").is_some() {
+                        for axis in 0..3 {
+                            let axis_values = values.iter().skip(axis).step_by(3);
+                            let min = axis_values.clone().fold(f32::MAX, |a, b| a.min(*b));
+                            let max = axis_values.fold(f32::MIN, |a, b| a.max(*b));
+                            assert_eq!(accessor["min"][axis].as_f64().unwrap(), min as f64);
+                            assert_eq!(accessor["max"][axis].as_f64().unwrap(), max as f64);
+                        }
+                    }
+                }
+                UNSIGNED_SHORT => {
+                    assert_eq!(accessor["type"], "SCALAR");
+                    assert_eq!(bytes.len(), count * 2);
+                }
+                other => panic!("unexpected component type {other}"),
+            }
+        }
+
+        // every POSITION attribute carries its bounds
+        for mesh in document["meshes"].as_array().unwrap() {
+            for primitive in mesh["primitives"].as_array().unwrap() {
+                let position = primitive["attributes"]["POSITION"].as_u64().unwrap() as usize;
+                assert!(document["accessors"][position]["min"].is_array());
+            }
+        }
+    }
+
+    #[test]
+    fn huge_positions_keep_finite_bounds() {
+        let mut scene = scene();
+        scene.vertices[1].position = [f32::MAX, f32::MIN, 0.0];
+        let glb = scene_to_glb(&scene, &ExportOptions::default()).unwrap();
+        let (document, _) = parse(&glb);
+        for accessor in document["accessors"].as_array().unwrap() {
+            for bound in ["min", "max"] {
+                if let Some(values) = accessor[bound].as_array() {
+                    assert!(values.iter().all(Value::is_f64), "{bound} {values:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_lobbies_export_a_valid_file() {
+        let glb = lobby_to_glb(&Lobby::new("empty")).unwrap();
+        let (document, bin) = parse(&glb);
+        assert!(bin.is_empty());
+        assert!(document.get("scenes").is_none() && document.get("buffers").is_none());
+        assert_eq!(document["asset"]["version"], "2.0");
+    }
+
+    /// `v` rotated by the unit quaternion `q`
+    fn rotate(q: [f32; 4], v: [f32; 3]) -> [f32; 3] {
+        let [x, y, z, w] = q;
+        // t = 2 (q.xyz x v), v' = v + w t + q.xyz x t
+        let t =
+            [2.0 * (y * v[2] - z * v[1]), 2.0 * (z * v[0] - x * v[2]), 2.0 * (x * v[1] - y * v[0])];
+        [
+            v[0] + w * t[0] + (y * t[2] - z * t[1]),
+            v[1] + w * t[1] + (z * t[0] - x * t[2]),
+            v[2] + w * t[2] + (x * t[1] - y * t[0]),
+        ]
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-5), "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn quarter_turns_rotate_axes() {
+        assert_close(rotate(quaternion([FRAC_PI_2, 0.0, 0.0]), [0.0, 1.0, 0.0]), [0.0, 0.0, 1.0]);
+        assert_close(rotate(quaternion([0.0, FRAC_PI_2, 0.0]), [0.0, 0.0, 1.0]), [1.0, 0.0, 0.0]);
+        assert_close(rotate(quaternion([0.0, 0.0, FRAC_PI_2]), [1.0, 0.0, 0.0]), [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn quaternions_match_the_vm_rotation() {
+        for angles in [[0.3, -0.4, 1.2], [0.0, 0.0, 0.0], [-2.5, 1.0, 3.0], [1.0, -1.5, -0.2]] {
+            let q = quaternion(angles);
+            assert!((q.iter().map(|c| c * c).sum::<f32>() - 1.0).abs() < 1e-5);
+
+            let m = from_euler(angles.map(f64::from));
+            for v in [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [0.5, -2.0, 3.0]] {
+                let expected = apply(&m, v.map(f64::from)).map(|c| c as f32);
+                assert_close(rotate(q, v), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,12 @@
+pub mod color;
+pub mod gltf;
+pub mod png;
+pub mod render;
+pub mod scene;
+pub mod svg;
+
+pub use gltf::lobby_to_glb;
+pub use png::lobby_to_png;
+pub use render::RenderOptions;
+pub use scene::Scene;
+pub use svg::lobby_to_svg;
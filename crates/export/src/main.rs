@@ -0,0 +1,38 @@
+use anyhow::{Error, anyhow};
+use dotenvy::dotenv;
+use export::{RenderOptions, lobby_to_glb, lobby_to_png, lobby_to_svg};
+use std::{env, path::Path};
+use storage::Database;
+use vm::Lobby;
+
+const USAGE: &str = "usage: olta-export <process_id> [output.glb|output.svg|output.png]";
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    dotenv().ok();
+
+    let mut args = env::args().skip(1);
+    let process_id = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    let output = args.next().unwrap_or_else(|| format!("{process_id}.glb"));
+
+    let database_url = env::var("DATABASE_URL")?;
+    let database = Database::new(&database_url).await?;
+
+    let state = database
+        .load_process_state(&process_id)
+        .await?
+        .ok_or_else(|| anyhow!("process {process_id} not found"))?;
+    let lobby: Lobby = serde_json::from_str(&state)?;
+
+    // format follows the output extension
+    let bytes = match Path::new(&output).extension().and_then(|e| e.to_str()) {
+        Some("glb") => lobby_to_glb(&lobby)?,
+        Some("svg") => lobby_to_svg(&lobby, &RenderOptions::default()).into_bytes(),
+        Some("png") => lobby_to_png(&lobby, &RenderOptions::default())?,
+        _ => return Err(anyhow!(USAGE)),
+    };
+    std::fs::write(&output, &bytes)?;
+    println!("exported {process_id} to {output} ({} bytes)", bytes.len());
+
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use crate::{
+    render::{Frame, RenderOptions, Shape, blob_curve, layout},
+    scene::Scene,
+};
+use anyhow::{Error, anyhow};
+use tiny_skia::{
+    Color, FillRule, LineCap, Paint, PathBuilder, Pixmap, Stroke, Transform as Affine,
+};
+use vm::Lobby;
+
+/// render a lobby's current state as a png image
+pub fn lobby_to_png(lobby: &Lobby, options: &RenderOptions) -> Result<Vec<u8>, Error> {
+    frame_to_png(&layout(&Scene::from_lobby(lobby), options))
+}
+
+pub fn frame_to_png(frame: &Frame) -> Result<Vec<u8>, Error> {
+    let mut pixmap = Pixmap::new(frame.width, frame.height)
+        .ok_or_else(|| anyhow!("invalid image size {}x{}", frame.width, frame.height))?;
+    let [r, g, b] = frame.background;
+    pixmap.fill(Color::from_rgba8(r, g, b, 255));
+
+    for shape in &frame.shapes {
+        match shape {
+            Shape::Polygon { points, fill, opacity } => {
+                let mut path = PathBuilder::new();
+                for (i, [x, y]) in points.iter().enumerate() {
+                    if i == 0 { path.move_to(*x, *y) } else { path.line_to(*x, *y) }
+                }
+                path.close();
+                if let Some(path) = path.finish() {
+                    let paint = paint(*fill, *opacity);
+                    pixmap.fill_path(&path, &paint, FillRule::Winding, Affine::identity(), None);
+                }
+            }
+            Shape::Blob { points, fill, opacity } => {
+                let Some((start, segments)) = blob_curve(points) else {
+                    continue;
+                };
+                let mut path = PathBuilder::new();
+                path.move_to(start[0], start[1]);
+                for (control, end) in segments {
+                    path.quad_to(control[0], control[1], end[0], end[1]);
+                }
+                path.close();
+                if let Some(path) = path.finish() {
+                    let paint = paint(*fill, *opacity);
+                    pixmap.fill_path(&path, &paint, FillRule::Winding, Affine::identity(), None);
+                }
+            }
+            Shape::Line { from, to, color, width } => {
+                let mut path = PathBuilder::new();
+                path.move_to(from[0], from[1]);
+                path.line_to(to[0], to[1]);
+                if let Some(path) = path.finish() {
+                    let stroke =
+                        Stroke { width: *width, line_cap: LineCap::Round, ..Stroke::default() };
+                    pixmap.stroke_path(
+                        &path,
+                        &paint(*color, 1.0),
+                        &stroke,
+                        Affine::identity(),
+                        None,
+                    );
+                }
+            }
+            Shape::Dot { center, radius, color } => {
+                if let Some(path) = PathBuilder::from_circle(center[0], center[1], *radius) {
+                    let paint = paint(*color, 1.0);
+                    pixmap.fill_path(&path, &paint, FillRule::Winding, Affine::identity(), None);
+                }
+            }
+        }
+    }
+
+    pixmap.encode_png().map_err(|e| anyhow!("failed to encode png: {e}"))
+}
+
+fn paint(color: [u8; 3], opacity: f32) -> Paint<'static> {
+    let mut paint = Paint::default();
+    let alpha = (opacity.clamp(0.0, 1.0) * 255.0).round() as u8;
+    paint.set_color_rgba8(color[0], color[1], color[2], alpha);
+    paint.anti_alias = true;
+    paint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    #[test]
+    fn empty_lobbies_render_a_png() {
+        let png = lobby_to_png(&Lobby::new("empty"), &RenderOptions::default()).unwrap();
+        assert!(png.starts_with(SIGNATURE));
+    }
+
+    #[test]
+    fn empty_images_are_an_error() {
+        let frame = Frame { width: 0, height: 10, background: [0; 3], shapes: Vec::new() };
+        assert_eq!(frame_to_png(&frame).unwrap_err().to_string(), "invalid image size 0x10");
+    }
+}
@@ -0,0 +1,420 @@
+//! projection of a scene into flat, back-to-front shapes that the svg and png backends draw
+use crate::scene::{Scene, SceneSplash};
+use vm::transform::{Vec3, apply, from_euler};
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub width: u32,
+    pub height: u32,
+    pub background: [u8; 3],
+    /// edge length of a cube in scene units
+    pub cube_size: f32,
+    /// vertical field of view in degrees
+    pub fov: f32,
+    /// canvas size splash coordinates were placed in, splashes are scaled from it
+    pub splash_canvas: [f32; 2],
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            width: 1200,
+            height: 630,
+            background: [255, 255, 255],
+            cube_size: 1.0,
+            fov: 50.0,
+            splash_canvas: [1920.0, 1080.0],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Shape {
+    Polygon {
+        points: Vec<[f32; 2]>,
+        fill: [u8; 3],
+        opacity: f32,
+    },
+    /// closed smooth outline through the midpoints of `points`
+    Blob {
+        points: Vec<[f32; 2]>,
+        fill: [u8; 3],
+        opacity: f32,
+    },
+    Line {
+        from: [f32; 2],
+        to: [f32; 2],
+        color: [u8; 3],
+        width: f32,
+    },
+    Dot {
+        center: [f32; 2],
+        radius: f32,
+        color: [u8; 3],
+    },
+}
+
+/// everything to draw, in painting order
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub background: [u8; 3],
+    pub shapes: Vec<Shape>,
+}
+
+/// cube faces as corner indices wound outwards, corner bits 0, 1 and 2 select the +x, +y and +z
+/// side
+const FACES: [[usize; 4]; 6] =
+    [[1, 3, 7, 5], [0, 4, 6, 2], [2, 6, 7, 3], [0, 1, 5, 4], [4, 5, 7, 6], [0, 2, 3, 1]];
+const LIGHT: Vec3 = [0.4, 0.8, 0.45];
+
+struct Camera {
+    eye: Vec3,
+    right: Vec3,
+    up: Vec3,
+    forward: Vec3,
+    focal: f64,
+    center: [f64; 2],
+}
+
+impl Camera {
+    /// looks from the last stored vertex camera (or from the front when none was stored) at
+    /// the middle of the scene
+    fn new(scene: &Scene, options: &RenderOptions) -> Self {
+        let points: Vec<Vec3> = scene
+            .cubes
+            .iter()
+            .map(|c| c.position)
+            .chain(scene.vertices.iter().map(|v| v.position))
+            .map(|p| p.map(f64::from))
+            .collect();
+
+        let (min, max) = bounds(&points);
+        let target = [0, 1, 2].map(|i| (min[i] + max[i]) / 2.0);
+        let extent = length(sub(max, min)).max(f64::from(options.cube_size) * 4.0);
+
+        let stored = scene.vertices.iter().rev().find_map(|v| v.camera).map(|c| c.map(f64::from));
+        let mut eye = stored.unwrap_or([target[0], target[1], target[2] + extent * 1.5]);
+        if length(sub(target, eye)) < 1e-6 {
+            eye[2] += extent * 1.5;
+        }
+
+        let forward = normalize(sub(target, eye));
+        let world_up = if cross(forward, [0.0, 1.0, 0.0]) == [0.0; 3] {
+            [0.0, 0.0, 1.0]
+        } else {
+            [0.0, 1.0, 0.0]
+        };
+        let right = normalize(cross(forward, world_up));
+        let up = cross(right, forward);
+
+        let half_height = f64::from(options.height) / 2.0;
+        Self {
+            eye,
+            right,
+            up,
+            forward,
+            focal: half_height / (f64::from(options.fov).to_radians() / 2.0).tan(),
+            center: [f64::from(options.width) / 2.0, half_height],
+        }
+    }
+
+    /// screen position and depth, `None` behind the camera
+    fn project(&self, point: Vec3) -> Option<([f32; 2], f64)> {
+        let d = sub(point, self.eye);
+        let depth = dot(d, self.forward);
+        if depth <= 1e-3 {
+            return None;
+        }
+        let x = self.center[0] + dot(d, self.right) / depth * self.focal;
+        let y = self.center[1] - dot(d, self.up) / depth * self.focal;
+        Some(([x as f32, y as f32], depth))
+    }
+}
+
+pub fn layout(scene: &Scene, options: &RenderOptions) -> Frame {
+    let camera = Camera::new(scene, options);
+    let mut shapes: Vec<Shape> = scene.splashes.iter().map(|s| splash(s, options)).collect();
+
+    // 3d shapes are sorted far to near (painter's algorithm)
+    let mut depth_sorted: Vec<(f64, Shape)> = Vec::new();
+
+    let half = f64::from(options.cube_size) / 2.0;
+    for cube in &scene.cubes {
+        let rotation = from_euler(cube.rotation.map(f64::from));
+        let position = cube.position.map(f64::from);
+        let corners: Vec<Vec3> = (0..8)
+            .map(|i| {
+                let local = [0, 1, 2].map(|axis| if (i >> axis) & 1 == 1 { half } else { -half });
+                add(apply(&rotation, local), position)
+            })
+            .collect();
+
+        for face in FACES {
+            let world = face.map(|i| corners[i]);
+            let normal = normalize(cross(sub(world[1], world[0]), sub(world[2], world[0])));
+            let middle = world.iter().fold([0.0; 3], |acc, p| add(acc, p.map(|v| v / 4.0)));
+            // back faces point away from the camera
+            if dot(normal, sub(camera.eye, middle)) <= 0.0 {
+                continue;
+            }
+            let Some(projected) =
+                world.iter().map(|p| camera.project(*p)).collect::<Option<Vec<_>>>()
+            else {
+                continue;
+            };
+
+            let depth = projected.iter().map(|(_, d)| d).sum::<f64>() / 4.0;
+            let light = 0.55 + 0.45 * dot(normal, normalize(LIGHT)).max(0.0);
+            depth_sorted.push((
+                depth,
+                Shape::Polygon {
+                    points: projected.into_iter().map(|(p, _)| p).collect(),
+                    fill: cube.color.map(|c| (f64::from(c) * light).min(255.0) as u8),
+                    opacity: 1.0,
+                },
+            ));
+        }
+    }
+
+    let projected: Vec<_> =
+        scene.vertices.iter().map(|v| camera.project(v.position.map(f64::from))).collect();
+    for (pair, vertices) in projected.windows(2).zip(scene.vertices.windows(2)) {
+        if let (Some((from, d0)), Some((to, d1))) = (pair[0], pair[1]) {
+            let color = vertices[0].line_color;
+            depth_sorted.push(((d0 + d1) / 2.0, Shape::Line { from, to, color, width: 2.0 }));
+        }
+    }
+    for (point, vertex) in projected.iter().zip(&scene.vertices) {
+        if let Some((center, depth)) = point {
+            let color = vertex.vertex_color;
+            depth_sorted.push((*depth, Shape::Dot { center: *center, radius: 3.0, color }));
+        }
+    }
+
+    depth_sorted.sort_by(|a, b| b.0.total_cmp(&a.0));
+    shapes.extend(depth_sorted.into_iter().map(|(_, shape)| shape));
+
+    Frame { width: options.width, height: options.height, background: options.background, shapes }
+}
+
+/// (control, end) points of a quadratic curve
+pub type QuadSegment = ([f32; 2], [f32; 2]);
+
+/// start point and quadratic segments of a blob outline, which runs through the
+/// midpoints of consecutive points and is closed
+pub fn blob_curve(points: &[[f32; 2]]) -> Option<([f32; 2], Vec<QuadSegment>)> {
+    let n = points.len();
+    if n < 3 {
+        return None;
+    }
+    let mid = |i: usize| {
+        let (a, b) = (points[i % n], points[(i + 1) % n]);
+        [(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0]
+    };
+    Some((mid(0), (1..=n).map(|i| (points[i % n], mid(i))).collect()))
+}
+
+/// a deterministic blob derived from the splash seed
+fn splash(splash: &SceneSplash, options: &RenderOptions) -> Shape {
+    let mut rng = SeedRng::new(&splash.seed);
+    let scale = [
+        options.width as f32 / options.splash_canvas[0],
+        options.height as f32 / options.splash_canvas[1],
+    ];
+    let center = [splash.position[0] * scale[0], splash.position[1] * scale[1]];
+    let radius = (30.0 + rng.next() * 90.0) * scale[0].min(scale[1]);
+
+    let count = 7 + (rng.next() * 6.0) as usize;
+    let points = (0..count)
+        .map(|i| {
+            let angle = i as f32 / count as f32 * std::f32::consts::TAU;
+            let r = radius * (0.6 + rng.next() * 0.4);
+            [center[0] + angle.cos() * r, center[1] + angle.sin() * r]
+        })
+        .collect();
+
+    Shape::Blob { points, fill: hsl_to_rgb(rng.next(), 0.65, 0.6), opacity: 0.85 }
+}
+
+/// xorshift generator seeded with an FNV-1a hash of the seed string
+struct SeedRng(u64);
+
+impl SeedRng {
+    fn new(seed: &str) -> Self {
+        let hash = seed.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+            (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        });
+        Self(hash.max(1))
+    }
+
+    /// uniform in [0, 1)
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+fn hsl_to_rgb(h: f32, s: f32, l: f32) -> [u8; 3] {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - ((h * 6.0) % 2.0 - 1.0).abs());
+    let m = l - c / 2.0;
+    let (r, g, b) = match (h * 6.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    [r, g, b].map(|v| ((v + m) * 255.0).round() as u8)
+}
+
+fn bounds(points: &[Vec3]) -> (Vec3, Vec3) {
+    if points.is_empty() {
+        return ([0.0; 3], [0.0; 3]);
+    }
+    let mut min = [f64::MAX; 3];
+    let mut max = [f64::MIN; 3];
+    for p in points {
+        for i in 0..3 {
+            min[i] = min[i].min(p[i]);
+            max[i] = max[i].max(p[i]);
+        }
+    }
+    (min, max)
+}
+
+fn add(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: Vec3, b: Vec3) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn length(a: Vec3) -> f64 {
+    dot(a, a).sqrt()
+}
+
+fn normalize(a: Vec3) -> Vec3 {
+    let len = length(a);
+    if len == 0.0 { a } else { a.map(|v| v / len) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::{SceneCube, SceneVertex};
+
+    fn cube(id: u64, position: [f32; 3], color: [u8; 3]) -> SceneCube {
+        SceneCube { id, position, rotation: [0.0; 3], color }
+    }
+
+    fn polygons(frame: &Frame) -> Vec<[u8; 3]> {
+        frame
+            .shapes
+            .iter()
+            .filter_map(|shape| match shape {
+                Shape::Polygon { fill, .. } => Some(*fill),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn back_faces_are_culled() {
+        // from the front only the front face shows
+        let scene = Scene { cubes: vec![cube(1, [0.0; 3], [200; 3])], ..Scene::default() };
+        assert_eq!(polygons(&layout(&scene, &RenderOptions::default())).len(), 1);
+
+        // from a corner three faces show
+        let mut scene = scene;
+        scene.vertices.push(SceneVertex {
+            id: 2,
+            position: [0.0; 3],
+            line_color: [0; 3],
+            vertex_color: [0; 3],
+            camera: Some([5.0, 4.0, 6.0]),
+        });
+        assert_eq!(polygons(&layout(&scene, &RenderOptions::default())).len(), 3);
+    }
+
+    #[test]
+    fn shapes_are_painted_far_to_near() {
+        let (near, far) = ([200, 0, 0], [0, 0, 200]);
+        let scene = Scene {
+            cubes: vec![cube(1, [0.3, 0.0, 2.0], near), cube(2, [-0.3, 0.0, -2.0], far)],
+            ..Scene::default()
+        };
+        let frame = layout(&scene, &RenderOptions::default());
+        let is_far = |fill: &[u8; 3]| fill[2] > 0;
+        let fills = polygons(&frame);
+        assert!(fills.iter().any(is_far) && !fills.iter().all(is_far));
+        // once a near face is painted no far face follows
+        let first_near = fills.iter().position(|fill| !is_far(fill)).unwrap();
+        assert!(fills[first_near..].iter().all(|fill| !is_far(fill)), "{fills:?}");
+    }
+
+    #[test]
+    fn splashes_follow_their_seed() {
+        let options = RenderOptions::default();
+        let splash_of = |seed: &str| {
+            let splash = SceneSplash {
+                id: 1,
+                position: [960.0, 540.0],
+                seed: seed.into(),
+                order: "a".into(),
+            };
+            match super::splash(&splash, &options) {
+                Shape::Blob { points, fill, .. } => (points, fill),
+                other => panic!("splash drawn as {other:?}"),
+            }
+        };
+        assert_eq!(splash_of("seed"), splash_of("seed"));
+        assert_ne!(splash_of("seed"), splash_of("other seed"));
+        let (points, _) = splash_of("seed");
+        assert!((7..13).contains(&points.len()));
+    }
+
+    #[test]
+    fn seeded_numbers_stay_in_range() {
+        let mut rng = SeedRng::new("");
+        assert!((0..1000).map(|_| rng.next()).all(|v| (0.0..1.0).contains(&v)));
+    }
+
+    #[test]
+    fn blobs_need_three_points() {
+        assert!(blob_curve(&[]).is_none());
+        assert!(blob_curve(&[[0.0, 0.0], [1.0, 1.0]]).is_none());
+
+        let (start, segments) = blob_curve(&[[0.0, 0.0], [2.0, 0.0], [2.0, 2.0]]).unwrap();
+        assert_eq!(start, [1.0, 0.0]);
+        assert_eq!(segments.len(), 3);
+        // closed, the last segment ends where the outline started
+        assert_eq!(segments.last().unwrap().1, start);
+    }
+
+    #[test]
+    fn primary_hues_convert_to_rgb() {
+        assert_eq!(hsl_to_rgb(0.0, 1.0, 0.5), [255, 0, 0]);
+        assert_eq!(hsl_to_rgb(1.0 / 6.0, 1.0, 0.5), [255, 255, 0]);
+        assert_eq!(hsl_to_rgb(1.0 / 3.0, 1.0, 0.5), [0, 255, 0]);
+        assert_eq!(hsl_to_rgb(0.5, 1.0, 0.5), [0, 255, 255]);
+        assert_eq!(hsl_to_rgb(2.0 / 3.0, 1.0, 0.5), [0, 0, 255]);
+        assert_eq!(hsl_to_rgb(5.0 / 6.0, 1.0, 0.5), [255, 0, 255]);
+        assert_eq!(hsl_to_rgb(0.3, 0.0, 1.0), [255, 255, 255]);
+        assert_eq!(hsl_to_rgb(0.3, 0.0, 0.0), [0, 0, 0]);
+    }
+}
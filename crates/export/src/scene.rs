@@ -0,0 +1,109 @@
+//! a lobby's 3d content read into plain numbers, shared by the exporters
+use crate::color::{WHITE, parse_color};
+use vm::{Lobby, numeric::parse_scalar, types::CollectionData};
+
+#[derive(Debug, Clone)]
+pub struct SceneCube {
+    pub id: u64,
+    pub position: [f32; 3],
+    // euler radians, XYZ order
+    pub rotation: [f32; 3],
+    pub color: [u8; 3],
+}
+
+#[derive(Debug, Clone)]
+pub struct SceneVertex {
+    pub id: u64,
+    pub position: [f32; 3],
+    // color of the segment from this vertex to the next one
+    pub line_color: [u8; 3],
+    pub vertex_color: [u8; 3],
+    pub camera: Option<[f32; 3]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SceneSplash {
+    pub id: u64,
+    pub position: [f32; 2],
+    pub seed: String,
+    // layer order key, see `vm::order`
+    pub order: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Scene {
+    pub cubes: Vec<SceneCube>,
+    // drawing order, consecutive vertices are joined by a line segment
+    pub vertices: Vec<SceneVertex>,
+    // flat overlays in layer order, bottom to top
+    pub splashes: Vec<SceneSplash>,
+}
+
+impl Scene {
+    /// unreadable numbers fall back to zero and unreadable colors to white, so a single broken
+    /// document never prevents an export
+    pub fn from_lobby(lobby: &Lobby) -> Self {
+        let mut scene = Scene::default();
+
+        for document in lobby.collections.values().flat_map(|c| c.values()) {
+            match &document.data {
+                CollectionData::Cube(cube) => scene.cubes.push(SceneCube {
+                    id: document.id,
+                    position: [number(&cube.x), number(&cube.y), number(&cube.z)],
+                    rotation: [number(&cube.rot_x), number(&cube.rot_y), number(&cube.rot_z)],
+                    color: parse_color(&cube.color).unwrap_or(WHITE),
+                }),
+                CollectionData::Vertex(vertex) => scene.vertices.push(SceneVertex {
+                    id: document.id,
+                    position: [number(&vertex.x), number(&vertex.y), number(&vertex.z)],
+                    line_color: parse_color(&vertex.line_color).unwrap_or(WHITE),
+                    vertex_color: parse_color(&vertex.vertex_color).unwrap_or(WHITE),
+                    camera: match (
+                        parse_scalar(&vertex.camera_x),
+                        parse_scalar(&vertex.camera_y),
+                        parse_scalar(&vertex.camera_z),
+                    ) {
+                        (Some(x), Some(y), Some(z)) => Some([narrow(x), narrow(y), narrow(z)]),
+                        _ => None,
+                    },
+                }),
+                CollectionData::Splash(splash) => scene.splashes.push(SceneSplash {
+                    id: document.id,
+                    position: [number(&splash.x), number(&splash.y)],
+                    seed: splash.seed.clone(),
+                    order: document.order.clone(),
+                }),
+            }
+        }
+
+        // collections are keyed by string ids, restore numeric creation order
+        scene.cubes.sort_by_key(|c| c.id);
+        scene.vertices.sort_by_key(|v| v.id);
+        scene.splashes.sort_by(|a, b| a.order.cmp(&b.order).then(a.id.cmp(&b.id)));
+        scene
+    }
+}
+
+fn number(value: &str) -> f32 {
+    narrow(parse_scalar(value).unwrap_or(0.0))
+}
+
+/// clamped to the f32 range, a cast would turn larger values into infinities, which the
+/// exporters cannot write
+fn narrow(value: f64) -> f32 {
+    value.clamp(f32::MIN as f64, f32::MAX as f64) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_beyond_f32_are_clamped() {
+        assert_eq!(number("1e300"), f32::MAX);
+        assert_eq!(number("-1e300"), f32::MIN);
+        assert_eq!(number("1.5"), 1.5);
+        assert_eq!(number("12n"), 12.0);
+        assert_eq!(number("nope"), 0.0);
+    }
+}
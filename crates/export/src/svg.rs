@@ -0,0 +1,95 @@
+use crate::{
+    render::{Frame, RenderOptions, Shape, blob_curve, layout},
+    scene::Scene,
+};
+use std::fmt::Write;
+use vm::Lobby;
+
+/// render a lobby's current state as an svg document
+pub fn lobby_to_svg(lobby: &Lobby, options: &RenderOptions) -> String {
+    frame_to_svg(&layout(&Scene::from_lobby(lobby), options))
+}
+
+pub fn frame_to_svg(frame: &Frame) -> String {
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+        w = frame.width,
+        h = frame.height,
+    );
+    let _ = write!(svg, r#"<rect width="100%" height="100%" fill="{}"/>"#, hex(frame.background));
+
+    for shape in &frame.shapes {
+        // writing into a String cannot fail
+        let _ = match shape {
+            Shape::Polygon { points, fill, opacity } => {
+                let points: Vec<String> =
+                    points.iter().map(|[x, y]| format!("{x:.2},{y:.2}")).collect();
+                write!(
+                    svg,
+                    r#"<polygon points="{}" fill="{}" fill-opacity="{opacity}"/>"#,
+                    points.join(" "),
+                    hex(*fill)
+                )
+            }
+            Shape::Blob { points, fill, opacity } => write!(
+                svg,
+                r#"<path d="{}" fill="{}" fill-opacity="{opacity}"/>"#,
+                blob_path(points),
+                hex(*fill)
+            ),
+            Shape::Line { from, to, color, width } => write!(
+                svg,
+                r#"<line x1="{:.2}" y1="{:.2}" x2="{:.2}" y2="{:.2}" stroke="{}" stroke-width="{width}" stroke-linecap="round"/>"#,
+                from[0],
+                from[1],
+                to[0],
+                to[1],
+                hex(*color)
+            ),
+            Shape::Dot { center, radius, color } => write!(
+                svg,
+                r#"<circle cx="{:.2}" cy="{:.2}" r="{radius}" fill="{}"/>"#,
+                center[0],
+                center[1],
+                hex(*color)
+            ),
+        };
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+fn blob_path(points: &[[f32; 2]]) -> String {
+    let Some((start, segments)) = blob_curve(points) else {
+        return String::new();
+    };
+    let mut d = format!("M{:.2},{:.2}", start[0], start[1]);
+    for (control, end) in segments {
+        let _ = write!(d, " Q{:.2},{:.2} {:.2},{:.2}", control[0], control[1], end[0], end[1]);
+    }
+    d.push('Z');
+    d
+}
+
+fn hex(color: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_lobbies_render_a_background() {
+        let svg = lobby_to_svg(&Lobby::new("empty"), &RenderOptions::default());
+        assert!(svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="1200""#));
+        assert!(svg.ends_with(r##"<rect width="100%" height="100%" fill="#ffffff"/></svg>"##));
+    }
+
+    #[test]
+    fn blobs_of_too_few_points_have_no_path() {
+        assert_eq!(blob_path(&[[0.0, 0.0], [1.0, 1.0]]), "");
+        assert!(blob_path(&[[0.0, 0.0], [2.0, 0.0], [2.0, 2.0]]).starts_with("M1.00,0.00 Q"));
+    }
+}
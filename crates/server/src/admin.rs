@@ -0,0 +1,258 @@
+//! operator endpoints for live lobbies, used by `olta-admin`
+//!
+//! - `POST /admin/processes/:pid/evict` save the lobby and unload it
+//! - `POST /admin/processes/:pid/reload` drop the lobby's unsaved changes and load it again
+//!   from the database
+//! - `GET /admin/processes/:pid/export.{glb,svg,png}` the lobby as a 3d model or an image
+//! - `POST /admin/processes/:pid/preview` render and store its preview image now
+//! - `PUT /admin/processes/:pid` store a dumped lobby under the id, `?force` replaces an
+//!   existing one
+//! - `DELETE /admin/processes/:pid`
+//! - `POST /admin/processes/:pid/archive` move it to the archive, which frees its id
+//! - `POST /admin/processes/:pid/rename/:new_id`
+//! - `POST /admin/processes/:pid/fork/:new_id` copy the stored process to a free id
+//!
+//! the last five change the stored process while its lobby is unloaded and cannot be loaded
+//! again, so no lobby writes back over the change; ids are percent-decoded path segments
+//!
+//! every request needs `Authorization: Bearer <admin_token>`, the api is disabled when no
+//! `admin_token` is configured
+use crate::{
+    errors::{ErrorCode, Rejection},
+    http::{Body, file},
+    preview::{self, Format},
+    rest::{ErrorBody, json, status_of},
+    server::Server,
+};
+use anyhow::Error;
+use export::Scene;
+use http_body_util::{BodyExt, Limited};
+use hyper::{Method, Request, Response, StatusCode, body::Incoming, header::AUTHORIZATION};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{error, info};
+use vm::Lobby;
+
+/// upper bound on an imported lobby
+const MAX_IMPORT_BYTES: usize = 64 * 1024 * 1024;
+
+/// what an eviction found
+#[derive(Serialize)]
+struct Evicted {
+    process_id: String,
+    // false when the lobby was not in memory
+    loaded: bool,
+}
+
+/// the lobby as loaded again
+#[derive(Serialize)]
+struct Reloaded {
+    process_id: String,
+    loaded: bool,
+    documents: usize,
+}
+
+/// a stored preview image
+#[derive(Serialize)]
+struct Previewed {
+    process_id: String,
+    bytes: usize,
+}
+
+/// a stored process after an import, deletion, archival, rename or fork
+#[derive(Serialize)]
+struct Stored {
+    process_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_process_id: Option<String>,
+}
+
+pub async fn handle(req: Request<Incoming>, server: Arc<Server>) -> Response<Body> {
+    route(req, server).await.unwrap_or_else(|e| {
+        let code = Rejection::code_of(&e);
+        if code == ErrorCode::Internal {
+            error!(error = %e, "admin request failed");
+        }
+        json(status_of(code), &ErrorBody { code, message: e.to_string() })
+    })
+}
+
+async fn route(req: Request<Incoming>, server: Arc<Server>) -> Result<Response<Body>, Error> {
+    authorize(&req, &server)?;
+    let path = req.uri().path().to_string();
+    let segments = path
+        .trim_matches('/')
+        .split('/')
+        .map(|segment| percent_decode_str(segment).decode_utf8().map(|s| s.into_owned()))
+        .collect::<Result<Vec<String>, _>>()
+        .map_err(|e| Rejection::error(ErrorCode::InvalidMessage, format!("invalid path: {}", e)))?;
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let method = req.method().clone();
+
+    match (&method, segments.as_slice()) {
+        (&Method::POST, ["admin", "processes", pid, "evict"]) => {
+            let loaded = server.evict(pid, true).await?;
+            info!(process_id = %pid, loaded, "lobby evicted by admin");
+            Ok(json(StatusCode::OK, &Evicted { process_id: pid.to_string(), loaded }))
+        }
+
+        (&Method::POST, ["admin", "processes", pid, "reload"]) => {
+            let loaded = server.evict(pid, false).await?;
+            let documents = server
+                .lobby(pid)?
+                .read(|lobby, _| lobby.collections.values().map(|c| c.len()).sum())
+                .await?;
+            info!(process_id = %pid, loaded, documents, "lobby reloaded by admin");
+            Ok(json(StatusCode::OK, &Reloaded { process_id: pid.to_string(), loaded, documents }))
+        }
+
+        (&Method::GET, ["admin", "processes", pid, name]) if name.starts_with("export.") => {
+            let format: Format = name["export.".len()..]
+                .parse()
+                .map_err(|e: Error| Rejection::error(ErrorCode::NotFound, e.to_string()))?;
+            let bytes = preview::render(scene(&server, pid).await?, format).await?;
+            info!(process_id = %pid, ?format, bytes = bytes.len(), "exported lobby");
+            Ok(file(format.content_type(), bytes))
+        }
+
+        (&Method::POST, ["admin", "processes", pid, "preview"]) => {
+            let png = preview::render(scene(&server, pid).await?, Format::Png).await?;
+            server.storage().save_preview(pid, &png).await?;
+            info!(process_id = %pid, bytes = png.len(), "rendered preview");
+            Ok(json(StatusCode::OK, &Previewed { process_id: pid.to_string(), bytes: png.len() }))
+        }
+
+        (&Method::PUT, ["admin", "processes", pid]) => {
+            let force = req.uri().query().is_some_and(|q| q.split('&').any(|p| p == "force"));
+            let bytes = Limited::new(req.into_body(), MAX_IMPORT_BYTES)
+                .collect()
+                .await
+                .map_err(|e| {
+                    Rejection::error(ErrorCode::InvalidMessage, format!("invalid body: {}", e))
+                })?
+                .to_bytes();
+            let mut lobby: Lobby = serde_json::from_slice(&bytes).map_err(|e| {
+                Rejection::error(ErrorCode::InvalidMessage, format!("not a lobby: {}", e))
+            })?;
+            lobby.process_id = pid.to_string();
+            lobby.hot = false;
+            let state = serde_json::to_string(&lobby)?;
+
+            let storage = server.storage();
+            server
+                .unloaded(&[pid], false, async {
+                    if !force && storage.process_exists(pid).await? {
+                        return Err(exists(pid));
+                    }
+                    storage.save_process_state(pid, &state, false).await
+                })
+                .await?;
+            info!(process_id = %pid, bytes = state.len(), "lobby imported by admin");
+            Ok(json(StatusCode::OK, &Stored { process_id: pid.to_string(), new_process_id: None }))
+        }
+
+        (&Method::DELETE, ["admin", "processes", pid]) => {
+            let storage = server.storage();
+            let deleted = server.unloaded(&[pid], false, storage.delete_process(pid)).await?;
+            if !deleted {
+                return Err(not_found(pid));
+            }
+            info!(process_id = %pid, "process deleted by admin");
+            Ok(json(StatusCode::OK, &Stored { process_id: pid.to_string(), new_process_id: None }))
+        }
+
+        (&Method::POST, ["admin", "processes", pid, "archive"]) => {
+            let storage = server.storage();
+            let archived = server.unloaded(&[pid], true, storage.archive_process(pid)).await?;
+            if !archived {
+                return Err(not_found(pid));
+            }
+            info!(process_id = %pid, "process archived by admin");
+            Ok(json(StatusCode::OK, &Stored { process_id: pid.to_string(), new_process_id: None }))
+        }
+
+        (&Method::POST, ["admin", "processes", pid, "rename", new_id]) => {
+            let storage = server.storage();
+            // a live lobby under the new id is saved first, and then takes it
+            server
+                .unloaded(&[pid, new_id], true, async {
+                    if !storage.process_exists(pid).await? {
+                        return Err(not_found(pid));
+                    }
+                    if storage.process_exists(new_id).await? {
+                        return Err(exists(new_id));
+                    }
+                    storage.rename_process(pid, new_id).await
+                })
+                .await?;
+            info!(process_id = %pid, new_process_id = %new_id, "process renamed by admin");
+            Ok(json(
+                StatusCode::OK,
+                &Stored { process_id: pid.to_string(), new_process_id: Some(new_id.to_string()) },
+            ))
+        }
+
+        (&Method::POST, ["admin", "processes", pid, "fork", new_id]) => {
+            let storage = server.storage();
+            // the copy is of the stored state, a live lobby under the new id keeps it taken
+            server
+                .unloaded(&[new_id], true, async {
+                    if !storage.process_exists(pid).await? {
+                        return Err(not_found(pid));
+                    }
+                    if storage.process_exists(new_id).await? {
+                        return Err(exists(new_id));
+                    }
+                    storage.fork_process(pid, new_id).await
+                })
+                .await?;
+            info!(process_id = %pid, new_process_id = %new_id, "process forked by admin");
+            Ok(json(
+                StatusCode::OK,
+                &Stored { process_id: pid.to_string(), new_process_id: Some(new_id.to_string()) },
+            ))
+        }
+
+        _ => {
+            Err(Rejection::error(ErrorCode::NotFound, format!("no route for {} {}", method, path)))
+        }
+    }
+}
+
+fn not_found(pid: &str) -> Error {
+    Rejection::error(ErrorCode::NotFound, format!("process {} not found", pid))
+}
+
+fn exists(pid: &str) -> Error {
+    Rejection::error(ErrorCode::Conflict, format!("process {} already exists", pid))
+}
+
+/// what a process looks like: live when its lobby is loaded, as stored otherwise
+async fn scene(server: &Server, pid: &str) -> Result<Scene, Error> {
+    server.read(pid, |lobby, _| Scene::from_lobby(lobby)).await
+}
+
+/// check the bearer token against `admin_token`, in constant time
+fn authorize(req: &Request<Incoming>, server: &Server) -> Result<(), Error> {
+    let Some(expected) = server.config().admin_token.clone() else {
+        return Err(Rejection::error(ErrorCode::NotFound, "admin api is disabled"));
+    };
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| {
+            Rejection::error(ErrorCode::Unauthorized, "expected Authorization: Bearer <token>")
+        })?;
+
+    // digests have the same length whatever the token, so the comparison leaks neither
+    let (given, expected) = (Sha256::digest(token), Sha256::digest(expected));
+    let differs = given.iter().zip(expected.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    if differs != 0 {
+        return Err(Rejection::error(ErrorCode::Unauthorized, "invalid admin token"));
+    }
+    Ok(())
+}
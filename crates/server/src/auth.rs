@@ -0,0 +1,561 @@
+//! wallet signature challenges and signed session tokens
+use anyhow::{Error, anyhow};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rsa::{BigUint, Pss, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sha3::Keccak256;
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// how long a session token can be used to authenticate again without signing
+pub const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// proof of an address, signatures are over the connection's challenge text
+#[derive(Serialize, Deserialize)]
+pub enum Credentials {
+    /// hex public key and hex signature, the address is the public key
+    Ed25519 { public_key: String, signature: String },
+    /// 0x address and 65 byte `personal_sign` signature, the signer is recovered from it
+    Ethereum { address: String, signature: String },
+    /// base64url jwk modulus `n` and RSA-PSS SHA-256 signature, the address is the
+    /// base64url SHA-256 of the modulus
+    Arweave { owner: String, signature: String },
+    /// token of a previous `Authenticated`
+    Session { token: String },
+}
+
+impl Credentials {
+    /// the signature scheme, none for a session token
+    pub fn method(&self) -> Option<AuthMethod> {
+        match self {
+            Credentials::Ed25519 { .. } => Some(AuthMethod::Ed25519),
+            Credentials::Ethereum { .. } => Some(AuthMethod::Ethereum),
+            Credentials::Arweave { .. } => Some(AuthMethod::Arweave),
+            Credentials::Session { .. } => None,
+        }
+    }
+}
+
+/// a signature scheme clients may authenticate with, see the `auth_methods` setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Ed25519,
+    Ethereum,
+    Arweave,
+}
+
+impl FromStr for AuthMethod {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ed25519" => Ok(AuthMethod::Ed25519),
+            "ethereum" => Ok(AuthMethod::Ethereum),
+            "arweave" => Ok(AuthMethod::Arweave),
+            other => {
+                Err(anyhow!("unknown auth method {}, expected ed25519, ethereum or arweave", other))
+            }
+        }
+    }
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AuthMethod::Ed25519 => "ed25519",
+            AuthMethod::Ethereum => "ethereum",
+            AuthMethod::Arweave => "arweave",
+        })
+    }
+}
+
+/// the text a connection has to sign, unique per connection
+pub fn challenge() -> String {
+    let mut nonce = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    format!("Sign in to olta\n\nnonce: {}", hex::encode(nonce))
+}
+
+/// check a wallet signature over `challenge`, returning the signer's address
+pub fn verify_signature(credentials: &Credentials, challenge: &str) -> Result<String, Error> {
+    match credentials {
+        Credentials::Ed25519 { public_key, signature } => {
+            let key: [u8; 32] = decode_hex(public_key)?
+                .try_into()
+                .map_err(|_| anyhow!("ed25519 public key must be 32 bytes"))?;
+            let key = ed25519_dalek::VerifyingKey::from_bytes(&key)
+                .map_err(|e| anyhow!("invalid ed25519 public key: {}", e))?;
+            let signature = ed25519_dalek::Signature::from_slice(&decode_hex(signature)?)
+                .map_err(|e| anyhow!("invalid ed25519 signature: {}", e))?;
+
+            key.verify_strict(challenge.as_bytes(), &signature)
+                .map_err(|_| anyhow!("ed25519 signature does not match"))?;
+            Ok(hex::encode(key.as_bytes()))
+        }
+
+        Credentials::Ethereum { address, signature } => {
+            let signature = decode_hex(signature)?;
+            if signature.len() != 65 {
+                return Err(anyhow!("ethereum signature must be 65 bytes"));
+            }
+            // wallets use 27/28 for the recovery byte, some 0/1
+            let recovery_id = match signature[64] {
+                0 | 27 => k256::ecdsa::RecoveryId::new(false, false),
+                1 | 28 => k256::ecdsa::RecoveryId::new(true, false),
+                v => return Err(anyhow!("invalid ethereum recovery id {}", v)),
+            };
+            let signature = k256::ecdsa::Signature::from_slice(&signature[..64])
+                .map_err(|e| anyhow!("invalid ethereum signature: {}", e))?;
+
+            // EIP-191 personal message
+            let message = format!("\x19Ethereum Signed Message:\n{}{}", challenge.len(), challenge);
+            let digest = Keccak256::digest(message.as_bytes());
+            let key =
+                k256::ecdsa::VerifyingKey::recover_from_prehash(&digest, &signature, recovery_id)
+                    .map_err(|_| anyhow!("ethereum signature does not match"))?;
+
+            let point = key.to_encoded_point(false);
+            let recovered =
+                format!("0x{}", hex::encode(&Keccak256::digest(&point.as_bytes()[1..])[12..]));
+            if !recovered.eq_ignore_ascii_case(address) {
+                return Err(anyhow!("ethereum signature is not from {}", address));
+            }
+            Ok(recovered)
+        }
+
+        Credentials::Arweave { owner, signature } => {
+            let modulus = URL_SAFE_NO_PAD
+                .decode(owner)
+                .map_err(|e| anyhow!("invalid arweave owner: {}", e))?;
+            let signature = URL_SAFE_NO_PAD
+                .decode(signature)
+                .map_err(|e| anyhow!("invalid arweave signature: {}", e))?;
+            // arweave keys always use the F4 exponent
+            let key = RsaPublicKey::new(BigUint::from_bytes_be(&modulus), BigUint::from(65537u32))
+                .map_err(|e| anyhow!("invalid arweave owner: {}", e))?;
+
+            key.verify(Pss::new::<Sha256>(), &Sha256::digest(challenge.as_bytes()), &signature)
+                .map_err(|_| anyhow!("arweave signature does not match"))?;
+            Ok(URL_SAFE_NO_PAD.encode(Sha256::digest(&modulus)))
+        }
+
+        Credentials::Session { .. } => Err(anyhow!("a session token is not a signature")),
+    }
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>, Error> {
+    hex::decode(value.strip_prefix("0x").unwrap_or(value))
+        .map_err(|e| anyhow!("invalid hex: {}", e))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    // verified address
+    sub: String,
+    // unix seconds
+    iat: u64,
+    exp: u64,
+}
+
+/// issues and checks HS256 JSON web tokens binding an address to a session
+#[derive(Debug)]
+pub struct Sessions {
+    secret: Vec<u8>,
+}
+
+impl Sessions {
+    /// without a secret, a random one is used and tokens do not survive a restart
+    pub fn new(secret: Option<String>) -> Self {
+        let secret = secret.map(String::into_bytes).unwrap_or_else(|| {
+            let mut secret = vec![0u8; 32];
+            rand::rngs::OsRng.fill_bytes(&mut secret);
+            secret
+        });
+        Self { secret }
+    }
+
+    /// a token for `address` and its expiry in unix milliseconds
+    pub fn issue(&self, address: &str) -> Result<(String, u64), Error> {
+        let now = unix_now();
+        let claims = Claims {
+            sub: address.to_string(),
+            iat: now.as_secs(),
+            exp: (now + SESSION_TTL).as_secs(),
+        };
+        Ok((self.sign(&claims)?, claims.exp * 1000))
+    }
+
+    fn sign(&self, claims: &Claims) -> Result<String, Error> {
+        let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?);
+        let signing_input = format!("{}.{}", header, payload);
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&signing_input)?.finalize().into_bytes());
+        Ok(format!("{}.{}", signing_input, signature))
+    }
+
+    /// the address of a valid, unexpired token
+    pub fn verify(&self, token: &str) -> Result<String, Error> {
+        let (signing_input, signature) =
+            token.rsplit_once('.').ok_or_else(|| anyhow!("malformed session token"))?;
+        let (header, payload) =
+            signing_input.split_once('.').ok_or_else(|| anyhow!("malformed session token"))?;
+
+        let signature =
+            URL_SAFE_NO_PAD.decode(signature).map_err(|_| anyhow!("malformed session token"))?;
+        self.mac(signing_input)?
+            .verify_slice(&signature)
+            .map_err(|_| anyhow!("session token signature does not match"))?;
+
+        let header: serde_json::Value = decode_segment(header)?;
+        if header.get("alg").and_then(|alg| alg.as_str()) != Some("HS256") {
+            return Err(anyhow!("unsupported session token algorithm"));
+        }
+        let claims: Claims = decode_segment(payload)?;
+        if claims.exp <= unix_now().as_secs() {
+            return Err(anyhow!("session token expired"));
+        }
+        Ok(claims.sub)
+    }
+
+    fn mac(&self, signing_input: &str) -> Result<Hmac<Sha256>, Error> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .map_err(|e| anyhow!("invalid session secret: {}", e))?;
+        mac.update(signing_input.as_bytes());
+        Ok(mac)
+    }
+}
+
+fn decode_segment<T: serde::de::DeserializeOwned>(segment: &str) -> Result<T, Error> {
+    let bytes = URL_SAFE_NO_PAD.decode(segment).map_err(|_| anyhow!("malformed session token"))?;
+    serde_json::from_slice(&bytes).map_err(|_| anyhow!("malformed session token"))
+}
+
+fn unix_now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::traits::PublicKeyParts;
+
+    const ADDRESS: &str = "0x52908400098527886e0f7030069857d2e4169ee7";
+
+    const CHALLENGE: &str = "Sign in to olta\n\nnonce: 00";
+
+    fn ed25519(seed: u8, challenge: &str) -> Credentials {
+        use ed25519_dalek::Signer;
+        let key = ed25519_dalek::SigningKey::from_bytes(&[seed; 32]);
+        Credentials::Ed25519 {
+            public_key: hex::encode(key.verifying_key().as_bytes()),
+            signature: hex::encode(key.sign(challenge.as_bytes()).to_bytes()),
+        }
+    }
+
+    /// the address of the key and a `personal_sign` signature with a 27/28 recovery byte
+    fn ethereum_signature(seed: u8, challenge: &str) -> (String, Vec<u8>) {
+        let key = k256::ecdsa::SigningKey::from_slice(&[seed; 32]).unwrap();
+        let message = format!("\x19Ethereum Signed Message:\n{}{}", challenge.len(), challenge);
+        let (signature, recovery_id) =
+            key.sign_prehash_recoverable(&Keccak256::digest(message.as_bytes())).unwrap();
+        let point = key.verifying_key().to_encoded_point(false);
+        let address =
+            format!("0x{}", hex::encode(&Keccak256::digest(&point.as_bytes()[1..])[12..]));
+        let mut bytes = signature.to_bytes().to_vec();
+        bytes.push(recovery_id.to_byte() + 27);
+        (address, bytes)
+    }
+
+    fn ethereum(seed: u8, challenge: &str) -> Credentials {
+        let (address, signature) = ethereum_signature(seed, challenge);
+        Credentials::Ethereum { address, signature: format!("0x{}", hex::encode(signature)) }
+    }
+
+    /// keys are slow to generate, so two are shared by all tests
+    fn arweave_key(index: usize) -> &'static rsa::RsaPrivateKey {
+        static KEYS: std::sync::OnceLock<Vec<rsa::RsaPrivateKey>> = std::sync::OnceLock::new();
+        &KEYS.get_or_init(|| {
+            (0..2).map(|_| rsa::RsaPrivateKey::new(&mut rand::rngs::OsRng, 1024).unwrap()).collect()
+        })[index]
+    }
+
+    fn arweave(index: usize, challenge: &str) -> Credentials {
+        let key = arweave_key(index);
+        let signature = key
+            .sign_with_rng(
+                &mut rand::rngs::OsRng,
+                Pss::new::<Sha256>(),
+                &Sha256::digest(challenge.as_bytes()),
+            )
+            .unwrap();
+        Credentials::Arweave {
+            owner: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+            signature: URL_SAFE_NO_PAD.encode(signature),
+        }
+    }
+
+    fn rejected(credentials: &Credentials) -> String {
+        verify_signature(credentials, CHALLENGE).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn ed25519_signatures_verify_to_the_public_key() {
+        let Credentials::Ed25519 { public_key, signature } = ed25519(1, CHALLENGE) else {
+            unreachable!()
+        };
+        let credentials = Credentials::Ed25519 { public_key: public_key.clone(), signature };
+        assert_eq!(verify_signature(&credentials, CHALLENGE).unwrap(), public_key);
+
+        // another key's signature under this public key
+        let Credentials::Ed25519 { signature, .. } = ed25519(2, CHALLENGE) else { unreachable!() };
+        let other = Credentials::Ed25519 { public_key, signature };
+        assert_eq!(rejected(&other), "ed25519 signature does not match");
+
+        assert_eq!(
+            rejected(&ed25519(1, "Sign in to olta\n\nnonce: 01")),
+            "ed25519 signature does not match"
+        );
+    }
+
+    #[test]
+    fn ethereum_signatures_recover_the_address() {
+        let (address, _) = ethereum_signature(1, CHALLENGE);
+        assert_eq!(verify_signature(&ethereum(1, CHALLENGE), CHALLENGE).unwrap(), address);
+
+        // checksummed addresses compare case insensitively, 0/1 recovery bytes are accepted
+        let (_, mut signature) = ethereum_signature(1, CHALLENGE);
+        signature[64] -= 27;
+        let credentials = Credentials::Ethereum {
+            address: address.to_uppercase().replacen("0X", "0x", 1),
+            signature: hex::encode(&signature),
+        };
+        assert_eq!(verify_signature(&credentials, CHALLENGE).unwrap(), address);
+
+        // another key's signature for this address
+        let Credentials::Ethereum { signature, .. } = ethereum(2, CHALLENGE) else {
+            unreachable!()
+        };
+        let other = Credentials::Ethereum { address: address.clone(), signature };
+        assert_eq!(rejected(&other), format!("ethereum signature is not from {}", address));
+
+        // a changed challenge recovers some other signer
+        assert!(
+            rejected(&ethereum(1, "Sign in to olta\n\nnonce: 01"))
+                .starts_with("ethereum signature")
+        );
+    }
+
+    #[test]
+    fn ethereum_recovery_bytes_other_than_27_28_0_1_are_rejected() {
+        let (address, mut signature) = ethereum_signature(1, CHALLENGE);
+        for v in [2, 26, 29, 255] {
+            signature[64] = v;
+            let credentials = Credentials::Ethereum {
+                address: address.clone(),
+                signature: hex::encode(&signature),
+            };
+            assert_eq!(rejected(&credentials), format!("invalid ethereum recovery id {v}"));
+        }
+    }
+
+    #[test]
+    fn arweave_signatures_verify_to_the_owner_digest() {
+        let credentials = arweave(0, CHALLENGE);
+        let Credentials::Arweave { owner, .. } = &credentials else { unreachable!() };
+        let address =
+            URL_SAFE_NO_PAD.encode(Sha256::digest(URL_SAFE_NO_PAD.decode(owner).unwrap()));
+        assert_eq!(verify_signature(&credentials, CHALLENGE).unwrap(), address);
+
+        // another key's signature under this owner
+        let Credentials::Arweave { signature, .. } = arweave(1, CHALLENGE) else { unreachable!() };
+        let other = Credentials::Arweave { owner: owner.clone(), signature };
+        assert_eq!(rejected(&other), "arweave signature does not match");
+
+        assert_eq!(
+            rejected(&arweave(0, "Sign in to olta\n\nnonce: 01")),
+            "arweave signature does not match"
+        );
+    }
+
+    #[test]
+    fn signatures_under_the_wrong_scheme_are_rejected() {
+        // an ed25519 signature presented as ethereum and arweave
+        let Credentials::Ed25519 { public_key, signature } = ed25519(1, CHALLENGE) else {
+            unreachable!()
+        };
+        let (address, _) = ethereum_signature(1, CHALLENGE);
+        assert!(
+            verify_signature(
+                &Credentials::Ethereum { address, signature: signature.clone() },
+                CHALLENGE
+            )
+            .is_err()
+        );
+        let Credentials::Arweave { owner, .. } = arweave(0, CHALLENGE) else { unreachable!() };
+        let arweave_signature = URL_SAFE_NO_PAD.encode(hex::decode(&signature).unwrap());
+        assert!(
+            verify_signature(
+                &Credentials::Arweave { owner, signature: arweave_signature },
+                CHALLENGE
+            )
+            .is_err()
+        );
+
+        // an ethereum signature presented as ed25519 and arweave
+        let Credentials::Ethereum { signature, .. } = ethereum(1, CHALLENGE) else {
+            unreachable!()
+        };
+        assert!(
+            verify_signature(
+                &Credentials::Ed25519 { public_key, signature: signature.clone() },
+                CHALLENGE
+            )
+            .is_err()
+        );
+        let Credentials::Arweave { owner, .. } = arweave(0, CHALLENGE) else { unreachable!() };
+        let arweave_signature = URL_SAFE_NO_PAD.encode(decode_hex(&signature).unwrap());
+        assert!(
+            verify_signature(
+                &Credentials::Arweave { owner, signature: arweave_signature },
+                CHALLENGE
+            )
+            .is_err()
+        );
+
+        // an arweave signature presented as ed25519 and ethereum
+        let Credentials::Arweave { signature, .. } = arweave(0, CHALLENGE) else { unreachable!() };
+        let signature = hex::encode(URL_SAFE_NO_PAD.decode(signature).unwrap());
+        let Credentials::Ed25519 { public_key, .. } = ed25519(1, CHALLENGE) else { unreachable!() };
+        assert!(
+            verify_signature(
+                &Credentials::Ed25519 { public_key, signature: signature.clone() },
+                CHALLENGE
+            )
+            .is_err()
+        );
+        let (address, _) = ethereum_signature(1, CHALLENGE);
+        assert!(
+            verify_signature(&Credentials::Ethereum { address, signature }, CHALLENGE).is_err()
+        );
+
+        let session = Credentials::Session { token: "token".into() };
+        assert_eq!(rejected(&session), "a session token is not a signature");
+    }
+
+    #[test]
+    fn malformed_signatures_are_rejected() {
+        let Credentials::Ed25519 { public_key, .. } = ed25519(1, CHALLENGE) else { unreachable!() };
+        let (address, _) = ethereum_signature(1, CHALLENGE);
+        let Credentials::Arweave { owner, .. } = arweave(0, CHALLENGE) else { unreachable!() };
+
+        for signature in ["", "zz", "0x123", "abcd"] {
+            let ed25519 = Credentials::Ed25519 {
+                public_key: public_key.clone(),
+                signature: signature.into(),
+            };
+            assert!(verify_signature(&ed25519, CHALLENGE).is_err(), "{signature:?} verified");
+            let ethereum =
+                Credentials::Ethereum { address: address.clone(), signature: signature.into() };
+            assert!(verify_signature(&ethereum, CHALLENGE).is_err(), "{signature:?} verified");
+        }
+        assert!(
+            rejected(&Credentials::Ed25519 { public_key: "zz".into(), signature: "00".into() })
+                .starts_with("invalid hex")
+        );
+        for signature in ["", "not base64!", "AAAA"] {
+            let arweave =
+                Credentials::Arweave { owner: owner.clone(), signature: signature.into() };
+            assert!(verify_signature(&arweave, CHALLENGE).is_err(), "{signature:?} verified");
+        }
+        assert!(
+            rejected(&Credentials::Arweave { owner: "***".into(), signature: "AAAA".into() })
+                .starts_with("invalid arweave owner")
+        );
+    }
+
+    fn sessions() -> Sessions {
+        Sessions::new(Some("test secret".into()))
+    }
+
+    #[test]
+    fn issued_tokens_verify_to_their_address() {
+        let sessions = sessions();
+        let (token, expires_at) = sessions.issue(ADDRESS).unwrap();
+        assert_eq!(sessions.verify(&token).unwrap(), ADDRESS);
+
+        let ttl = expires_at.saturating_sub(unix_now().as_millis() as u64);
+        assert!(
+            ttl > SESSION_TTL.as_millis() as u64 - 2000 && ttl <= SESSION_TTL.as_millis() as u64
+        );
+    }
+
+    #[test]
+    fn tokens_survive_a_restart_with_the_same_secret() {
+        let (token, _) = sessions().issue(ADDRESS).unwrap();
+        assert_eq!(sessions().verify(&token).unwrap(), ADDRESS);
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let sessions = sessions();
+        let now = unix_now().as_secs();
+        let claims = Claims { sub: ADDRESS.into(), iat: now - 7200, exp: now - 1 };
+        let token = sessions.sign(&claims).unwrap();
+        assert_eq!(sessions.verify(&token).unwrap_err().to_string(), "session token expired");
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let sessions = sessions();
+        let (token, _) = sessions.issue(ADDRESS).unwrap();
+        let [header, _, signature] = token.split('.').collect::<Vec<_>>()[..] else {
+            panic!("token has three segments");
+        };
+
+        // another address under the original signature
+        let now = unix_now().as_secs();
+        let claims = Claims { sub: "0xattacker".into(), iat: now, exp: now + 3600 };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        let forged = format!("{header}.{payload}.{signature}");
+        assert_eq!(
+            sessions.verify(&forged).unwrap_err().to_string(),
+            "session token signature does not match"
+        );
+
+        // a flipped signature byte
+        let mut bytes = URL_SAFE_NO_PAD.decode(signature).unwrap();
+        bytes[0] ^= 1;
+        let flipped =
+            format!("{}.{}", token.rsplit_once('.').unwrap().0, URL_SAFE_NO_PAD.encode(bytes));
+        assert!(sessions.verify(&flipped).is_err());
+    }
+
+    #[test]
+    fn tokens_of_another_secret_are_rejected() {
+        let (token, _) = Sessions::new(Some("other secret".into())).issue(ADDRESS).unwrap();
+        assert!(sessions().verify(&token).is_err());
+        // a random secret differs on every start
+        let (token, _) = Sessions::new(None).issue(ADDRESS).unwrap();
+        assert!(Sessions::new(None).verify(&token).is_err());
+    }
+
+    #[test]
+    fn unsigned_and_malformed_tokens_are_rejected() {
+        let sessions = sessions();
+        let (token, _) = sessions.issue(ADDRESS).unwrap();
+        let (_, payload) = token.split_once('.').unwrap();
+        let payload = payload.rsplit_once('.').unwrap().0;
+        let none = URL_SAFE_NO_PAD.encode(br#"{"alg":"none","typ":"JWT"}"#);
+        assert!(sessions.verify(&format!("{none}.{payload}.")).is_err());
+
+        for token in ["", "abc", "a.b", "a.b.c", "..."] {
+            assert!(sessions.verify(token).is_err(), "{token:?} verified");
+        }
+    }
+}
@@ -0,0 +1,484 @@
+//! server settings, loaded once at startup and again on SIGHUP from three layers, each
+//! overriding the one before: a toml file, the environment (and `.env`), command line flags
+//!
+//! a setting has one name in every layer: `port` in the `[server]` table of the file, `PORT`
+//! in the environment, `--port` on the command line
+use crate::{auth::AuthMethod, lobby::Durability, outbox::OverflowPolicy};
+use anyhow::{Error, anyhow, bail};
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tracing_subscriber::EnvFilter;
+
+/// read when neither `--config` nor `CONFIG_FILE` name a file, if it exists
+const DEFAULT_FILE: &str = "olta.toml";
+/// shortest `session_secret` accepted, anything guessable makes session tokens forgeable
+const MIN_SESSION_SECRET_BYTES: usize = 32;
+/// shortest `admin_token` accepted, it is all that guards deleting and replacing processes
+const MIN_ADMIN_TOKEN_BYTES: usize = 32;
+
+/// every setting, with the table of the config file it goes in
+const SETTINGS: &[(&str, &str)] = &[
+    ("server", "host"),
+    ("server", "port"),
+    ("database", "database_url"),
+    ("limits", "outbound_queue_capacity"),
+    ("limits", "outbound_queue_policy"),
+    ("limits", "event_buffer_capacity"),
+    ("limits", "sync_chunk_documents"),
+    ("limits", "broadcast_tick_hz"),
+    ("timeouts", "ping_interval_secs"),
+    ("timeouts", "idle_timeout_secs"),
+    ("timeouts", "max_connection_lifetime_secs"),
+    ("timeouts", "handshake_timeout_secs"),
+    ("timeouts", "shutdown_timeout_secs"),
+    ("persistence", "durability"),
+    ("persistence", "save_debounce_ms"),
+    ("persistence", "save_max_delay_ms"),
+    ("persistence", "preview_interval_secs"),
+    ("auth", "session_secret"),
+    ("auth", "auth_methods"),
+    ("auth", "admin_token"),
+    ("tls", "tls_cert"),
+    ("tls", "tls_key"),
+    ("logging", "log_filter"),
+    ("logging", "log_format"),
+];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub database_url: String,
+    // signs session tokens, they do not outlive the process when unset
+    pub session_secret: Option<String>,
+    // signature schemes accepted by `Authenticate`, sessions are always accepted
+    pub auth_methods: Vec<AuthMethod>,
+    // bearer token of the `/admin` api, which is disabled when unset
+    pub admin_token: Option<String>,
+    // serve https and wss with this certificate, plain http when unset
+    pub tls: Option<TlsFiles>,
+    // frames queued per connection before `overflow_policy` applies
+    pub outbound_queue_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+    // how often idle connections are pinged
+    pub ping_interval: Duration,
+    // connections that sent nothing for this long, not even a pong, are dropped
+    pub idle_timeout: Duration,
+    // connections are closed after this long, however active
+    pub max_connection_lifetime: Duration,
+    // time a client gets to complete the websocket upgrade
+    pub handshake_timeout: Duration,
+    // events kept per lobby for clients resuming after a reconnect, 0 always sends a FullSync
+    pub event_buffer_capacity: usize,
+    // documents per `SyncChunk` when syncing a lobby
+    pub sync_chunk_documents: usize,
+    // document updates are merged and broadcast once per tick, each on its own when unset
+    pub broadcast_tick: Option<Duration>,
+    // time a shutdown gets to close connections and write out lobbies
+    pub shutdown_timeout: Duration,
+    // whether inputs are acked before or after their change is stored
+    pub durability: Durability,
+    // write-behind saves wait for a lobby to be quiet this long, but no longer than the max
+    pub save_debounce: Duration,
+    pub save_max_delay: Duration,
+    // a saved lobby renders a png preview at most this often, and when it is unloaded; no
+    // previews when unset
+    pub preview_interval: Option<Duration>,
+    // tracing directives like `info` or `server=debug,info`, `RUST_LOG` when unset
+    pub log_filter: String,
+    pub log_format: LogFormat,
+}
+
+/// pem encoded certificate chain and private key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// one json object per line
+    Json,
+    /// human readable lines
+    Text,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "json" => Ok(LogFormat::Json),
+            "text" => Ok(LogFormat::Text),
+            other => Err(anyhow!("unknown log format {}, expected json or text", other)),
+        }
+    }
+}
+
+impl Config {
+    /// read every layer and validate the result, `args` are the command line arguments after
+    /// the program name
+    pub fn load(args: &[String]) -> Result<Self, Error> {
+        // .env only fills in variables that are not set already
+        dotenvy::dotenv().ok();
+        let cli = parse_args(args)?;
+        let env = environment();
+        let file = match cli.get("config").or_else(|| env.get("CONFIG_FILE")) {
+            Some(path) => read_file(Path::new(path))?,
+            None if Path::new(DEFAULT_FILE).exists() => read_file(Path::new(DEFAULT_FILE))?,
+            None => HashMap::new(),
+        };
+        Self::from_layers(&Layers { file, env, cli })
+    }
+
+    fn from_layers(layers: &Layers) -> Result<Self, Error> {
+        let config = Self {
+            host: layers.get("host").unwrap_or_else(|| "0.0.0.0".into()),
+            port: layers.parse("port", 8080)?,
+            database_url: layers
+                .get("database_url")
+                .ok_or_else(|| anyhow!("database_url is not set"))?,
+            session_secret: layers.get("session_secret"),
+            auth_methods: match layers.get("auth_methods") {
+                Some(methods) => methods
+                    .split(',')
+                    .map(|method| method.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| anyhow!("invalid auth_methods={}: {}", methods, e))?,
+                None => vec![AuthMethod::Ed25519, AuthMethod::Ethereum, AuthMethod::Arweave],
+            },
+            admin_token: layers.get("admin_token"),
+            tls: match (layers.get("tls_cert"), layers.get("tls_key")) {
+                (Some(cert), Some(key)) => Some(TlsFiles { cert: cert.into(), key: key.into() }),
+                (None, None) => None,
+                _ => bail!("tls_cert and tls_key must be set together"),
+            },
+            outbound_queue_capacity: positive(
+                layers.parse("outbound_queue_capacity", 1024)?,
+                "outbound_queue_capacity",
+            )?,
+            overflow_policy: layers.parse("outbound_queue_policy", OverflowPolicy::Coalesce)?,
+            ping_interval: layers.secs("ping_interval_secs", 20)?,
+            idle_timeout: layers.secs("idle_timeout_secs", 60)?,
+            max_connection_lifetime: layers.secs("max_connection_lifetime_secs", 24 * 60 * 60)?,
+            handshake_timeout: layers.secs("handshake_timeout_secs", 10)?,
+            event_buffer_capacity: layers.parse("event_buffer_capacity", 512)?,
+            sync_chunk_documents: positive(
+                layers.parse("sync_chunk_documents", 256)?,
+                "sync_chunk_documents",
+            )?,
+            broadcast_tick: layers.hz("broadcast_tick_hz")?,
+            shutdown_timeout: layers.secs("shutdown_timeout_secs", 20)?,
+            durability: layers.parse("durability", Durability::WriteBehind)?,
+            save_debounce: layers.millis("save_debounce_ms", 500)?,
+            save_max_delay: layers.millis("save_max_delay_ms", 5000)?,
+            preview_interval: match layers.parse::<u64>("preview_interval_secs", 60)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            log_filter: layers
+                .get("log_filter")
+                .or_else(|| layers.env.get("RUST_LOG").cloned())
+                .unwrap_or_else(|| "info".into()),
+            log_format: layers.parse("log_format", LogFormat::Json)?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// settings that are fine one by one but not together
+    fn validate(&self) -> Result<(), Error> {
+        if self.idle_timeout <= self.ping_interval {
+            bail!("idle_timeout_secs must be longer than ping_interval_secs");
+        }
+        if self.save_max_delay < self.save_debounce {
+            bail!("save_max_delay_ms must be at least save_debounce_ms");
+        }
+        if self.session_secret.as_ref().is_some_and(|s| s.len() < MIN_SESSION_SECRET_BYTES) {
+            bail!(
+                "session_secret must be at least {} bytes, e.g. from `openssl rand -hex 32`",
+                MIN_SESSION_SECRET_BYTES
+            );
+        }
+        if self.admin_token.as_ref().is_some_and(|t| t.len() < MIN_ADMIN_TOKEN_BYTES) {
+            bail!(
+                "admin_token must be at least {} bytes, e.g. from `openssl rand -hex 32`",
+                MIN_ADMIN_TOKEN_BYTES
+            );
+        }
+        EnvFilter::try_new(&self.log_filter)
+            .map_err(|e| anyhow!("invalid log_filter={}: {}", self.log_filter, e))?;
+        Ok(())
+    }
+
+    /// `next` with the settings that only apply at startup kept as they are, and the names
+    /// of those it would have changed
+    pub fn reloaded(&self, mut next: Config) -> (Config, Vec<&'static str>) {
+        let mut kept = Vec::new();
+        macro_rules! keep {
+            ($($field:ident),*) => {$(
+                if next.$field != self.$field {
+                    kept.push(stringify!($field));
+                    next.$field = self.$field.clone();
+                }
+            )*};
+        }
+        keep!(host, port, database_url, session_secret, tls, log_format);
+        (next, kept)
+    }
+}
+
+/// `--help` text
+pub fn usage() -> String {
+    let mut usage = format!(
+        "usage: server [--config FILE] [--<setting> VALUE]...\n\n\
+         settings are read from the tables of a toml file ({} unless --config or CONFIG_FILE \
+         name another),\nthen from environment variables of the same name in upper case, then \
+         from flags:\n",
+        DEFAULT_FILE
+    );
+    let mut table = "";
+    for (section, key) in SETTINGS {
+        if *section != table {
+            table = section;
+            usage.push_str(&format!("\n[{}]\n", table));
+        }
+        usage.push_str(&format!("  --{}\n", key.replace('_', "-")));
+    }
+    usage
+}
+
+/// the file and command line values by setting name, and the environment by variable name
+struct Layers {
+    file: HashMap<String, String>,
+    env: HashMap<String, String>,
+    cli: HashMap<String, String>,
+}
+
+impl Layers {
+    fn get(&self, key: &str) -> Option<String> {
+        self.cli
+            .get(key)
+            .or_else(|| self.env.get(&key.to_uppercase()))
+            .or_else(|| self.file.get(key))
+            .cloned()
+    }
+
+    /// `default` when unset, an error when set to something unparsable
+    fn parse<T: FromStr>(&self, key: &str, default: T) -> Result<T, Error>
+    where
+        T::Err: fmt::Display,
+    {
+        match self.get(key) {
+            Some(value) => value.parse().map_err(|e| anyhow!("invalid {}={}: {}", key, value, e)),
+            None => Ok(default),
+        }
+    }
+
+    /// a whole number of seconds, zero is refused
+    fn secs(&self, key: &str, default: u64) -> Result<Duration, Error> {
+        positive(self.parse(key, default)?, key).map(Duration::from_secs)
+    }
+
+    /// a whole number of milliseconds, zero is refused
+    fn millis(&self, key: &str, default: u64) -> Result<Duration, Error> {
+        positive(self.parse(key, default)?, key).map(Duration::from_millis)
+    }
+
+    /// a rate of at most 1000 per second as the time between two ticks, 0 or unset is off
+    fn hz(&self, key: &str) -> Result<Option<Duration>, Error> {
+        match self.parse::<u32>(key, 0)? {
+            0 => Ok(None),
+            hz if hz > 1000 => Err(anyhow!("{} must be at most 1000", key)),
+            hz => Ok(Some(Duration::from_secs(1) / hz)),
+        }
+    }
+}
+
+fn positive<T: Default + PartialEq>(value: T, key: &str) -> Result<T, Error> {
+    if value == T::default() {
+        return Err(anyhow!("{} must be greater than 0", key));
+    }
+    Ok(value)
+}
+
+/// the environment variables that are set to something, an empty one counts as unset
+fn environment() -> HashMap<String, String> {
+    env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+}
+
+/// `--name value` and `--name=value` flags, with dashes for the underscores of setting names
+fn parse_args(args: &[String]) -> Result<HashMap<String, String>, Error> {
+    let mut values = HashMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let flag = arg.strip_prefix("--").ok_or_else(|| anyhow!("unexpected argument {}", arg))?;
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, value.to_string()),
+            None => (flag, args.next().ok_or_else(|| anyhow!("--{} needs a value", flag))?.clone()),
+        };
+        let key = name.replace('-', "_");
+        if key != "config" && !SETTINGS.iter().any(|(_, setting)| *setting == key) {
+            bail!("unknown flag --{}, see --help", name);
+        }
+        values.insert(key, value);
+    }
+    Ok(values)
+}
+
+/// the settings of a toml file, each in its table from `SETTINGS`; lists are joined with
+/// commas like in the environment
+fn read_file(path: &Path) -> Result<HashMap<String, String>, Error> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| anyhow!("failed to read {}: {}", path.display(), e))?;
+    let tables: toml::Table =
+        text.parse().map_err(|e| anyhow!("invalid {}: {}", path.display(), e))?;
+
+    let mut values = HashMap::new();
+    for (section, entries) in tables {
+        let toml::Value::Table(entries) = entries else {
+            bail!("{}: {} is not in a table, see --help", path.display(), section);
+        };
+        for (key, value) in entries {
+            if !SETTINGS.contains(&(section.as_str(), key.as_str())) {
+                bail!("{}: unknown setting {}.{}, see --help", path.display(), section, key);
+            }
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Array(items) => items
+                    .into_iter()
+                    .map(|item| match item {
+                        toml::Value::String(item) => item,
+                        item => item.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+                value => value.to_string(),
+            };
+            values.insert(key, value);
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn layers(file: &[(&str, &str)], env: &[(&str, &str)], cli: &[(&str, &str)]) -> Layers {
+        let mut file = values(file);
+        file.entry("database_url".into()).or_insert_with(|| "postgres://localhost/olta".into());
+        Layers { file, env: values(env), cli: values(cli) }
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let file = [("port", "1001"), ("ping_interval_secs", "5")];
+        let env = [("PORT", "1002"), ("PING_INTERVAL_SECS", "6")];
+        let cli = [("port", "1003")];
+
+        let config = Config::from_layers(&layers(&file, &env, &cli)).unwrap();
+        assert_eq!(config.port, 1003);
+        assert_eq!(config.ping_interval, Duration::from_secs(6));
+
+        let config = Config::from_layers(&layers(&file, &env, &[])).unwrap();
+        assert_eq!(config.port, 1002);
+
+        let config = Config::from_layers(&layers(&file, &[], &[])).unwrap();
+        assert_eq!((config.port, config.ping_interval), (1001, Duration::from_secs(5)));
+
+        let config = Config::from_layers(&layers(&[], &[], &[])).unwrap();
+        assert_eq!((config.port, config.ping_interval), (8080, Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn environment_names_are_upper_case() {
+        let config = Config::from_layers(&layers(&[], &[("port", "1002")], &[])).unwrap();
+        assert_eq!(config.port, 8080);
+    }
+
+    #[test]
+    fn flags_take_both_forms_and_only_known_settings() {
+        let args = ["--port", "1003", "--outbound-queue-policy=disconnect", "--config", "a.toml"]
+            .map(String::from);
+        let cli = parse_args(&args).unwrap();
+        assert_eq!(cli["port"], "1003");
+        assert_eq!(cli["outbound_queue_policy"], "disconnect");
+        assert_eq!(cli["config"], "a.toml");
+
+        assert!(parse_args(&["--prot".into(), "1".into()]).is_err());
+        assert!(parse_args(&["--port".into()]).is_err());
+        assert!(parse_args(&["port".into()]).is_err());
+    }
+
+    #[test]
+    fn files_have_settings_in_their_tables() {
+        let path = env::temp_dir().join(format!("olta-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "[server]\nport = 1001\n[auth]\nauth_methods = [\"ed25519\", \"ethereum\"]\n",
+        )
+        .unwrap();
+        let file = read_file(&path).unwrap();
+        assert_eq!(file["port"], "1001");
+        assert_eq!(file["auth_methods"], "ed25519,ethereum");
+
+        fs::write(&path, "[limits]\nport = 1001\n").unwrap();
+        assert!(read_file(&path).is_err());
+        fs::write(&path, "port = 1001\n").unwrap();
+        assert!(read_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_values_are_refused() {
+        for (key, value) in [
+            ("port", "http"),
+            ("outbound_queue_capacity", "0"),
+            ("outbound_queue_policy", "drop"),
+            ("broadcast_tick_hz", "1001"),
+            ("log_format", "xml"),
+            ("session_secret", "change-me"),
+        ] {
+            assert!(Config::from_layers(&layers(&[(key, value)], &[], &[])).is_err(), "{key}");
+        }
+        let idle = [("ping_interval_secs", "60"), ("idle_timeout_secs", "60")];
+        assert!(Config::from_layers(&layers(&idle, &[], &[])).is_err());
+        let secret = [("session_secret", "0123456789abcdef0123456789abcdef")];
+        assert!(Config::from_layers(&layers(&secret, &[], &[])).is_ok());
+    }
+
+    #[test]
+    fn weak_admin_tokens_are_refused() {
+        for token in ["x", "change-me", "0123456789abcdef0123456789abcde"] {
+            let error = Config::from_layers(&layers(&[("admin_token", token)], &[], &[]));
+            assert!(error.unwrap_err().to_string().starts_with("admin_token must be"), "{token}");
+        }
+        let token = [("admin_token", "0123456789abcdef0123456789abcdef")];
+        let config = Config::from_layers(&layers(&token, &[], &[])).unwrap();
+        assert_eq!(config.admin_token.as_deref(), Some(token[0].1));
+    }
+
+    #[test]
+    fn reloads_keep_startup_settings() {
+        let config = Config::from_layers(&layers(&[("port", "1001")], &[], &[])).unwrap();
+        let next = layers(&[("port", "1002"), ("ping_interval_secs", "5")], &[], &[]);
+        let (config, kept) = config.reloaded(Config::from_layers(&next).unwrap());
+        assert_eq!(config.port, 1001);
+        assert_eq!(config.ping_interval, Duration::from_secs(5));
+        assert_eq!(kept, ["port"]);
+    }
+}
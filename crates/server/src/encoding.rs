@@ -0,0 +1,160 @@
+//! wire encodings, negotiated per connection through `Sec-WebSocket-Protocol`
+use crate::messages::OutputMessage;
+use anyhow::{Error, anyhow};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio_tungstenite::tungstenite::Message;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// text frames, used when the client offers no subprotocol
+    #[default]
+    Json,
+    /// binary frames, maps keep their field names
+    MessagePack,
+    /// binary frames
+    Cbor,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 3] = [Encoding::Json, Encoding::MessagePack, Encoding::Cbor];
+
+    pub fn protocol(self) -> &'static str {
+        match self {
+            Encoding::Json => "olta.json",
+            Encoding::MessagePack => "olta.msgpack",
+            Encoding::Cbor => "olta.cbor",
+        }
+    }
+
+    /// the first subprotocol of a `Sec-WebSocket-Protocol` header that we speak, in the
+    /// client's order of preference
+    pub fn negotiate(header: &str) -> Option<Self> {
+        header
+            .split(',')
+            .map(str::trim)
+            .find_map(|offered| Self::ALL.into_iter().find(|e| e.protocol() == offered))
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Message, Error> {
+        match self {
+            Encoding::Json => serde_json::to_string(value)
+                .map(|text| Message::Text(text.into()))
+                .map_err(|e| anyhow!("json encoding failed: {}", e)),
+            Encoding::MessagePack => rmp_serde::to_vec_named(value)
+                .map(|bytes| Message::Binary(bytes.into()))
+                .map_err(|e| anyhow!("msgpack encoding failed: {}", e)),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes)
+                    .map_err(|e| anyhow!("cbor encoding failed: {}", e))?;
+                Ok(Message::Binary(bytes.into()))
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T, Error> {
+        match self {
+            Encoding::Json => serde_json::from_slice(payload).map_err(|e| anyhow!("{}", e)),
+            Encoding::MessagePack => rmp_serde::from_slice(payload).map_err(|e| anyhow!("{}", e)),
+            Encoding::Cbor => ciborium::from_reader(payload).map_err(|e| anyhow!("{}", e)),
+        }
+    }
+}
+
+/// an output encoded lazily, at most once per encoding however many subscribers receive it
+pub struct Frames<'a> {
+    output: &'a OutputMessage,
+    encoded: [Option<Message>; Encoding::ALL.len()],
+}
+
+impl<'a> Frames<'a> {
+    pub fn new(output: &'a OutputMessage) -> Self {
+        Self { output, encoded: Default::default() }
+    }
+
+    /// frames share their payload, cloning one does not copy the message
+    pub fn get(&mut self, encoding: Encoding) -> Result<Message, Error> {
+        let slot = &mut self.encoded[encoding as usize];
+        if let Some(frame) = slot {
+            return Ok(frame.clone());
+        }
+        let frame = encoding.encode(self.output)?;
+        *slot = Some(frame.clone());
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{Input, InputMessage, Output};
+
+    fn payload(frame: &Message) -> &[u8] {
+        match frame {
+            Message::Text(text) => text.as_bytes(),
+            Message::Binary(bytes) => bytes,
+            other => panic!("unexpected frame {other:?}"),
+        }
+    }
+
+    #[test]
+    fn negotiation_follows_the_client_preference() {
+        assert_eq!(Encoding::negotiate("olta.cbor, olta.msgpack"), Some(Encoding::Cbor));
+        assert_eq!(Encoding::negotiate("chat,olta.msgpack,olta.json"), Some(Encoding::MessagePack));
+        assert_eq!(Encoding::negotiate("chat, olta.xml"), None);
+        assert_eq!(Encoding::negotiate(""), None);
+    }
+
+    #[test]
+    fn inputs_round_trip_in_every_encoding() {
+        let message = InputMessage {
+            input: Input::UpdateDocument {
+                collection_name: "cubes".into(),
+                doc_id: "1".into(),
+                changes: serde_json::from_str(r#"{"x":"2n","rotY":"0.5"}"#).unwrap(),
+            },
+            process_id: Some("p".into()),
+            request_id: Some("r1".into()),
+        };
+        for encoding in Encoding::ALL {
+            let frame = encoding.encode(&message).unwrap();
+            assert_eq!(frame.is_text(), encoding == Encoding::Json);
+            let decoded: InputMessage = encoding.decode(payload(&frame)).unwrap();
+            let Input::UpdateDocument { doc_id, changes, .. } = decoded.input else {
+                panic!("{encoding:?} decoded another input");
+            };
+            assert_eq!(doc_id, "1");
+            assert_eq!(changes.x.as_deref(), Some("2n"));
+            assert_eq!(changes.rot_y.as_deref(), Some("0.5"));
+            assert_eq!(decoded.request_id.as_deref(), Some("r1"));
+        }
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        for encoding in Encoding::ALL {
+            assert!(encoding.decode::<InputMessage>(b"\xff\x00not a message").is_err());
+        }
+    }
+
+    #[test]
+    fn frames_are_encoded_once_per_encoding() {
+        let output = OutputMessage {
+            output: Output::Error {
+                process_id: None,
+                code: crate::errors::ErrorCode::Internal,
+                message: "boom".into(),
+                request_id: None,
+            },
+            seq: Some(7),
+        };
+        let mut frames = Frames::new(&output);
+        let json = frames.get(Encoding::Json).unwrap();
+        let cbor = frames.get(Encoding::Cbor).unwrap();
+        assert_eq!(frames.get(Encoding::Json).unwrap(), json);
+        assert!(json.is_text() && cbor.is_binary());
+
+        let value: serde_json::Value = serde_json::from_slice(payload(&json)).unwrap();
+        assert_eq!(value["seq"], 7);
+    }
+}
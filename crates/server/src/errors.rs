@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use vm::errors::VMErrors;
+
+/// machine readable reason carried by `Output::Error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    InvalidMessage,
+    InvalidArgument,
+    NotFound,
+    // the input targets a process the connection did not join
+    NotJoined,
+    Locked,
+    Conflict,
+    Unsupported,
+    // the input needs an authenticated connection, or the credentials were refused
+    Unauthorized,
+    // the client's protocol version is outside of what the server accepts
+    IncompatibleVersion,
+    // the server is shutting down or the lobby was unloaded, retry later or join again
+    Unavailable,
+    Internal,
+}
+
+impl From<&VMErrors> for ErrorCode {
+    fn from(error: &VMErrors) -> Self {
+        match error {
+            VMErrors::ProcessNotFound(_)
+            | VMErrors::DocumentNotFound(_)
+            | VMErrors::CollectionNotFound(_)
+            | VMErrors::GroupNotFound(_) => ErrorCode::NotFound,
+            VMErrors::DocumentLocked(_) => ErrorCode::Locked,
+            VMErrors::GroupExists(_) => ErrorCode::Conflict,
+            VMErrors::InvalidOrderKey(_)
+            | VMErrors::InvalidGroupMember(_)
+            | VMErrors::InvalidNumber(_) => ErrorCode::InvalidArgument,
+            VMErrors::SerializationError(_)
+            | VMErrors::WebSocketError(_)
+            | VMErrors::CollectionUpdateError(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+/// an input the lobby refused, reported back to its sender with `code`
+#[derive(Debug)]
+pub struct Rejection {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl Rejection {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> anyhow::Error {
+        anyhow::Error::new(Self { code, message: message.into() })
+    }
+
+    /// wrap a vm error of operation `op`
+    pub fn vm(op: &str, error: VMErrors) -> anyhow::Error {
+        Self::error((&error).into(), format!("{} failed: {:?}", op, error))
+    }
+
+    /// the code of any error, `Internal` unless it is a rejection
+    pub fn code_of(error: &anyhow::Error) -> ErrorCode {
+        error.downcast_ref::<Self>().map(|r| r.code).unwrap_or(ErrorCode::Internal)
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Rejection {}
@@ -0,0 +1,196 @@
+//! the http side of the listener: websocket upgrades on `/ws`, the rest api on `/api`, the
+//! operator api on `/admin`, and `/healthz`, `/readyz` and `/metrics` for the deployment
+use crate::{
+    admin, encoding::Encoding, metrics::METRICS, rest, server::Server, ws::handle_websocket,
+};
+use http_body_util::Full;
+use hyper::{
+    Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::{
+        CONNECTION, CONTENT_TYPE, HeaderValue, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY,
+        SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE,
+    },
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use std::{convert::Infallible, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{handshake::derive_accept_key, protocol::Role},
+};
+use tracing::{Instrument, Span, debug, info_span, warn};
+use url::Url;
+
+pub(crate) type Body = Full<Bytes>;
+
+/// serve a new tcp connection, after the tls handshake when there is an acceptor
+pub async fn accept(stream: TcpStream, tls: Option<TlsAcceptor>, server: Arc<Server>) {
+    let Some(tls) = tls else {
+        return serve_connection(stream, server).await;
+    };
+    let handshake_timeout = server.config().handshake_timeout;
+    match tokio::time::timeout(handshake_timeout, tls.accept(stream)).await {
+        Ok(Ok(stream)) => serve_connection(stream, server).await,
+        Ok(Err(e)) => debug!(error = %e, "tls handshake failed"),
+        Err(_) => debug!("tls handshake timed out"),
+    }
+}
+
+/// serve one connection, plain tcp or tls, until it closes or was upgraded to a websocket
+pub async fn serve_connection<S>(stream: S, server: Arc<Server>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // a client that stalls sending its request would otherwise hold the socket forever
+    let handshake_timeout = server.config().handshake_timeout;
+    let closing = server.closing();
+    let service = service_fn(move |req| {
+        let server = server.clone();
+        let span = info_span!("request", method = %req.method(), path = %req.uri().path());
+        async move { Ok::<_, Infallible>(route(req, server).await) }.instrument(span)
+    });
+    let connection = http1::Builder::new()
+        .timer(TokioTimer::new())
+        .header_read_timeout(handshake_timeout)
+        .serve_connection(TokioIo::new(stream), service)
+        .with_upgrades();
+    let mut connection = std::pin::pin!(connection);
+    // on shutdown, requests in flight are answered and keep-alive ends; upgraded websockets
+    // are no longer part of the connection and close on their own
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = closing => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = result {
+        debug!(error = %e, "http connection error");
+    }
+}
+
+async fn route(req: Request<Incoming>, server: Arc<Server>) -> Response<Body> {
+    let path = req.uri().path();
+    if path == "/ws" || path.starts_with("/ws/") {
+        upgrade(req, server)
+    } else if path == "/api" || path.starts_with("/api/") {
+        rest::handle(req, server).await
+    } else if path == "/admin" || path.starts_with("/admin/") {
+        admin::handle(req, server).await
+    } else {
+        match path {
+            // the process is up
+            "/healthz" => text(StatusCode::OK, "ok"),
+            "/readyz" => match server.ready().await {
+                Ok(_) => text(StatusCode::OK, "ready"),
+                Err(e) => text(StatusCode::SERVICE_UNAVAILABLE, &e.to_string()),
+            },
+            "/metrics" => match METRICS.render(&server) {
+                Ok(metrics) => {
+                    let mut response = text(StatusCode::OK, &metrics);
+                    response.headers_mut().insert(
+                        CONTENT_TYPE,
+                        HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+                    );
+                    response
+                }
+                Err(e) => text(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+            },
+            _ => text(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+/// answer a websocket upgrade, the connection is handed to `handle_websocket` once hyper
+/// released it
+fn upgrade(mut req: Request<Incoming>, server: Arc<Server>) -> Response<Body> {
+    let headers = req.headers();
+    let header = |name| headers.get(name).and_then(|v: &HeaderValue| v.to_str().ok());
+    if !header(UPGRADE).is_some_and(|v| v.eq_ignore_ascii_case("websocket")) {
+        return text(StatusCode::UPGRADE_REQUIRED, "expected a websocket upgrade");
+    }
+    if header(SEC_WEBSOCKET_VERSION) != Some("13") {
+        let mut response = text(StatusCode::BAD_REQUEST, "unsupported websocket version");
+        response.headers_mut().insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+        return response;
+    }
+    let Some(key) = headers.get(SEC_WEBSOCKET_KEY) else {
+        return text(StatusCode::BAD_REQUEST, "missing Sec-WebSocket-Key");
+    };
+    let accept = derive_accept_key(key.as_bytes());
+
+    // lobby joined on connect, more are joined with `JoinProcess`
+    let mut process_id = None;
+    // epoch and last seq seen in that lobby, when reconnecting
+    let mut since = None;
+    let dummy = format!("ws://placeholder{}", req.uri());
+    if let Ok(url) = Url::parse(&dummy) {
+        let segments: Vec<&str> = url.path_segments().unwrap().collect();
+
+        // route: /ws/:pid or /ws
+        if segments.len() >= 2 && segments[0] == "ws" && !segments[1].is_empty() {
+            process_id = Some(segments[1].to_string());
+        }
+        // query: ?since=<seq>&epoch=<epoch>
+        let query = |name: &str| {
+            url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned())
+        };
+        since = query("epoch").zip(query("since").and_then(|seq| seq.parse().ok()));
+    }
+
+    // subprotocol: olta.msgpack, olta.cbor or olta.json, json when none is offered
+    let negotiated = header(SEC_WEBSOCKET_PROTOCOL).and_then(Encoding::negotiate);
+    let encoding = negotiated.unwrap_or_default();
+
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    tokio::spawn(
+        async move {
+            match on_upgrade.await {
+                Ok(upgraded) => {
+                    let ws_stream = WebSocketStream::from_raw_socket(
+                        TokioIo::new(upgraded),
+                        Role::Server,
+                        None,
+                    )
+                    .await;
+                    handle_websocket(ws_stream, process_id, since, encoding, server).await;
+                }
+                Err(e) => warn!(error = %e, "ws upgrade failed"),
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    let mut response = Response::new(Body::default());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    if let Ok(accept) = HeaderValue::from_str(&accept) {
+        headers.insert(SEC_WEBSOCKET_ACCEPT, accept);
+    }
+    if let Some(negotiated) = negotiated {
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(negotiated.protocol()));
+    }
+    response
+}
+
+/// a file of `content_type`
+pub(crate) fn file(content_type: &'static str, bytes: Vec<u8>) -> Response<Body> {
+    let mut response = Response::new(Body::from(bytes));
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+pub(crate) fn text(status: StatusCode, body: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+}
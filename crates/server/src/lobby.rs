@@ -0,0 +1,1401 @@
+//! one task per lobby: it owns the `Lobby` and its subscribers, and applies instructions in
+//! the order they arrive on its channel
+use crate::{
+    encoding::Frames,
+    errors::{ErrorCode, Rejection},
+    messages::{
+        DocumentLock, DocumentUpdate, Input, LegacyOutput, Output, OutputMessage, Participant,
+        SubscriptionFilter,
+    },
+    metrics::METRICS,
+    outbox::Delivery,
+    preview::{self, Format},
+    types::Subscriber,
+    view::{Projection, View},
+};
+use anyhow::{Error, anyhow};
+use export::Scene;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use storage::{Database, DbJob, DbOperation};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, frame::coding::CloseCode};
+use tracing::{Instrument, Span, debug, error, info, info_span, warn};
+use uuid::Uuid;
+use vm::{
+    Lobby,
+    types::{Document, DocumentChanges, GroupMember, Placement, Transform},
+};
+
+/// lease duration when the client does not ask for one
+const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(5);
+/// upper bound on a single lease, holders renew to keep it longer
+pub(crate) const MAX_LEASE_TTL: Duration = Duration::from_secs(30);
+/// how often leases that were not renewed are released
+const LEASE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// minimum gap between two relayed awareness updates of one participant, newer states in
+/// between replace the pending one
+pub(crate) const AWARENESS_INTERVAL: Duration = Duration::from_millis(50);
+/// upper bound on a serialized awareness state
+pub(crate) const MAX_AWARENESS_BYTES: usize = 4096;
+
+/// per-lobby limits, from the server config
+#[derive(Debug, Clone, Copy)]
+pub struct LobbySettings {
+    /// past events kept for resuming clients
+    pub event_buffer: usize,
+    /// documents per `SyncChunk`
+    pub sync_chunk: usize,
+    /// when set, document updates are held and sent as one `DocumentsUpdated` per tick
+    pub tick: Option<Duration>,
+    /// whether inputs are acked before or after their change is stored
+    pub durability: Durability,
+    /// changes are written once the lobby was quiet this long
+    pub save_debounce: Duration,
+    /// and at the latest this long after the first unsaved change
+    pub save_max_delay: Duration,
+    /// least time between two preview images, none are rendered when unset
+    pub preview_interval: Option<Duration>,
+}
+
+/// when a change to a lobby reaches the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// the db worker writes changed lobbies in the background, debounced; a crash loses at most
+    /// the last `save_max_delay` of changes
+    WriteBehind,
+    /// every change is written before its input is acked
+    Sync,
+}
+
+impl FromStr for Durability {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "write-behind" => Ok(Durability::WriteBehind),
+            "sync" => Ok(Durability::Sync),
+            other => Err(anyhow!("unknown durability {}, expected write-behind or sync", other)),
+        }
+    }
+}
+
+pub type LobbyRead = Box<dyn FnOnce(&Lobby, u64) + Send>;
+
+pub enum LobbyCommand {
+    /// register a subscriber, which receives a `FullSync` before any broadcast and then an
+    /// `Ack` tagged with `request_id`; with `since`, the epoch and last sequence number the
+    /// client saw, the events it missed are replayed instead when they are still buffered.
+    /// with `filter`, the sync and later broadcasts are narrowed to it. `legacy` subscribers
+    /// never sent a `Hello` and get a single `LegacyOutput::FullSync` instead, they are never
+    /// resumed
+    Subscribe {
+        connection_id: String,
+        since: Option<(String, u64)>,
+        filter: Option<SubscriptionFilter>,
+        request_id: Option<String>,
+        subscriber: Subscriber,
+        legacy: bool,
+    },
+    /// apply an input, its sender gets an `Ack` or an `Error` tagged with `request_id`, on
+    /// `reply` when given instead of its subscription; `address` is the sender's verified
+    /// address, if it authenticated; the lobby logs about it within `span`
+    Input {
+        connection_id: String,
+        address: Option<String>,
+        request_id: Option<String>,
+        input: Box<Input>,
+        reply: Option<oneshot::Sender<Output>>,
+        span: Span,
+    },
+    /// run a read against the lobby state and its current seq, see `LobbyHandle::read`
+    Read(LobbyRead),
+    /// a connection went away, its subscription, presence and leases are dropped
+    Disconnect { connection_id: String },
+    /// like `Disconnect`, but the connection stays open and gets an `Ack` first
+    Leave { connection_id: String, request_id: Option<String> },
+    /// end every subscription with an `Unavailable` error, write unsaved changes when `save`
+    /// is set, answer on `reply` once the db worker is done with them and stop the task
+    Shutdown { save: bool, reply: oneshot::Sender<Result<(), Error>> },
+}
+
+/// cheap, cloneable address of a lobby task
+#[derive(Debug, Clone)]
+pub struct LobbyHandle {
+    sender: mpsc::UnboundedSender<LobbyCommand>,
+}
+
+impl LobbyHandle {
+    pub fn spawn(
+        pid: &str,
+        storage: Database,
+        db_sender: mpsc::UnboundedSender<DbJob>,
+        settings: LobbySettings,
+    ) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let pid = pid.to_string();
+
+        let span = info_span!(parent: None, "lobby", process_id = %pid);
+        tokio::spawn(
+            async move {
+                match LobbyActor::load(&pid, storage, db_sender, settings).await {
+                    Ok(actor) => actor.run(receiver).await,
+                    Err(e) => {
+                        error!(error = %e, "failed to load lobby");
+                        reject_all(receiver, &pid, &e, &HashMap::new()).await;
+                    }
+                }
+            }
+            .instrument(span),
+        );
+
+        Self { sender }
+    }
+
+    /// false once the lobby task stopped
+    pub fn is_alive(&self) -> bool {
+        !self.sender.is_closed()
+    }
+
+    pub fn send(&self, command: LobbyCommand) -> Result<(), Error> {
+        self.sender.send(command).map_err(|_| unloaded())
+    }
+
+    /// what `read` makes of the lobby, in order with the commands sent before
+    pub async fn read<T: Send + 'static>(
+        &self,
+        read: impl FnOnce(&Lobby, u64) -> T + Send + 'static,
+    ) -> Result<T, Error> {
+        let (reply, response) = oneshot::channel();
+        self.send(LobbyCommand::Read(Box::new(move |lobby, seq| {
+            let _ = reply.send(read(lobby, seq));
+        })))?;
+        // dropped unanswered when the lobby could not be loaded
+        response.await.map_err(|_| anyhow!("lobby unavailable"))
+    }
+
+    /// unload the lobby, see `LobbyCommand::Shutdown`; a lobby that was never loaded has
+    /// nothing to save
+    pub async fn stop(&self, save: bool) -> Result<(), Error> {
+        let (reply, response) = oneshot::channel();
+        self.send(LobbyCommand::Shutdown { save, reply })?;
+        response.await.unwrap_or(Ok(()))
+    }
+}
+
+/// send what a filtered subscriber gets of an event, each with the event's seq; false once
+/// the subscriber is closed
+fn send_projected(
+    subscriber: &Subscriber,
+    outputs: Vec<Output>,
+    seq: Option<u64>,
+) -> Result<bool, Error> {
+    for output in outputs {
+        let delivery = Delivery::of(&output);
+        let kind = output.kind();
+        let frame = subscriber.encoding().encode(&OutputMessage { output, seq })?;
+        if !subscriber.send(frame, delivery) {
+            return Ok(false);
+        }
+        METRICS.messages_out.with_label_values(&[kind]).inc();
+    }
+    Ok(true)
+}
+
+/// how many of the latest events a client that last saw `since` in its epoch missed, none
+/// when it cannot be resumed: the epoch is another load's, `since` was never sent, or events
+/// after it are no longer buffered. `oldest` is the seq of the oldest buffered event, the
+/// buffer holds consecutive seqs up to the current one
+fn missed_events(
+    (epoch, since): (&str, u64),
+    (current_epoch, seq): (&str, u64),
+    oldest: Option<u64>,
+) -> Option<u64> {
+    if epoch != current_epoch || since > seq {
+        return None;
+    }
+    if since < seq && oldest.is_none_or(|oldest| since.saturating_add(1) < oldest) {
+        return None;
+    }
+    Some(seq - since)
+}
+
+fn unloaded() -> Error {
+    Rejection::error(ErrorCode::Unavailable, "lobby unloaded, join it again")
+}
+
+/// answer the commands of a lobby that cannot serve them with `error`, until every handle is
+/// dropped or the closed channel is empty; `subscribers` are the connections inputs without
+/// a `reply` are answered on
+async fn reject_all(
+    mut receiver: mpsc::UnboundedReceiver<LobbyCommand>,
+    pid: &str,
+    error: &Error,
+    subscribers: &HashMap<String, Subscriber>,
+) {
+    let message = |request_id| Output::Error {
+        process_id: Some(pid.to_string()),
+        code: Rejection::code_of(error),
+        message: error.to_string(),
+        request_id,
+    };
+    while let Some(command) = receiver.recv().await {
+        match command {
+            LobbyCommand::Subscribe { subscriber, request_id, .. } => {
+                let _ = subscriber.send_output(&message(request_id));
+            }
+            LobbyCommand::Input { request_id, reply: Some(reply), .. } => {
+                let _ = reply.send(message(request_id));
+            }
+            LobbyCommand::Input { connection_id, request_id, reply: None, .. } => {
+                if let Some(subscriber) = subscribers.get(&connection_id) {
+                    let _ = subscriber.send_output(&message(request_id));
+                }
+            }
+            LobbyCommand::Shutdown { reply, .. } => {
+                let _ = reply.send(Ok(()));
+            }
+            // nothing to leave or read, a dropped read is answered by `LobbyHandle::read`
+            LobbyCommand::Leave { .. }
+            | LobbyCommand::Disconnect { .. }
+            | LobbyCommand::Read(_) => {}
+        }
+    }
+}
+
+struct LobbyActor {
+    pid: String,
+    lobby: Lobby,
+    // bumped by every broadcast except awareness relays, from 0 on every load
+    seq: u64,
+    // random id of this load, a seq is only resumed from in the epoch it was sent in, so one
+    // from before a reload or restart is never taken for a recent one
+    epoch: String,
+    // latest broadcasts with their seq, oldest first, for resuming clients
+    events: VecDeque<OutputMessage>,
+    settings: LobbySettings,
+    // connection_id -> ws subscriber
+    subscribers: HashMap<String, Subscriber>,
+    // connection_id -> what a filtered subscriber follows, unfiltered ones get everything
+    views: HashMap<String, View>,
+    // subscribers synced in the legacy format, which only follow the process of their url
+    legacy: HashSet<String>,
+    // connection_id -> presence
+    participants: BTreeMap<String, Participant>,
+    // connection_id -> when its awareness was last relayed
+    awareness_relayed: HashMap<String, Instant>,
+    // connections whose latest awareness was held back by the rate limit
+    awareness_pending: BTreeSet<String>,
+    // (collection, doc_id) -> changes held for the next broadcast tick, merged
+    updates_pending: BTreeMap<(String, String), DocumentChanges>,
+    // when the lobby last changed, and when it first changed since it was saved
+    changed_at: Instant,
+    unsaved_since: Option<Instant>,
+    // when the last preview image was rendered
+    previewed_at: Option<Instant>,
+    // previews being rendered, each queues its write once done; awaited before the lobby is
+    // unloaded so none lands after an admin deletes, archives or renames the process
+    rendering: Vec<JoinHandle<()>>,
+    // persistent storage
+    storage: Database,
+    // background writes
+    db_sender: mpsc::UnboundedSender<DbJob>,
+}
+
+impl LobbyActor {
+    /// load the lobby from the database, or create it if it was never stored
+    async fn load(
+        pid: &str,
+        storage: Database,
+        db_sender: mpsc::UnboundedSender<DbJob>,
+        settings: LobbySettings,
+    ) -> Result<Self, Error> {
+        let lobby = match storage.load_process_state(pid).await {
+            Ok(Some(state_json)) => serde_json::from_str(&state_json)
+                .map_err(|e| anyhow!("failed to deserialize lobby: {}", e))?,
+            Ok(None) => {
+                info!("creating new lobby");
+                // stored like any other change
+                let mut lobby = Lobby::new(pid);
+                lobby.hot = true;
+                lobby
+            }
+            Err(e) => return Err(anyhow!("failed to load lobby from storage: {}", e)),
+        };
+
+        Ok(Self {
+            pid: pid.to_string(),
+            lobby,
+            seq: 0,
+            epoch: Uuid::new_v4().to_string(),
+            events: VecDeque::with_capacity(settings.event_buffer),
+            settings,
+            subscribers: HashMap::new(),
+            legacy: HashSet::new(),
+            views: HashMap::new(),
+            participants: BTreeMap::new(),
+            awareness_relayed: HashMap::new(),
+            awareness_pending: BTreeSet::new(),
+            updates_pending: BTreeMap::new(),
+            changed_at: Instant::now(),
+            unsaved_since: None,
+            previewed_at: None,
+            rendering: Vec::new(),
+            storage,
+            db_sender,
+        })
+    }
+
+    async fn run(mut self, mut receiver: mpsc::UnboundedReceiver<LobbyCommand>) {
+        let mut lease_sweep = tokio::time::interval(LEASE_SWEEP_INTERVAL);
+        let mut awareness_flush = tokio::time::interval(AWARENESS_INTERVAL);
+        awareness_flush.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        // updates are only held when there is a tick
+        let mut broadcast_tick =
+            tokio::time::interval(self.settings.tick.unwrap_or(LEASE_SWEEP_INTERVAL));
+        broadcast_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        if let Err(e) = self.changed().await {
+            error!(error = %e, "failed to save lobby");
+        }
+
+        loop {
+            let save_due = self.save_due();
+            tokio::select! {
+                command = receiver.recv() => match command {
+                    Some(LobbyCommand::Shutdown { save, reply }) => {
+                        let _ = reply.send(self.stop(save).await);
+                        break;
+                    }
+                    Some(command) => self.handle_command(command).await,
+                    None => {
+                        if let Err(e) = self.stop(true).await {
+                            error!(error = %e, "failed to save lobby");
+                        }
+                        break;
+                    }
+                },
+                _ = lease_sweep.tick() => self.expire_leases(),
+                _ = awareness_flush.tick(), if !self.awareness_pending.is_empty() => {
+                    self.flush_awareness();
+                }
+                _ = broadcast_tick.tick(), if !self.updates_pending.is_empty() => {
+                    if let Err(e) = self.flush_updates() {
+                        error!(error = %e, "failed to broadcast updates");
+                    }
+                }
+                _ = tokio::time::sleep_until(save_due.unwrap_or_else(Instant::now).into()),
+                    if save_due.is_some() =>
+                {
+                    if let Err(e) = self.save_behind(true) {
+                        error!(error = %e, "failed to save lobby");
+                    }
+                }
+            }
+        }
+
+        // commands sent while the lobby was stopping are answered, later ones fail to send
+        receiver.close();
+        reject_all(receiver, &self.pid, &unloaded(), &self.subscribers).await;
+    }
+
+    async fn handle_command(&mut self, command: LobbyCommand) {
+        match command {
+            LobbyCommand::Subscribe {
+                connection_id,
+                since,
+                filter,
+                request_id,
+                subscriber,
+                legacy,
+            } => {
+                let view = filter.map(View::new);
+                self.subscribe(connection_id.clone(), since, view, subscriber, legacy);
+                let ack = self.ack(request_id, None);
+                self.send_to(&connection_id, &ack);
+            }
+            LobbyCommand::Input { connection_id, address, request_id, input, reply, span } => {
+                async {
+                    let timer = METRICS
+                        .instruction_seconds
+                        .with_label_values(&[input.kind()])
+                        .start_timer();
+                    let mut result = self.handle_input(&connection_id, address, *input);
+                    timer.observe_duration();
+                    // the change is applied and broadcast either way
+                    if let Err(e) = self.changed().await {
+                        error!(error = %e, "failed to save lobby");
+                        result = result.and(Err(anyhow!("change applied but not saved: {}", e)));
+                    }
+                    let output = match result {
+                        Ok(doc_id) => self.ack(request_id, doc_id),
+                        Err(e) => Output::Error {
+                            process_id: Some(self.pid.clone()),
+                            code: Rejection::code_of(&e),
+                            message: e.to_string(),
+                            request_id,
+                        },
+                    };
+                    match reply {
+                        Some(reply) => {
+                            let _ = reply.send(output);
+                        }
+                        None => self.send_to(&connection_id, &output),
+                    }
+                }
+                .instrument(span)
+                .await
+            }
+            LobbyCommand::Disconnect { connection_id } => {
+                self.disconnect(&connection_id);
+            }
+            LobbyCommand::Leave { connection_id, request_id } => {
+                let ack = self.ack(request_id, None);
+                self.send_to(&connection_id, &ack);
+                self.disconnect(&connection_id);
+            }
+            LobbyCommand::Read(read) => read(&self.lobby, self.seq),
+            // handled by `run`
+            LobbyCommand::Shutdown { reply, .. } => {
+                let _ = reply.send(Ok(()));
+            }
+        }
+    }
+
+    /// send out held updates, close the subscriptions and wait for the db worker to write
+    /// the lobby if it changed since it was stored, or just for its queue when not `save`, and
+    /// for the previews being rendered
+    async fn stop(&mut self, save: bool) -> Result<(), Error> {
+        if let Err(e) = self.flush_updates() {
+            error!(error = %e, "failed to broadcast updates");
+        }
+        // clients join again, which loads the lobby again; only this subscription ends, the
+        // connection may follow other lobbies. legacy clients know nothing but reconnecting
+        let unloaded = Output::Error {
+            process_id: Some(self.pid.clone()),
+            code: ErrorCode::Unavailable,
+            message: unloaded().to_string(),
+            request_id: None,
+        };
+        for (connection_id, subscriber) in &self.subscribers {
+            if self.legacy.contains(connection_id) {
+                subscriber.close(Some(CloseFrame {
+                    code: CloseCode::Restart,
+                    reason: "lobby unloaded, reconnect".into(),
+                }));
+            } else if let Err(e) = subscriber.send_output(&unloaded) {
+                debug!(%connection_id, error = %e, "failed to tell subscriber");
+            }
+        }
+
+        self.unsaved_since = None;
+        let saving = save && self.lobby.hot;
+        let operation = if saving {
+            self.lobby.hot = false;
+            DbOperation::SaveProcessState {
+                process_id: self.pid.clone(),
+                full_state: serde_json::to_string(&self.lobby)?,
+                is_hot: false,
+            }
+        } else {
+            // earlier saves of this lobby may still be queued
+            DbOperation::Flush
+        };
+        let (done, written) = oneshot::channel();
+        let job = DbJob { operation, span: Span::current(), done: Some(done) };
+        self.db_sender.send(job).map_err(|_| anyhow!("db worker stopped"))?;
+        // the last state gets a preview whatever the interval
+        if saving {
+            self.preview(true);
+        }
+        // previews are written behind the state, wait for them too before the process can be
+        // changed by an admin
+        let previewed = !self.rendering.is_empty();
+        for render in self.rendering.drain(..) {
+            let _ = render.await;
+        }
+        let saved = written.await.map_err(|_| anyhow!("db worker stopped"))?;
+        if previewed {
+            let (done, written) = oneshot::channel();
+            let job =
+                DbJob { operation: DbOperation::Flush, span: Span::current(), done: Some(done) };
+            self.db_sender.send(job).map_err(|_| anyhow!("db worker stopped"))?;
+            written.await.map_err(|_| anyhow!("db worker stopped"))??;
+        }
+        saved
+    }
+
+    /// render a png of the lobby off the task and queue it for the db worker, behind the save
+    /// that was just queued; at most once per `preview_interval` unless `force`d
+    fn preview(&mut self, force: bool) {
+        let Some(interval) = self.settings.preview_interval else {
+            return;
+        };
+        if !force && self.previewed_at.is_some_and(|at| at.elapsed() < interval) {
+            return;
+        }
+        self.previewed_at = Some(Instant::now());
+
+        let scene = Scene::from_lobby(&self.lobby);
+        let (process_id, db_sender) = (self.pid.clone(), self.db_sender.clone());
+        let task = async move {
+            match preview::render(scene, Format::Png).await {
+                Ok(png) => {
+                    let operation = DbOperation::SavePreview { process_id, png };
+                    if db_sender.send(operation.into()).is_err() {
+                        debug!("db worker stopped before the preview was rendered");
+                    }
+                }
+                Err(e) => warn!(error = %e, "failed to render preview"),
+            }
+        };
+        self.rendering.retain(|render| !render.is_finished());
+        self.rendering.push(tokio::spawn(task.instrument(Span::current())));
+    }
+
+    /// after an input, store what it changed as the durability mode asks: now, or once the
+    /// save is due
+    async fn changed(&mut self) -> Result<(), Error> {
+        if !self.lobby.hot {
+            return Ok(());
+        }
+        match self.settings.durability {
+            Durability::Sync => self.save_now().await,
+            Durability::WriteBehind => {
+                let now = Instant::now();
+                self.changed_at = now;
+                self.unsaved_since.get_or_insert(now);
+                Ok(())
+            }
+        }
+    }
+
+    /// when the unsaved changes are to be written: after a quiet `save_debounce`, but no
+    /// later than `save_max_delay` after the first of them
+    fn save_due(&self) -> Option<Instant> {
+        let first = self.unsaved_since?;
+        let debounced = self.changed_at + self.settings.save_debounce;
+        Some(debounced.min(first + self.settings.save_max_delay))
+    }
+
+    /// queue the lobby for the db worker if it has unsaved changes; `is_hot` is false once
+    /// the lobby is unloaded
+    fn save_behind(&mut self, is_hot: bool) -> Result<(), Error> {
+        self.unsaved_since = None;
+        if !self.lobby.hot {
+            return Ok(());
+        }
+        self.lobby.hot = false;
+        let operation = DbOperation::SaveProcessState {
+            process_id: self.pid.clone(),
+            full_state: serde_json::to_string(&self.lobby)?,
+            is_hot,
+        };
+        self.db_sender.send(operation.into()).map_err(|_| anyhow!("db worker stopped"))?;
+        debug!("queued lobby for saving");
+        self.preview(false);
+        Ok(())
+    }
+
+    /// write the lobby and wait for it, it stays marked unsaved when the write fails
+    async fn save_now(&mut self) -> Result<(), Error> {
+        self.lobby.hot = false;
+        let full_state = serde_json::to_string(&self.lobby)?;
+        let saved = self.storage.save_process_state(&self.pid, &full_state, true).await;
+        self.lobby.hot = saved.is_err();
+        if saved.is_ok() {
+            self.preview(false);
+        }
+        saved
+    }
+
+    fn ack(&self, request_id: Option<String>, doc_id: Option<String>) -> Output {
+        Output::Ack { process_id: self.pid.clone(), request_id, doc_id, seq: self.seq }
+    }
+
+    /// apply one input, returning the id of the document it created or changed
+    fn handle_input(
+        &mut self,
+        connection_id: &str,
+        address: Option<String>,
+        input: Input,
+    ) -> Result<Option<String>, Error> {
+        match input {
+            Input::CreateDocument { collection_name, document } => {
+                let doc_id = self
+                    .create_document(&collection_name, document, address)
+                    .inspect_err(|e| warn!(error = %e, "failed to create document"))?;
+                info!(%doc_id, %collection_name, "created document");
+                Ok(Some(doc_id))
+            }
+
+            Input::UpdateDocument { collection_name, doc_id, changes } => {
+                self.update_document(&collection_name, &doc_id, changes, connection_id)
+                    .inspect_err(|e| warn!(error = %e, "failed to update document"))?;
+                debug!(%doc_id, %collection_name, "updated document");
+                Ok(Some(doc_id))
+            }
+
+            Input::DeleteDocument { collection_name, doc_id } => {
+                self.delete_document(&collection_name, &doc_id, connection_id)
+                    .inspect_err(|e| warn!(error = %e, "failed to delete document"))?;
+                info!(%doc_id, %collection_name, "deleted document");
+                Ok(Some(doc_id))
+            }
+
+            Input::MoveDocument { collection_name, doc_id, placement } => {
+                let order = self
+                    .move_document(&collection_name, &doc_id, placement, connection_id)
+                    .inspect_err(|e| warn!(error = %e, "failed to move document"))?;
+                info!(%doc_id, %collection_name, %order, "moved document");
+                Ok(Some(doc_id))
+            }
+
+            Input::AcquireLease { collection_name, doc_id, ttl_ms } => {
+                let ttl = self
+                    .acquire_lease(
+                        &collection_name,
+                        &doc_id,
+                        connection_id,
+                        ttl_ms.map(Duration::from_millis),
+                    )
+                    .inspect_err(|e| warn!(error = %e, "failed to lease document"))?;
+                debug!(%doc_id, %collection_name, ?ttl, "leased document");
+                Ok(Some(doc_id))
+            }
+
+            Input::ReleaseLease { collection_name, doc_id } => {
+                self.release_lease(&collection_name, &doc_id, connection_id)
+                    .inspect_err(|e| warn!(error = %e, "failed to release lease"))?;
+                Ok(Some(doc_id))
+            }
+
+            Input::CreateGroup { group, parent } => {
+                self.create_group(&group, parent.as_deref())
+                    .inspect_err(|e| warn!(error = %e, "failed to create group"))?;
+                info!(%group, "created group");
+                Ok(None)
+            }
+
+            Input::DeleteGroup { group } => {
+                self.delete_group(&group)
+                    .inspect_err(|e| warn!(error = %e, "failed to delete group"))?;
+                info!(%group, "deleted group");
+                Ok(None)
+            }
+
+            Input::AddToGroup { group, member } => {
+                self.add_to_group(&group, member)
+                    .inspect_err(|e| warn!(error = %e, "failed to add to group"))?;
+                Ok(None)
+            }
+
+            Input::RemoveFromGroup { member } => {
+                self.remove_from_group(member)
+                    .inspect_err(|e| warn!(error = %e, "failed to remove from group"))?;
+                Ok(None)
+            }
+
+            Input::TransformGroup { group, transform } => {
+                let count = self
+                    .transform_group(&group, &transform, connection_id)
+                    .inspect_err(|e| warn!(error = %e, "failed to transform group"))?;
+                debug!(%group, count, "transformed group");
+                Ok(None)
+            }
+
+            Input::SetAwareness { state } => {
+                self.set_awareness(connection_id, state)
+                    .inspect_err(|e| warn!(error = %e, "failed to set awareness"))?;
+                Ok(None)
+            }
+
+            // the handshake and subscriptions belong to the connection, see `ws.rs`
+            Input::Hello { .. }
+            | Input::Authenticate { .. }
+            | Input::JoinProcess { .. }
+            | Input::LeaveProcess { .. }
+            | Input::GetConnectionStats {} => Err(Rejection::error(
+                ErrorCode::InvalidMessage,
+                "Hello, Authenticate, JoinProcess, LeaveProcess and GetConnectionStats are not \
+                 lobby inputs",
+            )),
+        }
+    }
+
+    /// send the lobby state as a `FullSync`, `SyncChunk`s and a `SyncFinished`, narrowed to
+    /// the subscriber's view if it has one
+    fn sync(
+        &self,
+        connection_id: &str,
+        view: Option<&mut View>,
+        subscriber: &Subscriber,
+    ) -> Result<(), Error> {
+        let mut layers = self.lobby.get_layers().unwrap_or_default();
+        let mut locks = self.get_locks();
+        if let Some(view) = view {
+            layers.retain(|collection_name, _| view.wants_collection(collection_name));
+            for (collection_name, doc_ids) in layers.iter_mut() {
+                let collection = self.lobby.collections.get(collection_name);
+                doc_ids.retain(|doc_id| {
+                    collection
+                        .and_then(|c| c.get(doc_id))
+                        .is_some_and(|document| view.admit(collection_name, doc_id, document))
+                });
+            }
+            locks.retain(|lock| view.knows(&lock.collection_name, &lock.doc_id));
+        }
+
+        subscriber.send_output(&Output::FullSync {
+            process_id: self.pid.clone(),
+            connection_id: connection_id.to_string(),
+            seq: self.seq,
+            epoch: self.epoch.clone(),
+            documents: layers.values().map(Vec::len).sum(),
+            layers: layers.clone(),
+            locks,
+            groups: self.lobby.groups.clone(),
+            participants: self.participants.values().cloned().collect(),
+        })?;
+
+        // documents are cloned a chunk at a time, in layer order
+        for (collection_name, doc_ids) in &layers {
+            let Some(collection) = self.lobby.collections.get(collection_name) else {
+                continue;
+            };
+            for chunk in doc_ids.chunks(self.settings.sync_chunk) {
+                subscriber.send_output(&Output::SyncChunk {
+                    process_id: self.pid.clone(),
+                    collection_name: collection_name.clone(),
+                    documents: chunk
+                        .iter()
+                        .filter_map(|doc_id| {
+                            Some((doc_id.clone(), collection.get(doc_id)?.clone()))
+                        })
+                        .collect(),
+                })?;
+            }
+        }
+
+        subscriber
+            .send_output(&Output::SyncFinished { process_id: self.pid.clone(), seq: self.seq })
+    }
+
+    /// send the lobby state as one `LegacyOutput::FullSync`, narrowed to the subscriber's view
+    /// if it has one
+    fn sync_legacy(&self, view: Option<&mut View>, subscriber: &Subscriber) -> Result<(), Error> {
+        let mut collections = self.lobby.collections.clone();
+        if let Some(view) = view {
+            collections.retain(|collection_name, _| view.wants_collection(collection_name));
+            for (collection_name, collection) in collections.iter_mut() {
+                collection.retain(|doc_id, document| view.admit(collection_name, doc_id, document));
+            }
+        }
+
+        let full_sync = LegacyOutput::FullSync { process_id: self.pid.clone(), collections };
+        if !subscriber
+            .send(subscriber.encoding().encode(&full_sync)?, Delivery::Snapshot(self.pid.clone()))
+        {
+            return Err(anyhow!("subscriber closed"));
+        }
+        METRICS.messages_out.with_label_values(&["FullSync"]).inc();
+        Ok(())
+    }
+
+    /// send `Resumed` and the events broadcast after `since`, false when some of them are no
+    /// longer buffered or `since` is from another epoch
+    fn replay(
+        &self,
+        connection_id: &str,
+        (epoch, since): (String, u64),
+        mut view: Option<&mut View>,
+        subscriber: &Subscriber,
+    ) -> Result<bool, Error> {
+        let oldest = self.events.front().and_then(|event| event.seq);
+        let Some(missed) = missed_events((&epoch, since), (&self.epoch, self.seq), oldest) else {
+            return Ok(false);
+        };
+        // what a region sends depends on what was sent before, which the new connection lost
+        if view.as_deref().is_some_and(|view| !view.is_stateless()) {
+            return Ok(false);
+        }
+
+        subscriber.send_output(&Output::Resumed {
+            process_id: self.pid.clone(),
+            connection_id: connection_id.to_string(),
+            since,
+            seq: self.seq,
+            epoch: self.epoch.clone(),
+            participants: self.participants.values().cloned().collect(),
+        })?;
+        for event in self.events.iter().skip(self.events.len().saturating_sub(missed as usize)) {
+            let projection = match view.as_deref_mut() {
+                Some(view) => view.project(&event.output, &self.lobby),
+                None => Projection::All,
+            };
+            let sent = match projection {
+                Projection::All => {
+                    let sent = subscriber
+                        .send(subscriber.encoding().encode(event)?, Delivery::of(&event.output));
+                    if sent {
+                        METRICS.messages_out.with_label_values(&[event.output.kind()]).inc();
+                    }
+                    sent
+                }
+                Projection::Nothing => true,
+                Projection::Some(outputs) => send_projected(subscriber, outputs, event.seq)?,
+            };
+            if !sent {
+                return Err(anyhow!("subscriber closed"));
+            }
+        }
+        info!(%connection_id, events = missed, "replayed missed events");
+        Ok(true)
+    }
+
+    fn subscribe(
+        &mut self,
+        connection_id: String,
+        since: Option<(String, u64)>,
+        mut view: Option<View>,
+        subscriber: Subscriber,
+        legacy: bool,
+    ) {
+        let joined_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let participant =
+            Participant { connection_id: connection_id.clone(), joined_at, awareness: None };
+
+        // announce to the others first, the newcomer sees itself in its FullSync or replay
+        if let Err(e) = self.broadcast(Output::ParticipantJoined {
+            process_id: self.pid.clone(),
+            participant: participant.clone(),
+        }) {
+            error!(error = %e, "failed to broadcast join");
+        }
+        self.participants.insert(connection_id.clone(), participant);
+
+        let replayed = match since {
+            Some(_) if legacy => Ok(false),
+            Some(since) => self.replay(&connection_id, since, view.as_mut(), &subscriber),
+            None => Ok(false),
+        };
+        let synced = replayed.and_then(|replayed| match replayed {
+            true => Ok(()),
+            false if legacy => self.sync_legacy(view.as_mut(), &subscriber),
+            false => self.sync(&connection_id, view.as_mut(), &subscriber),
+        });
+        match synced {
+            Ok(_) => {
+                if let Some(view) = view {
+                    self.views.insert(connection_id.clone(), view);
+                }
+                if legacy {
+                    self.legacy.insert(connection_id.clone());
+                }
+                self.subscribers.insert(connection_id, subscriber);
+            }
+            Err(e) => {
+                warn!(%connection_id, error = %e, "failed to sync subscriber");
+                self.disconnect(&connection_id);
+            }
+        }
+    }
+
+    /// drop a connection's subscription, presence and leases, and tell the others
+    fn disconnect(&mut self, connection_id: &str) {
+        // a sync the client has not received yet is of no use to it anymore
+        if let Some(subscriber) = self.subscribers.remove(connection_id) {
+            subscriber.outbox.discard_sync(&self.pid);
+        }
+        self.views.remove(connection_id);
+        self.legacy.remove(connection_id);
+        self.awareness_relayed.remove(connection_id);
+        self.awareness_pending.remove(connection_id);
+
+        let unlocked = self.lobby.release_leases_held_by(connection_id);
+        self.broadcast_unlocked(unlocked);
+
+        if self.participants.remove(connection_id).is_some() {
+            if let Err(e) = self.broadcast(Output::ParticipantLeft {
+                process_id: self.pid.clone(),
+                connection_id: connection_id.to_string(),
+            }) {
+                error!(error = %e, "failed to broadcast leave");
+            }
+        }
+    }
+
+    /// keep the participant's latest state and relay it to the others, at most once per
+    /// `AWARENESS_INTERVAL`; it never reaches the vm or storage
+    fn set_awareness(
+        &mut self,
+        connection_id: &str,
+        state: serde_json::Value,
+    ) -> Result<(), Error> {
+        let size = serde_json::to_string(&state)
+            .map_err(|e| anyhow!("failed to serialize awareness: {}", e))?
+            .len();
+        if size > MAX_AWARENESS_BYTES {
+            return Err(Rejection::error(
+                ErrorCode::InvalidArgument,
+                format!("awareness state of {} bytes exceeds {}", size, MAX_AWARENESS_BYTES),
+            ));
+        }
+
+        let participant = self.participants.get_mut(connection_id).ok_or_else(|| {
+            Rejection::error(ErrorCode::NotFound, format!("{} is not a participant", connection_id))
+        })?;
+        participant.awareness = Some(state);
+
+        let throttled = self
+            .awareness_relayed
+            .get(connection_id)
+            .is_some_and(|relayed| relayed.elapsed() < AWARENESS_INTERVAL);
+        if throttled {
+            self.awareness_pending.insert(connection_id.to_string());
+            return Ok(());
+        }
+        self.relay_awareness(connection_id)
+    }
+
+    /// relay held back states whose interval has passed
+    fn flush_awareness(&mut self) {
+        let due: Vec<String> = self
+            .awareness_pending
+            .iter()
+            .filter(|connection_id| {
+                self.awareness_relayed
+                    .get(*connection_id)
+                    .is_none_or(|relayed| relayed.elapsed() >= AWARENESS_INTERVAL)
+            })
+            .cloned()
+            .collect();
+        for connection_id in due {
+            if let Err(e) = self.relay_awareness(&connection_id) {
+                error!(error = %e, "failed to relay awareness");
+            }
+        }
+    }
+
+    fn relay_awareness(&mut self, connection_id: &str) -> Result<(), Error> {
+        self.awareness_pending.remove(connection_id);
+        let Some(state) = self.participants.get(connection_id).and_then(|p| p.awareness.clone())
+        else {
+            return Ok(());
+        };
+        self.awareness_relayed.insert(connection_id.to_string(), Instant::now());
+
+        self.broadcast_except(
+            Output::AwarenessUpdated {
+                process_id: self.pid.clone(),
+                connection_id: connection_id.to_string(),
+                state,
+            },
+            Some(connection_id),
+        )
+    }
+
+    /// send to every subscriber as the next event, subscribers whose connection is gone or
+    /// whose queue overflowed are disconnected
+    fn broadcast(&mut self, message: Output) -> Result<(), Error> {
+        // held updates happened before this
+        self.flush_updates()?;
+
+        self.seq += 1;
+        let message = OutputMessage { output: message, seq: Some(self.seq) };
+        let timer = METRICS.broadcast_seconds.start_timer();
+        let closed = self.send_all(&message, None)?;
+        timer.observe_duration();
+
+        // buffered before dropping closed subscribers, whose leave is the event after it
+        if self.settings.event_buffer > 0 {
+            if self.events.len() == self.settings.event_buffer {
+                self.events.pop_front();
+            }
+            self.events.push_back(message);
+        }
+        self.drop_closed(closed);
+        Ok(())
+    }
+
+    /// broadcast the updates held since the last tick, as one event
+    fn flush_updates(&mut self) -> Result<(), Error> {
+        if self.updates_pending.is_empty() {
+            return Ok(());
+        }
+        let updates = std::mem::take(&mut self.updates_pending)
+            .into_iter()
+            .map(|((collection_name, doc_id), changes)| DocumentUpdate {
+                collection_name,
+                doc_id,
+                changes,
+            })
+            .collect();
+        self.broadcast(Output::DocumentsUpdated { process_id: self.pid.clone(), updates })
+    }
+
+    /// reply to a single connection
+    fn send_to(&mut self, connection_id: &str, message: &Output) {
+        let Some(subscriber) = self.subscribers.get(connection_id) else {
+            return;
+        };
+        match subscriber.encoding().encode(message) {
+            Ok(frame) => {
+                if !subscriber.send(frame, Delivery::of(message)) {
+                    warn!(%connection_id, "dropping closed subscriber");
+                    self.disconnect(connection_id);
+                    return;
+                }
+                METRICS.messages_out.with_label_values(&[message.kind()]).inc();
+            }
+            Err(e) => error!(error = %e, "failed to encode reply"),
+        }
+    }
+
+    /// send to every subscriber but `except`, without a seq
+    fn broadcast_except(&mut self, message: Output, except: Option<&str>) -> Result<(), Error> {
+        let closed = self.send_all(&OutputMessage { output: message, seq: None }, except)?;
+        self.drop_closed(closed);
+        Ok(())
+    }
+
+    /// returns the subscribers whose connection turned out to be closed
+    fn send_all(
+        &mut self,
+        message: &OutputMessage,
+        except: Option<&str>,
+    ) -> Result<Vec<String>, Error> {
+        // encoded once per encoding in use, not once per subscriber
+        let mut frames = Frames::new(message);
+        let delivery = Delivery::of(&message.output);
+        let sent_counter = METRICS.messages_out.with_label_values(&[message.output.kind()]);
+
+        let mut closed = Vec::new();
+        for (connection_id, sub) in &self.subscribers {
+            if Some(connection_id.as_str()) == except {
+                continue;
+            }
+            let projection = match self.views.get_mut(connection_id) {
+                Some(view) => view.project(&message.output, &self.lobby),
+                None => Projection::All,
+            };
+            let sent = match projection {
+                Projection::All => {
+                    let sent = sub.send(frames.get(sub.encoding())?, delivery.clone());
+                    if sent {
+                        sent_counter.inc();
+                    }
+                    sent
+                }
+                Projection::Nothing => true,
+                Projection::Some(outputs) => send_projected(sub, outputs, message.seq)?,
+            };
+            if !sent {
+                closed.push(connection_id.clone());
+            }
+        }
+        Ok(closed)
+    }
+
+    fn drop_closed(&mut self, closed: Vec<String>) {
+        for connection_id in closed {
+            warn!(%connection_id, "dropping closed subscriber");
+            self.disconnect(&connection_id);
+        }
+    }
+
+    fn create_document(
+        &mut self,
+        collection_name: &str,
+        mut document: Document,
+        creator: Option<String>,
+    ) -> Result<String, Error> {
+        // `_creator` is the verified address, never what the client claims
+        document.creator = creator
+            .ok_or_else(|| Rejection::error(ErrorCode::Unauthorized, "not authenticated"))?;
+        let doc_id = self
+            .lobby
+            .create_document(collection_name, document)
+            .map_err(|e| Rejection::vm("create_document", e))?;
+
+        // broadcast the stored document, which carries its assigned id and layer
+        let document = self
+            .lobby
+            .collections
+            .get(collection_name)
+            .and_then(|collection| collection.get(&doc_id))
+            .cloned()
+            .ok_or_else(|| anyhow!("created document {} not found", doc_id))?;
+        self.broadcast(Output::DocumentCreated {
+            process_id: self.pid.clone(),
+            collection_name: collection_name.to_string(),
+            doc_id: doc_id.clone(),
+            document,
+        })?;
+
+        Ok(doc_id)
+    }
+
+    fn update_document(
+        &mut self,
+        collection_name: &str,
+        doc_id: &str,
+        changes: DocumentChanges,
+        actor: &str,
+    ) -> Result<(), Error> {
+        let res = self
+            .lobby
+            .update_document(collection_name, doc_id, changes, actor)
+            .map_err(|e| Rejection::vm("update_document", e))?;
+
+        if self.settings.tick.is_some() {
+            self.updates_pending
+                .entry((collection_name.to_string(), doc_id.to_string()))
+                .or_default()
+                .merge(res);
+            return Ok(());
+        }
+        self.broadcast(Output::DocumentUpdated {
+            process_id: self.pid.clone(),
+            collection_name: collection_name.to_string(),
+            doc_id: doc_id.to_string(),
+            changes: res,
+        })
+    }
+
+    fn delete_document(
+        &mut self,
+        collection_name: &str,
+        doc_id: &str,
+        actor: &str,
+    ) -> Result<(), Error> {
+        let success = self
+            .lobby
+            .delete_document(collection_name, doc_id, actor)
+            .map_err(|e| Rejection::vm("delete_document", e))?;
+
+        if success {
+            self.broadcast(Output::DocumentDeleted {
+                process_id: self.pid.clone(),
+                collection_name: collection_name.to_string(),
+                doc_id: doc_id.to_string(),
+            })
+        } else {
+            Err(Rejection::error(
+                ErrorCode::NotFound,
+                format!("document {} not found in {}", doc_id, collection_name),
+            ))
+        }
+    }
+
+    fn move_document(
+        &mut self,
+        collection_name: &str,
+        doc_id: &str,
+        placement: Placement,
+        actor: &str,
+    ) -> Result<String, Error> {
+        let order = self
+            .lobby
+            .move_document(collection_name, doc_id, placement, actor)
+            .map_err(|e| Rejection::vm("move_document", e))?;
+
+        self.broadcast(Output::DocumentMoved {
+            process_id: self.pid.clone(),
+            collection_name: collection_name.to_string(),
+            doc_id: doc_id.to_string(),
+            order: order.clone(),
+        })?;
+        Ok(order)
+    }
+
+    /// current leases, for late joiners
+    fn get_locks(&self) -> Vec<DocumentLock> {
+        let now = Instant::now();
+        self.lobby
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.expires_at > now)
+            .map(|((collection_name, doc_id), lease)| DocumentLock {
+                collection_name: collection_name.clone(),
+                doc_id: doc_id.clone(),
+                holder: lease.holder.clone(),
+            })
+            .collect()
+    }
+
+    fn acquire_lease(
+        &mut self,
+        collection_name: &str,
+        doc_id: &str,
+        holder: &str,
+        ttl: Option<Duration>,
+    ) -> Result<Duration, Error> {
+        let ttl = ttl.unwrap_or(DEFAULT_LEASE_TTL).min(MAX_LEASE_TTL);
+        self.lobby
+            .acquire_lease(collection_name, doc_id, holder, ttl, Instant::now())
+            .map_err(|e| Rejection::vm("acquire_lease", e))?;
+
+        self.broadcast(Output::DocumentLocked {
+            process_id: self.pid.clone(),
+            collection_name: collection_name.to_string(),
+            doc_id: doc_id.to_string(),
+            holder: holder.to_string(),
+            ttl_ms: ttl.as_millis() as u64,
+        })?;
+        Ok(ttl)
+    }
+
+    fn release_lease(
+        &mut self,
+        collection_name: &str,
+        doc_id: &str,
+        holder: &str,
+    ) -> Result<(), Error> {
+        let released = self
+            .lobby
+            .release_lease(collection_name, doc_id, holder)
+            .map_err(|e| Rejection::vm("release_lease", e))?;
+
+        if released {
+            self.broadcast_unlocked(vec![(collection_name.to_string(), doc_id.to_string())]);
+        }
+        Ok(())
+    }
+
+    /// drop leases that were not renewed in time
+    fn expire_leases(&mut self) {
+        let unlocked = self.lobby.expire_leases(Instant::now());
+        self.broadcast_unlocked(unlocked);
+    }
+
+    fn broadcast_unlocked(&mut self, unlocked: Vec<(String, String)>) {
+        for (collection_name, doc_id) in unlocked {
+            let unlocked =
+                Output::DocumentUnlocked { process_id: self.pid.clone(), collection_name, doc_id };
+            if let Err(e) = self.broadcast(unlocked) {
+                error!(error = %e, "failed to broadcast unlock");
+            }
+        }
+    }
+
+    fn create_group(&mut self, group: &str, parent: Option<&str>) -> Result<(), Error> {
+        self.lobby.create_group(group, parent).map_err(|e| Rejection::vm("create_group", e))?;
+
+        self.broadcast(Output::GroupCreated {
+            process_id: self.pid.clone(),
+            group: group.to_string(),
+            parent: parent.map(str::to_string),
+        })
+    }
+
+    fn delete_group(&mut self, group: &str) -> Result<(), Error> {
+        self.lobby.delete_group(group).map_err(|e| Rejection::vm("delete_group", e))?;
+
+        self.broadcast(Output::GroupDeleted {
+            process_id: self.pid.clone(),
+            group: group.to_string(),
+        })
+    }
+
+    fn add_to_group(&mut self, group: &str, member: GroupMember) -> Result<(), Error> {
+        self.lobby
+            .add_to_group(group, member.clone())
+            .map_err(|e| Rejection::vm("add_to_group", e))?;
+
+        self.broadcast(Output::GroupMemberAdded {
+            process_id: self.pid.clone(),
+            group: group.to_string(),
+            member,
+        })
+    }
+
+    fn remove_from_group(&mut self, member: GroupMember) -> Result<(), Error> {
+        let group = self
+            .lobby
+            .remove_from_group(&member)
+            .ok_or_else(|| Rejection::error(ErrorCode::NotFound, "member is not in a group"))?;
+
+        self.broadcast(Output::GroupMemberRemoved { process_id: self.pid.clone(), group, member })
+    }
+
+    fn transform_group(
+        &mut self,
+        group: &str,
+        transform: &Transform,
+        actor: &str,
+    ) -> Result<usize, Error> {
+        let applied = self
+            .lobby
+            .transform_group(group, transform, actor)
+            .map_err(|e| Rejection::vm("transform_group", e))?;
+
+        let updates: Vec<DocumentUpdate> = applied
+            .into_iter()
+            .filter_map(|(member, changes)| match member {
+                GroupMember::Document { collection_name, doc_id } => {
+                    Some(DocumentUpdate { collection_name, doc_id, changes })
+                }
+                GroupMember::Group { .. } => None,
+            })
+            .collect();
+        let count = updates.len();
+
+        self.broadcast(Output::GroupTransformed {
+            process_id: self.pid.clone(),
+            group: group.to_string(),
+            updates,
+        })?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPOCH: &str = "3f1c";
+
+    #[test]
+    fn other_epochs_get_a_full_sync() {
+        assert_eq!(missed_events(("old", 5), (EPOCH, 10), Some(1)), None);
+        assert_eq!(missed_events(("", 0), (EPOCH, 0), None), None);
+    }
+
+    #[test]
+    fn seqs_older_than_the_buffer_get_a_full_sync() {
+        // 4 is missing from a buffer starting at 5
+        assert_eq!(missed_events((EPOCH, 3), (EPOCH, 10), Some(5)), None);
+        assert_eq!(missed_events((EPOCH, 4), (EPOCH, 10), Some(5)), Some(6));
+        // nothing buffered, e.g. with `event_buffer = 0`
+        assert_eq!(missed_events((EPOCH, 9), (EPOCH, 10), None), None);
+    }
+
+    #[test]
+    fn seqs_never_sent_get_a_full_sync() {
+        assert_eq!(missed_events((EPOCH, 11), (EPOCH, 10), Some(1)), None);
+        assert_eq!(missed_events((EPOCH, u64::MAX), (EPOCH, 10), Some(1)), None);
+    }
+
+    #[test]
+    fn the_latest_seq_replays_nothing() {
+        assert_eq!(missed_events((EPOCH, 10), (EPOCH, 10), Some(1)), Some(0));
+        assert_eq!(missed_events((EPOCH, 10), (EPOCH, 10), None), Some(0));
+        // a fresh load that broadcast nothing yet
+        assert_eq!(missed_events((EPOCH, 0), (EPOCH, 0), None), Some(0));
+    }
+
+    #[test]
+    fn missed_events_are_counted_from_since() {
+        assert_eq!(missed_events((EPOCH, 0), (EPOCH, 3), Some(1)), Some(3));
+        assert_eq!(missed_events((EPOCH, 7), (EPOCH, 10), Some(2)), Some(3));
+    }
+}
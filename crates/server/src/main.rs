@@ -1,81 +1,133 @@
-use std::sync::Arc;
+use std::{env, sync::Arc};
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::Mutex,
+    net::TcpListener,
+    signal::unix::{SignalKind, signal},
 };
-use tokio_tungstenite::{
-    accept_hdr_async,
-    tungstenite::handshake::server::{Request, Response},
+use tracing::{Instrument, debug, error, info, info_span, warn};
+use tracing_subscriber::{
+    EnvFilter, Registry, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt,
 };
-use url::Url;
 
+mod admin;
+mod auth;
+mod config;
+mod encoding;
+mod errors;
+mod http;
+mod lobby;
 mod messages;
+mod metrics;
+mod outbox;
+mod preview;
+mod rest;
 mod server;
+mod tls;
 mod types;
-mod utils;
+mod view;
 mod ws;
 
-use crate::utils::get_env_var;
+use crate::config::{Config, LogFormat};
+use anyhow::Error;
 use server::Server;
-use ws::handle_websocket;
+
+type LogFilter = reload::Handle<EnvFilter, Registry>;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let db_url = get_env_var("DATABASE_URL")?;
-    let host = get_env_var("HOST").unwrap_or_else(|_| "0.0.0.0".into());
-    let port: u16 = get_env_var("PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(8080);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", config::usage());
+        return Ok(());
+    }
+    let config = Config::load(&args)?;
+    let log_filter = init_tracing(&config)?;
+    let (host, port) = (config.host.clone(), config.port);
+    let shutdown_timeout = config.shutdown_timeout;
     let bind_addr = format!("{host}:{port}");
 
+    if config.session_secret.is_none() {
+        warn!("session_secret is not set, sessions will not survive a restart");
+    }
+    let tls = config.tls.as_ref().map(tls::acceptor).transpose()?;
+    let (ws, http) = if tls.is_some() { ("wss", "https") } else { ("ws", "http") };
+
     // server state
-    let server = Arc::new(Mutex::new(Server::new(&db_url).await?));
+    let server = Arc::new(Server::new(config).await?);
 
     let listener = TcpListener::bind(&bind_addr).await?;
-    println!("server listening on ws://{host}:{port}");
+    info!("server listening on {ws}://{host}:{port}/ws and {http}://{host}:{port}/api");
 
-    while let Ok((stream, addr)) = listener.accept().await {
-        println!("new ws connection from: {addr}");
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!(error = %e, "failed to accept connections");
+                    break;
+                }
+            },
+            _ = hangup.recv() => {
+                reload(&server, &args, &log_filter);
+                continue;
+            }
+            _ = terminate.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        debug!(peer = %addr, "new connection");
         let server = server.clone();
-        tokio::spawn(handle_connection(stream, server));
+        tokio::spawn(
+            http::accept(stream, tls.clone(), server).instrument(info_span!("http", peer = %addr)),
+        );
+    }
+
+    // no new connections, then give the open ones and the lobbies until the deadline
+    drop(listener);
+    info!("shutting down");
+    match tokio::time::timeout(shutdown_timeout, server.shutdown()).await {
+        Ok(Ok(())) => info!("shutdown complete"),
+        Ok(Err(e)) => error!(error = %e, "shutdown failed"),
+        Err(_) => warn!(timeout = ?shutdown_timeout, "shutdown deadline passed, stopping anyway"),
     }
 
     Ok(())
 }
 
-async fn handle_connection(stream: TcpStream, server: Arc<Mutex<Server>>) {
-    let mut process_id = String::from("default");
-    let expected_token = get_env_var("TOKEN").unwrap_or_else(|_| "OLTA".into());
-
-    let callback = |req: &Request, response: Response| {
-        let dummy = format!("ws://placeholder{}", req.uri());
-        if let Ok(url) = Url::parse(&dummy) {
-            let segments: Vec<&str> = url.path_segments().unwrap().collect();
-
-            // route: /ws/:pid
-            if segments.len() >= 2 && segments[0] == "ws" {
-                process_id = segments[1].to_string();
-            }
-
-            // token=? in query
-            let token_ok = url
-                .query_pairs()
-                .find(|(k, _)| k == "token")
-                .map(|(_, v)| v)
-                .map(|v| v == expected_token.as_str())
-                .unwrap_or(false);
+/// log to stdout, filtered by `log_filter`, which can be reloaded through the returned handle
+fn init_tracing(config: &Config) -> Result<LogFilter, Error> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(&config.log_filter)?);
+    let json = config.log_format == LogFormat::Json;
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json.then(|| fmt::layer().json()))
+        .with((!json).then(fmt::layer))
+        .try_init()?;
+    Ok(handle)
+}
 
-            if !token_ok {
-                return Err(Response::builder().status(401).body(None).unwrap());
-            }
+/// read the config again on SIGHUP; limits and timeouts apply to connections and lobbies that
+/// start afterwards, the log filter right away
+fn reload(server: &Server, args: &[String], log_filter: &LogFilter) {
+    let next = match Config::load(args) {
+        Ok(next) => next,
+        Err(e) => {
+            error!(error = %e, "invalid config, keeping the current one");
+            return;
         }
-
-        Ok(response)
     };
-
-    match accept_hdr_async(stream, callback).await {
-        Ok(ws_stream) => {
-            println!("client joined process: {process_id}");
-            handle_websocket(ws_stream, process_id, server).await;
+    let (next, kept) = server.config().reloaded(next);
+    for setting in kept {
+        warn!(setting, "changed setting only applies after a restart");
+    }
+    match EnvFilter::try_new(&next.log_filter) {
+        Ok(filter) => {
+            if let Err(e) = log_filter.reload(filter) {
+                error!(error = %e, "failed to reload log filter");
+            }
         }
-        Err(e) => eprintln!("ws connection error: {e}"),
+        Err(e) => error!(error = %e, "invalid log filter"),
     }
+    server.set_config(next);
+    info!("config reloaded");
 }
@@ -1,19 +1,325 @@
+use crate::{auth::Credentials, errors::ErrorCode, outbox::OverflowPolicy};
 use serde::{Deserialize, Serialize};
-use vm::types::{Collections, Document, DocumentChanges};
+use std::collections::{BTreeMap, BTreeSet};
+use vm::{
+    transform::Vec3,
+    types::{
+        Collection, CollectionName, Collections, Document, DocumentChanges, Group, GroupMember,
+        Placement, Transform,
+    },
+};
+
+/// version of the `Input`/`Output` protocol spoken by this server, bumped on breaking changes
+pub const PROTOCOL_VERSION: u32 = 1;
+/// oldest client protocol version still accepted; clients that never send a `Hello` predate
+/// versioning and are synced with a `LegacyOutput`
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// optional behaviour clients can rely on when the server lists it in its `Hello`
+pub const FEATURES: &[&str] = &[
+    "acks",
+    "auth",
+    "awareness",
+    "backpressure",
+    "batched-updates",
+    "chunked-sync",
+    "filters",
+    "multiplex",
+    "presence",
+    "resume",
+];
 
 #[derive(Serialize, Deserialize)]
 pub enum Input {
-    JoinProcess { process_id: String },
-    CreateDocument { collection_name: String, document: Document },
-    UpdateDocument { collection_name: String, doc_id: String, changes: DocumentChanges },
-    DeleteDocument { collection_name: String, doc_id: String },
+    // must come before any other input, incompatible clients are disconnected; without it the
+    // client is taken for one that predates versioning and synced with a `LegacyOutput`
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        features: Vec<String>,
+    },
+    // binds an address to the connection, required before any input that changes a lobby
+    Authenticate {
+        credentials: Credentials,
+    },
+    // with `since` and `epoch`, the last `seq` seen in the process before reconnecting and
+    // the epoch it belongs to, the missed events are replayed instead of sending a
+    // `FullSync`; with `filter`, only that part of the lobby is synced and followed
+    JoinProcess {
+        process_id: String,
+        #[serde(default)]
+        since: Option<u64>,
+        #[serde(default)]
+        epoch: Option<String>,
+        #[serde(default)]
+        filter: Option<SubscriptionFilter>,
+    },
+    LeaveProcess {
+        process_id: String,
+    },
+    // answered with the connection's `ConnectionStats`
+    GetConnectionStats {},
+    CreateDocument {
+        collection_name: String,
+        document: Document,
+    },
+    UpdateDocument {
+        collection_name: String,
+        doc_id: String,
+        changes: DocumentChanges,
+    },
+    DeleteDocument {
+        collection_name: String,
+        doc_id: String,
+    },
+    MoveDocument {
+        collection_name: String,
+        doc_id: String,
+        placement: Placement,
+    },
+    // acquiring a lease the connection already holds renews it
+    AcquireLease {
+        collection_name: String,
+        doc_id: String,
+        ttl_ms: Option<u64>,
+    },
+    ReleaseLease {
+        collection_name: String,
+        doc_id: String,
+    },
+    CreateGroup {
+        group: String,
+        parent: Option<String>,
+    },
+    DeleteGroup {
+        group: String,
+    },
+    AddToGroup {
+        group: String,
+        member: GroupMember,
+    },
+    RemoveFromGroup {
+        member: GroupMember,
+    },
+    TransformGroup {
+        group: String,
+        transform: Transform,
+    },
+    // ephemeral client state such as cursors or camera, relayed but never stored
+    SetAwareness {
+        state: serde_json::Value,
+    },
+}
+
+impl Input {
+    /// the variant name, as it appears on the wire
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Input::Hello { .. } => "Hello",
+            Input::Authenticate { .. } => "Authenticate",
+            Input::JoinProcess { .. } => "JoinProcess",
+            Input::LeaveProcess { .. } => "LeaveProcess",
+            Input::GetConnectionStats { .. } => "GetConnectionStats",
+            Input::CreateDocument { .. } => "CreateDocument",
+            Input::UpdateDocument { .. } => "UpdateDocument",
+            Input::DeleteDocument { .. } => "DeleteDocument",
+            Input::MoveDocument { .. } => "MoveDocument",
+            Input::AcquireLease { .. } => "AcquireLease",
+            Input::ReleaseLease { .. } => "ReleaseLease",
+            Input::CreateGroup { .. } => "CreateGroup",
+            Input::DeleteGroup { .. } => "DeleteGroup",
+            Input::AddToGroup { .. } => "AddToGroup",
+            Input::RemoveFromGroup { .. } => "RemoveFromGroup",
+            Input::TransformGroup { .. } => "TransformGroup",
+            Input::SetAwareness { .. } => "SetAwareness",
+        }
+    }
+
+    /// inputs that change a lobby need an authenticated connection, viewing does not
+    pub fn needs_auth(&self) -> bool {
+        !matches!(
+            self,
+            Input::Hello { .. }
+                | Input::Authenticate { .. }
+                | Input::JoinProcess { .. }
+                | Input::LeaveProcess { .. }
+                | Input::GetConnectionStats {}
+                | Input::SetAwareness { .. }
+        )
+    }
+}
+
+/// an `Input` as sent by clients, `request_id` is echoed in the `Ack` or `Error` it causes
+#[derive(Serialize, Deserialize)]
+pub struct InputMessage {
+    #[serde(flatten)]
+    pub input: Input,
+    // target lobby, defaults to the process of the connection url
+    #[serde(default)]
+    pub process_id: Option<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// the part of a lobby a subscriber follows, unset fields do not restrict it
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SubscriptionFilter {
+    #[serde(default)]
+    pub collections: Option<BTreeSet<String>>,
+    // documents positioned inside it, documents moving in or out are announced with
+    // `DocumentEnteredRegion` and `DocumentLeftRegion`
+    #[serde(default)]
+    pub region: Option<Region>,
+}
+
+/// axis-aligned box, bounds included
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Region {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+/// an `Output` as sent to clients, lobby events carry the lobby sequence number they
+/// advanced it to; clients resume from the last one they saw. numbers can be skipped, when a
+/// slow client's queued updates were coalesced
+#[derive(Serialize, Deserialize)]
+pub struct OutputMessage {
+    #[serde(flatten)]
+    pub output: Output,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DocumentLock {
+    pub collection_name: String,
+    pub doc_id: String,
+    pub holder: String,
+}
+
+/// bounds the server enforces, announced in its `Hello`
+#[derive(Serialize, Deserialize)]
+pub struct Limits {
+    pub max_joined_processes: usize,
+    pub max_awareness_bytes: usize,
+    pub awareness_interval_ms: u64,
+    pub max_lease_ttl_ms: u64,
+    // frames queued for a slow client before its overflow policy applies
+    pub outbound_queue_capacity: usize,
+    pub ping_interval_ms: u64,
+    // silence, pongs included, after which the connection is dropped
+    pub idle_timeout_ms: u64,
+    pub max_connection_lifetime_ms: u64,
+    // events kept per lobby for resuming clients
+    pub event_buffer_capacity: usize,
+    // documents per `SyncChunk`
+    pub sync_chunk_documents: usize,
+    // when set, document updates are sent as one `DocumentsUpdated` per tick
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broadcast_tick_ms: Option<u64>,
+}
+
+/// a connection subscribed to a lobby
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Participant {
+    pub connection_id: String,
+    // unix milliseconds
+    pub joined_at: u64,
+    // last awareness state, so late joiners see it without waiting for the next update
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub awareness: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DocumentUpdate {
+    pub collection_name: String,
+    pub doc_id: String,
+    pub changes: DocumentChanges,
+}
+
+/// the sync understood by clients from before `Hello`, which cannot parse chunked syncs
+#[derive(Serialize, Deserialize)]
+pub enum LegacyOutput {
+    // the whole lobby in one frame
+    FullSync { process_id: String, collections: Collections },
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum Output {
+    // first frame of every connection
+    Hello {
+        protocol_version: u32,
+        min_protocol_version: u32,
+        connection_id: String,
+        // subprotocols, see `Encoding`
+        encodings: Vec<String>,
+        features: Vec<String>,
+        limits: Limits,
+        // text to sign for `Authenticate`
+        challenge: String,
+    },
+    // answer to the client's `Hello`, with the features both sides support. a `Hello` later
+    // than 300 ms, see `ws::HELLO_TIMEOUT`, finds the process of the connection url synced by a
+    // `LegacyOutput::FullSync`; it is synced again after the `Welcome` and the client should
+    // replace what it got from the first sync
+    Welcome {
+        protocol_version: u32,
+        features: Vec<String>,
+        request_id: Option<String>,
+    },
+    // `session` authenticates later connections until `expires_at` (unix milliseconds)
+    Authenticated {
+        address: String,
+        session: String,
+        expires_at: u64,
+        request_id: Option<String>,
+    },
+    // outbound queue of the receiving connection, `dropped` and `coalesced` count frames
+    // the overflow policy did not send as is
+    ConnectionStats {
+        connection_id: String,
+        queue_depth: usize,
+        queue_capacity: usize,
+        overflow_policy: OverflowPolicy,
+        dropped: u64,
+        coalesced: u64,
+        request_id: Option<String>,
+    },
+    // start of a lobby's state, its documents follow in `SyncChunk`s up to a `SyncFinished`;
+    // filtered subscribers only get the documents and locks their filter lets through
     FullSync {
         process_id: String,
-        collections: Collections,
+        // the receiving connection, as it appears in `participants` and lease holders
+        connection_id: String,
+        // lobby sequence number the state is at, events after it carry higher ones
+        seq: u64,
+        // names this load of the lobby, seqs restart on the next one
+        epoch: String,
+        // documents in the chunks to come
+        documents: usize,
+        // document ids per collection in layer order, bottom to top
+        layers: BTreeMap<CollectionName, Vec<String>>,
+        locks: Vec<DocumentLock>,
+        groups: BTreeMap<String, Group>,
+        participants: Vec<Participant>,
+    },
+    SyncChunk {
+        process_id: String,
+        collection_name: String,
+        documents: Collection,
+    },
+    SyncFinished {
+        process_id: String,
+        seq: u64,
+    },
+    // sent instead of a `FullSync` to a client joining with `since`, followed by the events
+    // after `since` up to `seq`
+    Resumed {
+        process_id: String,
+        connection_id: String,
+        since: u64,
+        seq: u64,
+        epoch: String,
+        participants: Vec<Participant>,
     },
     DocumentCreated {
         process_id: String,
@@ -27,12 +333,133 @@ pub enum Output {
         doc_id: String,
         changes: DocumentChanges,
     },
+    // instead of `DocumentUpdated` when the lobby has a broadcast tick: the updates of one
+    // tick, with the changes to each document merged into one
+    DocumentsUpdated {
+        process_id: String,
+        updates: Vec<DocumentUpdate>,
+    },
     DocumentDeleted {
         process_id: String,
         collection_name: String,
         doc_id: String,
     },
+    DocumentMoved {
+        process_id: String,
+        collection_name: String,
+        doc_id: String,
+        order: String,
+    },
+    DocumentLocked {
+        process_id: String,
+        collection_name: String,
+        doc_id: String,
+        holder: String,
+        ttl_ms: u64,
+    },
+    DocumentUnlocked {
+        process_id: String,
+        collection_name: String,
+        doc_id: String,
+    },
+    GroupCreated {
+        process_id: String,
+        group: String,
+        parent: Option<String>,
+    },
+    GroupDeleted {
+        process_id: String,
+        group: String,
+    },
+    GroupMemberAdded {
+        process_id: String,
+        group: String,
+        member: GroupMember,
+    },
+    GroupMemberRemoved {
+        process_id: String,
+        group: String,
+        member: GroupMember,
+    },
+    // every member's resulting delta, in one message
+    GroupTransformed {
+        process_id: String,
+        group: String,
+        updates: Vec<DocumentUpdate>,
+    },
+    // sent to subscribers with a region instead of the update that moved a document in or out
+    DocumentEnteredRegion {
+        process_id: String,
+        collection_name: String,
+        doc_id: String,
+        document: Document,
+    },
+    DocumentLeftRegion {
+        process_id: String,
+        collection_name: String,
+        doc_id: String,
+    },
+    ParticipantJoined {
+        process_id: String,
+        participant: Participant,
+    },
+    ParticipantLeft {
+        process_id: String,
+        connection_id: String,
+    },
+    AwarenessUpdated {
+        process_id: String,
+        connection_id: String,
+        state: serde_json::Value,
+    },
+    // sent to the sender only, once its input was applied; `seq` is the lobby sequence
+    // number after it, an update held for the broadcast tick gets a later one
+    Ack {
+        process_id: String,
+        request_id: Option<String>,
+        doc_id: Option<String>,
+        seq: u64,
+    },
+    // sent to the sender only, `process_id` is unset for errors outside of a lobby
     Error {
+        process_id: Option<String>,
+        code: ErrorCode,
         message: String,
+        request_id: Option<String>,
     },
 }
+
+impl Output {
+    /// the variant name, as it appears on the wire
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Output::Hello { .. } => "Hello",
+            Output::Welcome { .. } => "Welcome",
+            Output::Authenticated { .. } => "Authenticated",
+            Output::ConnectionStats { .. } => "ConnectionStats",
+            Output::FullSync { .. } => "FullSync",
+            Output::SyncChunk { .. } => "SyncChunk",
+            Output::SyncFinished { .. } => "SyncFinished",
+            Output::Resumed { .. } => "Resumed",
+            Output::DocumentCreated { .. } => "DocumentCreated",
+            Output::DocumentUpdated { .. } => "DocumentUpdated",
+            Output::DocumentsUpdated { .. } => "DocumentsUpdated",
+            Output::DocumentDeleted { .. } => "DocumentDeleted",
+            Output::DocumentMoved { .. } => "DocumentMoved",
+            Output::DocumentLocked { .. } => "DocumentLocked",
+            Output::DocumentUnlocked { .. } => "DocumentUnlocked",
+            Output::GroupCreated { .. } => "GroupCreated",
+            Output::GroupDeleted { .. } => "GroupDeleted",
+            Output::GroupMemberAdded { .. } => "GroupMemberAdded",
+            Output::GroupMemberRemoved { .. } => "GroupMemberRemoved",
+            Output::GroupTransformed { .. } => "GroupTransformed",
+            Output::DocumentEnteredRegion { .. } => "DocumentEnteredRegion",
+            Output::DocumentLeftRegion { .. } => "DocumentLeftRegion",
+            Output::ParticipantJoined { .. } => "ParticipantJoined",
+            Output::ParticipantLeft { .. } => "ParticipantLeft",
+            Output::AwarenessUpdated { .. } => "AwarenessUpdated",
+            Output::Ack { .. } => "Ack",
+            Output::Error { .. } => "Error",
+        }
+    }
+}
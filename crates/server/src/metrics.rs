@@ -0,0 +1,110 @@
+//! process-wide prometheus metrics, rendered by `/metrics`
+use crate::server::Server;
+use anyhow::Error;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder, exponential_buckets,
+};
+use std::sync::{LazyLock, Mutex};
+
+pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    // open websocket connections
+    pub connections: IntGauge,
+    // inputs received, by variant
+    pub messages_in: IntCounterVec,
+    // messages queued for clients, by variant, once per recipient
+    pub messages_out: IntCounterVec,
+    // time to hand one broadcast to every subscriber of a lobby
+    pub broadcast_seconds: Histogram,
+    // time a lobby took to apply an input, by variant
+    pub instruction_seconds: HistogramVec,
+    // sampled from the server when rendering
+    lobbies: IntGauge,
+    db_queue_depth: IntGauge,
+    db_failed: IntCounter,
+    // serializes renders, which bring `db_failed` up to date
+    render: Mutex<()>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        // 10µs to about 2.6s, lobby work is mostly well under a millisecond
+        let buckets = exponential_buckets(0.00001, 4.0, 10).expect("valid buckets");
+        let metrics = Self {
+            registry: Registry::new(),
+            connections: IntGauge::new("olta_connections", "open websocket connections")
+                .expect("valid metric"),
+            messages_in: IntCounterVec::new(
+                Opts::new("olta_messages_in_total", "inputs received, by type"),
+                &["type"],
+            )
+            .expect("valid metric"),
+            messages_out: IntCounterVec::new(
+                Opts::new("olta_messages_out_total", "messages sent to clients, by type"),
+                &["type"],
+            )
+            .expect("valid metric"),
+            broadcast_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "olta_broadcast_seconds",
+                    "time to fan a broadcast out to a lobby's subscribers",
+                )
+                .buckets(buckets.clone()),
+            )
+            .expect("valid metric"),
+            instruction_seconds: HistogramVec::new(
+                HistogramOpts::new(
+                    "olta_vm_instruction_seconds",
+                    "time to apply an input to a lobby, by instruction",
+                )
+                .buckets(buckets),
+                &["instruction"],
+            )
+            .expect("valid metric"),
+            lobbies: IntGauge::new("olta_lobbies", "lobbies loaded in memory")
+                .expect("valid metric"),
+            db_queue_depth: IntGauge::new(
+                "olta_db_queue_depth",
+                "operations waiting for the database worker",
+            )
+            .expect("valid metric"),
+            db_failed: IntCounter::new(
+                "olta_db_failed_operations_total",
+                "database worker operations that failed",
+            )
+            .expect("valid metric"),
+            render: Mutex::new(()),
+        };
+
+        let registry = &metrics.registry;
+        for collector in [
+            Box::new(metrics.connections.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.messages_in.clone()),
+            Box::new(metrics.messages_out.clone()),
+            Box::new(metrics.broadcast_seconds.clone()),
+            Box::new(metrics.instruction_seconds.clone()),
+            Box::new(metrics.lobbies.clone()),
+            Box::new(metrics.db_queue_depth.clone()),
+            Box::new(metrics.db_failed.clone()),
+        ] {
+            registry.register(collector).expect("metric registered once");
+        }
+        metrics
+    }
+
+    /// every metric in the prometheus text format
+    pub fn render(&self, server: &Server) -> Result<String, Error> {
+        let _guard = self.render.lock().unwrap_or_else(|e| e.into_inner());
+        self.lobbies.set(server.lobby_count() as i64);
+        let db = server.db_stats();
+        self.db_queue_depth.set(db.queue_depth() as i64);
+        self.db_failed.inc_by(db.failed().saturating_sub(self.db_failed.get()));
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
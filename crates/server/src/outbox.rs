@@ -0,0 +1,514 @@
+//! bounded per-connection queue of outgoing frames, so a stalled client cannot grow server
+//! memory without limit
+use crate::{
+    encoding::Encoding,
+    messages::{Output, OutputMessage},
+};
+use anyhow::{Error, anyhow};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::{
+    Message,
+    protocol::{CloseFrame, frame::coding::CloseCode},
+};
+use tracing::{error, warn};
+
+/// what happens when a frame arrives while the queue is full; whenever nothing can give way,
+/// the connection is closed and the client has to reconnect for a fresh `FullSync`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// awareness relays are dropped, the oldest queued one first
+    DropEphemeral,
+    /// like `DropEphemeral`, but first a queued awareness state or document update is folded
+    /// into a newer one of the same participant or document, updates only when no other
+    /// document event is queued between them
+    Coalesce,
+    /// close the connection right away
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop-ephemeral" => Ok(OverflowPolicy::DropEphemeral),
+            "coalesce" => Ok(OverflowPolicy::Coalesce),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            other => Err(anyhow!(
+                "unknown overflow policy {}, expected drop-ephemeral, coalesce or disconnect",
+                other
+            )),
+        }
+    }
+}
+
+/// how a frame may be treated once the queue is full, keys name the participant or document
+/// it is about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Delivery {
+    /// must arrive, in order; updates are never coalesced across one, which may be about
+    /// the same documents
+    Reliable,
+    /// may be dropped or replaced by a newer state with the same key
+    Ephemeral(String),
+    /// partial document update, may be merged into a newer one with the same key
+    Update(String),
+    /// part of a sync of the keyed process, which already comes in bounded chunks; never
+    /// counts against the capacity, so a large lobby does not overflow the queue of a joining
+    /// client. what is left of it is discarded when the client leaves the process, so at most
+    /// one sync per joined process is queued
+    Snapshot(String),
+}
+
+impl Delivery {
+    pub fn of(output: &Output) -> Self {
+        match output {
+            Output::AwarenessUpdated { process_id, connection_id, .. } => {
+                Delivery::Ephemeral(format!("awareness/{}/{}", process_id, connection_id))
+            }
+            Output::DocumentUpdated { process_id, collection_name, doc_id, .. } => {
+                Delivery::Update(document_key(process_id, collection_name, doc_id))
+            }
+            Output::FullSync { process_id, .. }
+            | Output::SyncChunk { process_id, .. }
+            | Output::SyncFinished { process_id, .. } => Delivery::Snapshot(process_id.clone()),
+            _ => Delivery::Reliable,
+        }
+    }
+
+    fn key(&self) -> Option<&str> {
+        match self {
+            Delivery::Ephemeral(key) | Delivery::Update(key) => Some(key),
+            Delivery::Reliable | Delivery::Snapshot(_) => None,
+        }
+    }
+}
+
+fn document_key(process_id: &str, collection_name: &str, doc_id: &str) -> String {
+    format!("document/{}/{}/{}", process_id, collection_name, doc_id)
+}
+
+struct Queued {
+    frame: Message,
+    delivery: Delivery,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Queued>,
+    // queued `Delivery::Snapshot` frames, which are not bounded by the capacity
+    snapshots: usize,
+    // nothing more is accepted, queued frames are still delivered
+    closed: bool,
+    // ephemeral frames that were never sent
+    dropped: u64,
+    // frames merged into a queued one
+    coalesced: u64,
+}
+
+/// queue depth and what the overflow policy did so far
+pub(crate) struct OutboxStats {
+    pub depth: usize,
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+pub(crate) struct Outbox {
+    state: Mutex<State>,
+    ready: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    // of the queued frames, needed to merge updates
+    encoding: Encoding,
+}
+
+impl fmt::Debug for Outbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Outbox")
+            .field("capacity", &self.capacity)
+            .field("policy", &self.policy)
+            .field("encoding", &self.encoding)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: OverflowPolicy, encoding: Encoding) -> Self {
+        Self { state: Mutex::default(), ready: Notify::new(), capacity, policy, encoding }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// queue a frame, false once the outbox is closed; a full queue is handled by the policy
+    pub fn push(&self, frame: Message, delivery: Delivery) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.closed {
+            return false;
+        }
+
+        let accepted = if matches!(delivery, Delivery::Snapshot(_)) {
+            state.snapshots += 1;
+            state.queue.push_back(Queued { frame, delivery });
+            true
+        } else if state.queue.len() - state.snapshots < self.capacity {
+            state.queue.push_back(Queued { frame, delivery });
+            true
+        } else {
+            self.overflow(&mut state, frame, delivery)
+        };
+        self.ready.notify_one();
+        accepted
+    }
+
+    /// make room for `frame` in a full queue, false when the connection had to be closed
+    fn overflow(&self, state: &mut State, frame: Message, delivery: Delivery) -> bool {
+        state.queue.push_back(Queued { frame, delivery });
+
+        if self.policy == OverflowPolicy::Coalesce && self.coalesce(state) {
+            state.coalesced += 1;
+            return true;
+        }
+
+        if self.policy != OverflowPolicy::Disconnect {
+            let ephemeral =
+                state.queue.iter().position(|q| matches!(q.delivery, Delivery::Ephemeral(_)));
+            if let Some(index) = ephemeral {
+                state.queue.remove(index);
+                state.dropped += 1;
+                return true;
+            }
+        }
+
+        // nothing can give way, what is queued is stale once the client resyncs
+        warn!(capacity = self.capacity, "outbound queue full, closing connection");
+        state.queue.clear();
+        state.snapshots = 0;
+        state.queue.push_back(Queued {
+            frame: Message::Close(Some(CloseFrame {
+                code: CloseCode::Again,
+                reason: "outbound queue full, reconnect to resync".into(),
+            })),
+            delivery: Delivery::Reliable,
+        });
+        state.closed = true;
+        false
+    }
+
+    /// fold the oldest queued frame that has a newer one with the same key into it, false
+    /// when there is none
+    fn coalesce(&self, state: &mut State) -> bool {
+        let mut pair = None;
+        // key -> index of the latest frame with it
+        let mut latest = HashMap::new();
+        for (index, queued) in state.queue.iter().enumerate() {
+            let Some(key) = queued.delivery.key() else {
+                // merging would move an update past a creation, deletion, batch or sync that
+                // may touch the same document
+                if !matches!(queued.delivery, Delivery::Ephemeral(_)) {
+                    latest.retain(|_, older: &mut usize| {
+                        !matches!(state.queue[*older].delivery, Delivery::Update(_))
+                    });
+                }
+                continue;
+            };
+            if let Some(older) = latest.insert(key, index) {
+                if state.queue[older].delivery == queued.delivery {
+                    pair = Some((older, index));
+                    break;
+                }
+            }
+        }
+        let Some((older, newer)) = pair else {
+            return false;
+        };
+
+        if matches!(state.queue[newer].delivery, Delivery::Update(_)) {
+            match merge_updates(self.encoding, &state.queue[older].frame, &state.queue[newer].frame)
+            {
+                Ok(merged) => state.queue[newer].frame = merged,
+                Err(e) => {
+                    error!(error = %e, "failed to coalesce update");
+                    return false;
+                }
+            }
+        }
+        state.queue.remove(older);
+        true
+    }
+
+    /// stop accepting frames, `frame` is delivered after what is already queued
+    pub fn close(&self, frame: Option<CloseFrame>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.closed {
+            return;
+        }
+        if let Some(frame) = frame {
+            state.queue.push_back(Queued {
+                frame: Message::Close(Some(frame)),
+                delivery: Delivery::Reliable,
+            });
+        }
+        state.closed = true;
+        self.ready.notify_one();
+    }
+
+    /// drop what is still queued of a sync of `process_id`
+    pub fn discard_sync(&self, process_id: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let before = state.queue.len();
+        state
+            .queue
+            .retain(|queued| !matches!(&queued.delivery, Delivery::Snapshot(p) if p == process_id));
+        state.snapshots -= before - state.queue.len();
+    }
+
+    /// next frame to send, `None` once closed and drained
+    pub async fn recv(&self) -> Option<Message> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(queued) = state.queue.pop_front() {
+                    if matches!(queued.delivery, Delivery::Snapshot(_)) {
+                        state.snapshots -= 1;
+                    }
+                    return Some(queued.frame);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            // a single reader, a notification sent in between is kept as a permit
+            self.ready.notified().await;
+        }
+    }
+
+    pub fn stats(&self) -> OutboxStats {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        OutboxStats { depth: state.queue.len(), dropped: state.dropped, coalesced: state.coalesced }
+    }
+}
+
+/// one `DocumentUpdated` with the changes of both, the newer ones and the newer seq win
+fn merge_updates(encoding: Encoding, older: &Message, newer: &Message) -> Result<Message, Error> {
+    let decode = |frame: &Message| -> Result<OutputMessage, Error> {
+        match frame {
+            Message::Text(text) => encoding.decode(text.as_bytes()),
+            Message::Binary(bytes) => encoding.decode(bytes),
+            _ => Err(anyhow!("not a data frame")),
+        }
+    };
+    let (
+        OutputMessage {
+            output: Output::DocumentUpdated { process_id, collection_name, doc_id, mut changes },
+            ..
+        },
+        OutputMessage { output: Output::DocumentUpdated { changes: newer, .. }, seq },
+    ) = (decode(older)?, decode(newer)?)
+    else {
+        return Err(anyhow!("not a document update"));
+    };
+
+    changes.merge(newer);
+    let output = Output::DocumentUpdated { process_id, collection_name, doc_id, changes };
+    encoding.encode(&OutputMessage { output, seq })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::DocumentUpdate;
+    use serde_json::{Value, json};
+    use vm::types::DocumentChanges;
+
+    const PID: &str = "p";
+
+    fn push(outbox: &Outbox, output: Output) -> bool {
+        let delivery = Delivery::of(&output);
+        let frame = outbox.encoding().encode(&OutputMessage { output, seq: None }).unwrap();
+        outbox.push(frame, delivery)
+    }
+
+    fn changes(x: Option<&str>, y: Option<&str>) -> DocumentChanges {
+        DocumentChanges { x: x.map(str::to_string), y: y.map(str::to_string), ..Default::default() }
+    }
+
+    fn updated(doc_id: &str, changes: DocumentChanges) -> Output {
+        Output::DocumentUpdated {
+            process_id: PID.into(),
+            collection_name: "cubes".into(),
+            doc_id: doc_id.into(),
+            changes,
+        }
+    }
+
+    fn transformed(doc_id: &str, x: &str) -> Output {
+        Output::GroupTransformed {
+            process_id: PID.into(),
+            group: "g".into(),
+            updates: vec![DocumentUpdate {
+                collection_name: "cubes".into(),
+                doc_id: doc_id.into(),
+                changes: changes(Some(x), None),
+            }],
+        }
+    }
+
+    fn awareness(connection_id: &str, state: Value) -> Output {
+        Output::AwarenessUpdated {
+            process_id: PID.into(),
+            connection_id: connection_id.into(),
+            state,
+        }
+    }
+
+    fn chunk(process_id: &str) -> Output {
+        Output::SyncChunk {
+            process_id: process_id.into(),
+            collection_name: "cubes".into(),
+            documents: Default::default(),
+        }
+    }
+
+    /// everything queued, as json values and `"Close"` for a close frame
+    async fn drain(outbox: &Outbox) -> Vec<Value> {
+        outbox.close(None);
+        let mut frames = Vec::new();
+        while let Some(frame) = outbox.recv().await {
+            frames.push(match frame {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                Message::Close(_) => json!("Close"),
+                other => panic!("unexpected frame {other:?}"),
+            });
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn updates_are_not_coalesced_past_reliable_frames() {
+        let outbox = Outbox::new(3, OverflowPolicy::Coalesce, Encoding::Json);
+        assert!(push(&outbox, updated("a", changes(Some("1n"), None))));
+        assert!(push(&outbox, transformed("a", "5n")));
+        assert!(push(&outbox, updated("a", changes(None, Some("2n")))));
+        // full, only the updates behind the transform may be merged
+        assert!(push(&outbox, updated("a", changes(None, Some("3n")))));
+        assert_eq!(outbox.stats().coalesced, 1);
+
+        let frames = drain(&outbox).await;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0]["DocumentUpdated"]["changes"], json!({"x": "1n"}));
+        assert_eq!(frames[1]["GroupTransformed"]["updates"][0]["changes"], json!({"x": "5n"}));
+        assert_eq!(frames[2]["DocumentUpdated"]["changes"], json!({"y": "3n"}));
+    }
+
+    #[tokio::test]
+    async fn a_queue_that_cannot_coalesce_across_a_batch_is_closed() {
+        let outbox = Outbox::new(2, OverflowPolicy::Coalesce, Encoding::Json);
+        assert!(push(&outbox, updated("a", changes(Some("1n"), None))));
+        assert!(push(&outbox, transformed("a", "5n")));
+        // merging the first update into this one would leave the client at x = 1
+        assert!(!push(&outbox, updated("a", changes(None, Some("2n")))));
+        assert!(!push(&outbox, updated("b", changes(None, Some("2n")))));
+
+        assert_eq!(drain(&outbox).await, vec![json!("Close")]);
+    }
+
+    #[tokio::test]
+    async fn updates_of_a_document_are_merged_in_order() {
+        let outbox = Outbox::new(2, OverflowPolicy::Coalesce, Encoding::Json);
+        assert!(push(&outbox, updated("a", changes(Some("1n"), Some("1n")))));
+        assert!(push(&outbox, updated("b", changes(Some("7n"), None))));
+        assert!(push(&outbox, updated("a", changes(None, Some("2n")))));
+        assert_eq!(outbox.stats().coalesced, 1);
+
+        let frames = drain(&outbox).await;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0]["DocumentUpdated"]["doc_id"], "b");
+        assert_eq!(frames[1]["DocumentUpdated"]["doc_id"], "a");
+        assert_eq!(frames[1]["DocumentUpdated"]["changes"], json!({"x": "1n", "y": "2n"}));
+    }
+
+    #[tokio::test]
+    async fn coalesce_keeps_the_latest_awareness_state() {
+        let outbox = Outbox::new(2, OverflowPolicy::Coalesce, Encoding::Json);
+        assert!(push(&outbox, awareness("c1", json!(1))));
+        assert!(push(&outbox, transformed("a", "5n")));
+        assert!(push(&outbox, awareness("c1", json!(2))));
+
+        let stats = outbox.stats();
+        assert_eq!((stats.coalesced, stats.dropped), (1, 0));
+        let frames = drain(&outbox).await;
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].get("GroupTransformed").is_some());
+        assert_eq!(frames[1]["AwarenessUpdated"]["state"], 2);
+    }
+
+    #[tokio::test]
+    async fn drop_ephemeral_drops_the_oldest_awareness_relay() {
+        let outbox = Outbox::new(2, OverflowPolicy::DropEphemeral, Encoding::Json);
+        assert!(push(&outbox, awareness("c1", json!(1))));
+        assert!(push(&outbox, awareness("c2", json!(1))));
+        assert!(push(&outbox, updated("a", changes(Some("1n"), None))));
+        assert_eq!(outbox.stats().dropped, 1);
+
+        let frames = drain(&outbox).await;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0]["AwarenessUpdated"]["connection_id"], "c2");
+        assert!(frames[1].get("DocumentUpdated").is_some());
+    }
+
+    #[tokio::test]
+    async fn drop_ephemeral_never_merges_updates() {
+        let outbox = Outbox::new(1, OverflowPolicy::DropEphemeral, Encoding::Json);
+        assert!(push(&outbox, updated("a", changes(Some("1n"), None))));
+        assert!(!push(&outbox, updated("a", changes(Some("2n"), None))));
+        assert!(!push(&outbox, awareness("c1", json!(1))));
+
+        assert_eq!(drain(&outbox).await, vec![json!("Close")]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_on_the_first_overflow() {
+        let outbox = Outbox::new(1, OverflowPolicy::Disconnect, Encoding::Json);
+        assert!(push(&outbox, awareness("c1", json!(1))));
+        assert!(!push(&outbox, awareness("c1", json!(2))));
+
+        let stats = outbox.stats();
+        assert_eq!((stats.coalesced, stats.dropped), (0, 0));
+        assert_eq!(drain(&outbox).await, vec![json!("Close")]);
+    }
+
+    #[tokio::test]
+    async fn syncs_are_not_bounded_by_the_capacity_and_discarded_on_leave() {
+        let outbox = Outbox::new(1, OverflowPolicy::Disconnect, Encoding::Json);
+        for _ in 0..3 {
+            assert!(push(&outbox, chunk(PID)));
+            assert!(push(&outbox, chunk("other")));
+        }
+        assert!(push(&outbox, awareness("c1", json!(1))));
+        assert_eq!(outbox.stats().depth, 7);
+
+        outbox.discard_sync(PID);
+        let frames = drain(&outbox).await;
+        assert_eq!(frames.len(), 4);
+        assert!(frames[..3].iter().all(|frame| frame["SyncChunk"]["process_id"] == "other"));
+        assert!(frames[3].get("AwarenessUpdated").is_some());
+    }
+}
@@ -0,0 +1,52 @@
+//! exports and preview images of lobbies, rendered on the blocking pool so lobby tasks and
+//! requests are not held up by them
+use anyhow::{Error, anyhow};
+use export::{
+    Scene,
+    gltf::{ExportOptions, scene_to_glb},
+    png::frame_to_png,
+    render::{RenderOptions, layout},
+    svg::frame_to_svg,
+};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Glb,
+    Svg,
+    Png,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Glb => "model/gltf-binary",
+            Format::Svg => "image/svg+xml",
+            Format::Png => "image/png",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    /// a file extension
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "glb" => Ok(Format::Glb),
+            "svg" => Ok(Format::Svg),
+            "png" => Ok(Format::Png),
+            other => Err(anyhow!("unknown export format {}, expected glb, svg or png", other)),
+        }
+    }
+}
+
+/// the scene as a file of `format`
+pub async fn render(scene: Scene, format: Format) -> Result<Vec<u8>, Error> {
+    tokio::task::spawn_blocking(move || match format {
+        Format::Glb => scene_to_glb(&scene, &ExportOptions::default()),
+        Format::Svg => Ok(frame_to_svg(&layout(&scene, &RenderOptions::default())).into_bytes()),
+        Format::Png => frame_to_png(&layout(&scene, &RenderOptions::default())),
+    })
+    .await?
+}
@@ -0,0 +1,265 @@
+//! json over http for clients that do not keep a websocket open; writes are applied by the
+//! lobby task like websocket inputs, so subscribers see them as usual. reads of a lobby that
+//! is not loaded are served from the database, unknown processes are not found
+//!
+//! - `GET /api/processes/:pid` lobby state
+//! - `GET /api/processes/:pid/collections/:collection` its documents in layer order, bottom
+//!   to top
+//! - `POST /api/processes/:pid/collections/:collection` create a document
+//! - `GET|PATCH|DELETE /api/processes/:pid/collections/:collection/:doc_id`
+//! - `GET /api/processes/:pid/preview.png` the latest preview image
+//!
+//! writes need `Authorization: Bearer <session>`, a session token from `Authenticate`
+use crate::{
+    errors::{ErrorCode, Rejection},
+    http::{Body, file, text},
+    lobby::LobbyCommand,
+    messages::{Input, Output},
+    metrics::METRICS,
+    server::Server,
+};
+use anyhow::{Error, anyhow};
+use http_body_util::{BodyExt, Limited};
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::Incoming,
+    header::{
+        ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+        AUTHORIZATION, CONTENT_TYPE, HeaderValue,
+    },
+};
+use serde::{Serialize, de::DeserializeOwned};
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::sync::oneshot;
+use tracing::{Span, error};
+use uuid::Uuid;
+use vm::types::{Collections, Document, Group};
+
+/// upper bound on a request body
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// a whole lobby, as `GET /api/processes/:pid` returns it
+#[derive(Serialize)]
+struct LobbyState {
+    process_id: String,
+    // seq of the last event broadcast to websocket subscribers
+    seq: u64,
+    layers: BTreeMap<String, Vec<String>>,
+    groups: BTreeMap<String, Group>,
+    collections: Collections,
+}
+
+/// the `Ack` of a write
+#[derive(Serialize)]
+struct Written {
+    process_id: String,
+    doc_id: Option<String>,
+    seq: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+pub async fn handle(req: Request<Incoming>, server: Arc<Server>) -> Response<Body> {
+    let mut response = match req.method() {
+        // cors preflight of browser writes
+        &Method::OPTIONS => {
+            let mut response = text(StatusCode::NO_CONTENT, "");
+            let headers = response.headers_mut();
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static("GET, POST, PATCH, DELETE"),
+            );
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                HeaderValue::from_static("Authorization, Content-Type"),
+            );
+            response
+        }
+        _ => route(req, server).await.unwrap_or_else(|e| {
+            let code = Rejection::code_of(&e);
+            if code == ErrorCode::Internal {
+                error!(error = %e, "rest request failed");
+            }
+            json(status_of(code), &ErrorBody { code, message: e.to_string() })
+        }),
+    };
+    response.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    response
+}
+
+async fn route(req: Request<Incoming>, server: Arc<Server>) -> Result<Response<Body>, Error> {
+    let path = req.uri().path().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let method = req.method().clone();
+
+    match (&method, segments.as_slice()) {
+        (&Method::GET, ["api", "processes", pid]) => {
+            let state = server
+                .read(pid, |lobby, seq| LobbyState {
+                    process_id: lobby.process_id.clone(),
+                    seq,
+                    layers: lobby.get_layers().unwrap_or_default(),
+                    groups: lobby.groups.clone(),
+                    collections: lobby.collections.clone(),
+                })
+                .await?;
+            Ok(json(StatusCode::OK, &state))
+        }
+
+        (&Method::GET, ["api", "processes", pid, "preview.png"]) => {
+            let png = server.storage().load_preview(pid).await?.ok_or_else(|| {
+                Rejection::error(ErrorCode::NotFound, format!("no preview of {}", pid))
+            })?;
+            Ok(file("image/png", png))
+        }
+
+        (&Method::GET, ["api", "processes", pid, "collections", collection_name]) => {
+            let name = collection_name.to_string();
+            let documents: Vec<Document> = server
+                .read(pid, move |lobby, _| lobby.get_layered_collection(&name).ok())
+                .await?
+                .ok_or_else(|| {
+                    Rejection::error(
+                        ErrorCode::NotFound,
+                        format!("collection {} not found", collection_name),
+                    )
+                })?;
+            Ok(json(StatusCode::OK, &documents))
+        }
+
+        (&Method::POST, ["api", "processes", pid, "collections", collection_name]) => {
+            let address = authenticate(&req, &server)?;
+            let document = body(req).await?;
+            let input =
+                Input::CreateDocument { collection_name: collection_name.to_string(), document };
+            apply(&server, pid, address, input, StatusCode::CREATED).await
+        }
+
+        (&Method::GET, ["api", "processes", pid, "collections", collection_name, doc_id]) => {
+            let (name, id) = (collection_name.to_string(), doc_id.to_string());
+            let document = server
+                .read(pid, move |lobby, _| lobby.collections.get(&name)?.get(&id).cloned())
+                .await?
+                .ok_or_else(|| {
+                    Rejection::error(
+                        ErrorCode::NotFound,
+                        format!("document {} not found in {}", doc_id, collection_name),
+                    )
+                })?;
+            Ok(json(StatusCode::OK, &document))
+        }
+
+        (&Method::PATCH, ["api", "processes", pid, "collections", collection_name, doc_id]) => {
+            let address = authenticate(&req, &server)?;
+            let changes = body(req).await?;
+            let input = Input::UpdateDocument {
+                collection_name: collection_name.to_string(),
+                doc_id: doc_id.to_string(),
+                changes,
+            };
+            apply(&server, pid, address, input, StatusCode::OK).await
+        }
+
+        (&Method::DELETE, ["api", "processes", pid, "collections", collection_name, doc_id]) => {
+            let address = authenticate(&req, &server)?;
+            let input = Input::DeleteDocument {
+                collection_name: collection_name.to_string(),
+                doc_id: doc_id.to_string(),
+            };
+            apply(&server, pid, address, input, StatusCode::OK).await
+        }
+
+        _ => {
+            Err(Rejection::error(ErrorCode::NotFound, format!("no route for {} {}", method, path)))
+        }
+    }
+}
+
+/// the address of a valid session token
+fn authenticate(req: &Request<Incoming>, server: &Server) -> Result<String, Error> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| {
+            Rejection::error(ErrorCode::Unauthorized, "expected Authorization: Bearer <session>")
+        })?;
+    server
+        .sessions()
+        .verify(token)
+        .map_err(|e| Rejection::error(ErrorCode::Unauthorized, e.to_string()))
+}
+
+async fn body<T: DeserializeOwned>(req: Request<Incoming>) -> Result<T, Error> {
+    let bytes = Limited::new(req.into_body(), MAX_BODY_BYTES)
+        .collect()
+        .await
+        .map_err(|e| Rejection::error(ErrorCode::InvalidMessage, format!("invalid body: {}", e)))?
+        .to_bytes();
+    serde_json::from_slice(&bytes)
+        .map_err(|e| Rejection::error(ErrorCode::InvalidMessage, format!("invalid json: {}", e)))
+}
+
+/// run a write through the lobby task and answer with its `Ack`
+async fn apply(
+    server: &Server,
+    pid: &str,
+    address: String,
+    input: Input,
+    status: StatusCode,
+) -> Result<Response<Body>, Error> {
+    METRICS.messages_in.with_label_values(&[input.kind()]).inc();
+    let (reply, response) = oneshot::channel();
+    server.lobby(pid)?.send(LobbyCommand::Input {
+        // leases are held by connections, a request never holds one
+        connection_id: format!("http/{}", Uuid::new_v4()),
+        address: Some(address),
+        request_id: None,
+        input: Box::new(input),
+        reply: Some(reply),
+        span: Span::current(),
+    })?;
+
+    match response.await.map_err(|_| anyhow!("lobby unavailable"))? {
+        Output::Ack { process_id, doc_id, seq, .. } => {
+            Ok(json(status, &Written { process_id, doc_id, seq }))
+        }
+        Output::Error { code, message, .. } => Err(Rejection::error(code, message)),
+        _ => Err(anyhow!("unexpected reply from lobby")),
+    }
+}
+
+pub(crate) fn status_of(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::InvalidMessage
+        | ErrorCode::InvalidArgument
+        | ErrorCode::NotJoined
+        | ErrorCode::IncompatibleVersion => StatusCode::BAD_REQUEST,
+        ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::Locked => StatusCode::LOCKED,
+        ErrorCode::Conflict => StatusCode::CONFLICT,
+        ErrorCode::Unsupported => StatusCode::NOT_IMPLEMENTED,
+        ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+        ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub(crate) fn json(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    match serde_json::to_vec(body) {
+        Ok(bytes) => {
+            let mut response = Response::new(Body::from(bytes));
+            *response.status_mut() = status;
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response
+        }
+        Err(e) => text(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
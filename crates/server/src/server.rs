@@ -1,202 +1,274 @@
 /// TODO: proper error types
 use crate::{
-    messages::{Input, Output},
-    types::Subscriber,
+    auth::Sessions,
+    config::Config,
+    errors::{ErrorCode, Rejection},
+    lobby::{LobbyHandle, LobbySettings},
 };
 use anyhow::{Error, anyhow};
-use std::collections::HashMap;
-use storage::{Database, DatabaseWorker, DbOperation};
-use tokio::sync::mpsc;
-use vm::{
-    Lobby,
-    types::{Document, DocumentChanges},
+use futures_util::future::join_all;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
 };
+use storage::{Database, DatabaseWorker, DbJob, WorkerStats};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+};
+use tracing::{error, info};
+use vm::Lobby;
 
+/// routes process ids to their lobby tasks, the lock only guards the lookup
 #[derive(Debug)]
 pub struct Server {
-    // process_id -> lobby
-    pub lobbies: HashMap<String, Lobby>,
-    // process_id -> ws subscribers
-    pub subscribers: HashMap<String, Vec<Subscriber>>,
+    // process_id -> lobby task
+    lobbies: Mutex<HashMap<String, LobbyHandle>>,
+    // processes whose stored state an admin is changing, `lobby` refuses to load them;
+    // only locked while holding `lobbies`
+    unloading: Mutex<HashSet<String>>,
     // Background queue
-    db_sender: mpsc::UnboundedSender<DbOperation>,
+    db_sender: mpsc::UnboundedSender<DbJob>,
+    db_stats: Arc<WorkerStats>,
+    // tells the db worker to drain its queue and stop, and its task; taken on shutdown
+    db_worker: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+    // true once the server is shutting down
+    closing: watch::Sender<bool>,
     // persistent storage
     storage: Database,
+    // session tokens of authenticated addresses
+    sessions: Sessions,
+    // replaced on reload, connections and lobbies read it when they start
+    config: RwLock<Arc<Config>>,
 }
 
 impl Server {
-    pub async fn new(database_url: &str) -> Result<Self, Error> {
-        let database = Database::new(database_url).await?;
+    pub async fn new(config: Config) -> Result<Self, Error> {
+        let database = Database::new(&config.database_url).await?;
         database.run_migrations().await?;
 
-        // Crcreacreateteeate background worker channel
-        let (db_sender, db_receiver) = mpsc::unbounded_channel::<DbOperation>();
+        // create background worker channel
+        let (db_sender, db_receiver) = mpsc::unbounded_channel::<DbJob>();
 
         // start db worker
         let db_worker = DatabaseWorker::new(database.clone(), db_receiver);
-        tokio::spawn(async move {
-            db_worker.run().await;
+        let db_stats = db_worker.stats();
+        let (drain, drained) = oneshot::channel::<()>();
+        let db_task = tokio::spawn(async move {
+            db_worker
+                .run(async {
+                    let _ = drained.await;
+                })
+                .await;
         });
 
         Ok(Self {
-            lobbies: HashMap::new(),
-            subscribers: HashMap::new(),
+            lobbies: Mutex::new(HashMap::new()),
+            unloading: Mutex::new(HashSet::new()),
             storage: database,
             db_sender,
+            db_stats,
+            db_worker: Mutex::new(Some((drain, db_task))),
+            closing: watch::Sender::new(false),
+            sessions: Sessions::new(config.session_secret.clone()),
+            config: RwLock::new(Arc::new(config)),
         })
     }
-    pub fn add_subscriber(&mut self, pid: &str, sub: Subscriber) -> &mut Self {
-        self.subscribers.entry(pid.to_owned()).or_default().push(sub);
-        self
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap_or_else(|e| e.into_inner()).clone()
     }
-    /// get lobby from memory if hot or load from database if not
-    pub async fn get_lobby(&mut self, pid: &str) -> Result<&mut Lobby, Error> {
-        if self.lobbies.contains_key(pid) {
-            return self.lobbies.get_mut(pid).ok_or_else(|| anyhow!("lobby not in memory"));
-        }
 
-        // If lobby not in memory - load from storage
-        match self.storage.load_process_state(pid).await {
-            Ok(Some(state_json)) => {
-                let lobby: Lobby = serde_json::from_str(&state_json)
-                    .map_err(|e| anyhow!("failed to deserialize lobby: {}", e))?;
+    /// apply a reloaded config, see `Config::reloaded` for what it can change
+    pub fn set_config(&self, config: Config) {
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+    }
 
-                // insert rebuilt lobby into memory (make hot)
-                self.lobbies.insert(pid.to_string(), lobby);
+    pub fn storage(&self) -> &Database {
+        &self.storage
+    }
 
-                self.lobbies.get_mut(pid).ok_or_else(|| anyhow!("Failed to insert lobby"))
-            }
-            Ok(None) => {
-                // new lobby - create in memory and storage
-                println!("creating new lobby: {}", pid);
-
-                let lobby = Lobby::new(pid);
-                self.lobbies.insert(pid.to_string(), lobby);
-
-                // save to db in background
-                let lobby_json = serde_json::to_string(self.lobbies.get(pid).unwrap())?;
-                let _ = self.db_sender.send(DbOperation::SaveProcessState {
-                    process_id: pid.to_string(),
-                    full_state: lobby_json,
-                    is_hot: true,
-                });
-
-                self.lobbies.get_mut(pid).ok_or_else(|| anyhow!("failed to get new lobby"))
-            }
-            Err(e) => Err(anyhow!("failed to load lobby from storage: {}", e)),
-        }
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
     }
 
-    pub async fn create_lobby(&mut self, pid: &str) -> Result<bool, Error> {
-        if !self.lobbies.contains_key(pid) {
-            let lobby = Lobby::new(pid);
-            self.lobbies.insert(pid.to_string(), lobby);
+    pub fn db_stats(&self) -> &WorkerStats {
+        &self.db_stats
+    }
 
-            let lobby_json = serde_json::to_string(self.lobbies.get(pid).unwrap())?;
-            let _ = self.db_sender.send(DbOperation::SaveProcessState {
-                process_id: pid.to_string(),
-                full_state: lobby_json,
-                is_hot: true,
-            });
+    /// lobbies whose task is running
+    pub fn lobby_count(&self) -> usize {
+        let lobbies = self.lobbies.lock().unwrap_or_else(|e| e.into_inner());
+        lobbies.values().filter(|handle| handle.is_alive()).count()
+    }
 
-            Ok(true)
-        } else {
-            Ok(false)
+    /// whether requests can be served: postgres answers and the db worker is running
+    pub async fn ready(&self) -> Result<(), Error> {
+        if *self.closing.borrow() {
+            return Err(anyhow!("shutting down"));
+        }
+        if self.db_sender.is_closed() {
+            return Err(anyhow!("db worker stopped"));
         }
+        self.storage.ping().await
     }
 
-    pub async fn broadcast_to_lobby(&self, pid: &str, message: Output) -> Result<(), Error> {
-        if let Some(subs) = self.subscribers.get(pid) {
-            let msg = serde_json::to_string(&message)
-                .map_err(|e| anyhow!("failed to serialize message: {}", e))?;
+    /// completes once the server starts shutting down
+    pub fn closing(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut closing = self.closing.subscribe();
+        async move {
+            let _ = closing.wait_for(|closing| *closing).await;
+        }
+    }
+
+    /// close every connection, persist every lobby with unsaved changes and wait for the db
+    /// worker to write out its queue; callers bound it with a deadline
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        // connections close with a reconnect hint and leave their lobbies
+        self.closing.send_replace(true);
 
-            for sub in subs {
-                if let Err(_e) = sub.send(msg.clone()) {
-                    eprintln!("failed to send to subscriber channel");
-                }
+        let lobbies: Vec<LobbyHandle> = {
+            let mut lobbies = self.lobbies.lock().unwrap_or_else(|e| e.into_inner());
+            lobbies.drain().map(|(_, handle)| handle).collect()
+        };
+        info!(lobbies = lobbies.len(), "persisting lobbies");
+        join_all(lobbies.iter().map(|lobby| async {
+            if let Err(e) = lobby.stop(true).await {
+                error!(error = %e, "failed to save lobby");
             }
+        }))
+        .await;
+
+        let worker = self.db_worker.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some((drain, task)) = worker {
+            info!(queued = self.db_stats.queue_depth(), "draining db worker");
+            let _ = drain.send(());
+            task.await?;
         }
         Ok(())
     }
 
-    pub async fn create_document(
-        &mut self,
-        pid: &str,
-        collection_name: &str,
-        document: Document,
-    ) -> Result<String, Error> {
-        // do all work that needs &mut Lobby without awaiting
-        let (doc_id, complete_state) = {
-            let lobby = self.get_lobby(pid).await?;
-            let doc_id = lobby
-                .create_document(collection_name, document.clone())
-                .map_err(|_e| anyhow!("create_document failed"))?;
-
-            let complete_state = serde_json::to_string(&*lobby)?;
-            (doc_id, complete_state)
-        };
+    /// stop the lobby task of a process, if it is running, writing its unsaved changes when
+    /// `save` is set; the next `lobby` loads it again from the database. Returns whether it
+    /// was loaded
+    pub async fn evict(&self, pid: &str, save: bool) -> Result<bool, Error> {
+        // left in the map, so lookups get the stopping task until its state is written, and a
+        // new one once it stopped
+        let handle = self.lobbies.lock().unwrap_or_else(|e| e.into_inner()).get(pid).cloned();
+        match handle.filter(|handle| handle.is_alive()) {
+            Some(handle) => {
+                info!(process_id = %pid, save, "evicting lobby");
+                handle.stop(save).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
 
-        self.broadcast_to_lobby(
-            pid,
-            Output::DocumentCreated {
-                process_id: pid.to_string(),
-                collection_name: collection_name.to_string(),
-                doc_id: doc_id.clone(),
-                document: document.clone(),
-            },
-        )
-        .await?;
-        self.storage.save_process_state(&pid, &complete_state, true).await?;
+    /// run `operation` on the stored state of processes that stay unloaded meanwhile: their
+    /// lobbies are stopped first, writing unsaved changes when `save` is set, and `lobby`
+    /// refuses to load them again until `operation` is done, so no lobby writes over it
+    pub async fn unloaded<T>(
+        &self,
+        pids: &[&str],
+        save: bool,
+        operation: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let _unloading = {
+            let _lobbies = self.lobbies.lock().unwrap_or_else(|e| e.into_inner());
+            let mut unloading = self.unloading.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(pid) = pids.iter().find(|pid| unloading.contains(**pid)) {
+                return Err(Rejection::error(
+                    ErrorCode::Conflict,
+                    format!("process {} is already being changed", pid),
+                ));
+            }
+            unloading.extend(pids.iter().map(|pid| pid.to_string()));
+            Unloading { server: self, pids }
+        };
+        for pid in pids {
+            self.evict(pid, save).await?;
+        }
+        operation.await
+    }
 
-        Ok(doc_id)
+    /// the lobby task of a process if it is running, without starting one
+    pub fn loaded(&self, pid: &str) -> Option<LobbyHandle> {
+        let lobbies = self.lobbies.lock().unwrap_or_else(|e| e.into_inner());
+        lobbies.get(pid).filter(|handle| handle.is_alive()).cloned()
     }
 
-    pub async fn update_document(
-        &mut self,
+    /// what `read` makes of a process: live when its lobby is loaded, as stored otherwise;
+    /// never loads the lobby, so reads do not create processes
+    pub async fn read<T: Send + 'static>(
+        &self,
         pid: &str,
-        collection_name: &str,
-        doc_id: &str,
-        changes: DocumentChanges,
-    ) -> Result<(), Error> {
-        let lobby = self.get_lobby(pid).await?;
-        let res = lobby
-            .update_document(collection_name, doc_id, changes)
-            .map_err(|_| anyhow!("".to_string()))?;
-
-        let _ = self.broadcast_to_lobby(
+        read: impl FnOnce(&Lobby, u64) -> T + Clone + Send + 'static,
+    ) -> Result<T, Error> {
+        if let Some(lobby) = self.loaded(pid) {
+            // a lobby that is stopping has written its state by the time it stopped
+            if let Ok(value) = lobby.read(read.clone()).await {
+                return Ok(value);
+            }
+        }
+        let state = self.storage.load_process_state(pid).await?.ok_or_else(|| {
+            Rejection::error(ErrorCode::NotFound, format!("process {} not found", pid))
+        })?;
+        let lobby: Lobby = serde_json::from_str(&state)
+            .map_err(|e| anyhow!("failed to deserialize lobby: {}", e))?;
+        Ok(read(&lobby, 0))
+    }
+
+    /// get the lobby task of a process, starting it (and loading the lobby) if it is not
+    /// running; `Unavailable` once the server is shutting down
+    pub fn lobby(&self, pid: &str) -> Result<LobbyHandle, Error> {
+        let mut lobbies = self.lobbies.lock().unwrap_or_else(|e| e.into_inner());
+        // checked under the lock, so `shutdown` stops every lobby started before it
+        if *self.closing.borrow() {
+            return Err(Rejection::error(ErrorCode::Unavailable, "server is shutting down"));
+        }
+        if self.unloading.lock().unwrap_or_else(|e| e.into_inner()).contains(pid) {
+            return Err(Rejection::error(
+                ErrorCode::Unavailable,
+                format!("process {} is being changed by an admin, retry", pid),
+            ));
+        }
+
+        if let Some(handle) = lobbies.get(pid).filter(|handle| handle.is_alive()) {
+            return Ok(handle.clone());
+        }
+
+        let config = self.config();
+        let handle = LobbyHandle::spawn(
             pid,
-            Output::DocumentUpdated {
-                process_id: pid.to_string(),
-                collection_name: collection_name.to_string(),
-                doc_id: doc_id.to_string(),
-                changes: res,
+            self.storage.clone(),
+            self.db_sender.clone(),
+            LobbySettings {
+                event_buffer: config.event_buffer_capacity,
+                sync_chunk: config.sync_chunk_documents,
+                tick: config.broadcast_tick,
+                durability: config.durability,
+                save_debounce: config.save_debounce,
+                save_max_delay: config.save_max_delay,
+                preview_interval: config.preview_interval,
             },
         );
-        Ok(())
+        lobbies.insert(pid.to_string(), handle.clone());
+        Ok(handle)
     }
+}
 
-    pub async fn delete_document(
-        &mut self,
-        pid: &str,
-        collection_name: &str,
-        doc_id: &str,
-    ) -> Result<(), Error> {
-        let lobby = self.get_lobby(pid).await?;
-        let success =
-            lobby.delete_document(collection_name, doc_id).map_err(|_| anyhow!("".to_string()))?;
-
-        if success {
-            let _ = self.broadcast_to_lobby(
-                pid,
-                Output::DocumentDeleted {
-                    process_id: pid.to_string(),
-                    collection_name: collection_name.to_string(),
-                    doc_id: doc_id.to_string(),
-                },
-            );
-            Ok(())
-        } else {
-            Err(anyhow!("".to_string()))
+/// marks processes as unloading until dropped, see `Server::unloaded`
+struct Unloading<'a> {
+    server: &'a Server,
+    pids: &'a [&'a str],
+}
+
+impl Drop for Unloading<'_> {
+    fn drop(&mut self) {
+        let mut unloading = self.server.unloading.lock().unwrap_or_else(|e| e.into_inner());
+        for pid in self.pids {
+            unloading.remove(*pid);
         }
     }
 }
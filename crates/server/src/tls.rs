@@ -0,0 +1,27 @@
+//! tls termination for https and wss, when the config names a certificate
+use crate::config::TlsFiles;
+use anyhow::{Error, anyhow};
+use std::sync::Arc;
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+
+/// an acceptor for the certificate chain and key, read once at startup
+pub fn acceptor(files: &TlsFiles) -> Result<TlsAcceptor, Error> {
+    let certs = CertificateDer::pem_file_iter(&files.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("failed to read {}: {}", files.cert.display(), e))?;
+    let key = PrivateKeyDer::from_pem_file(&files.key)
+        .map_err(|e| anyhow!("failed to read {}: {}", files.key.display(), e))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
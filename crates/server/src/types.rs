@@ -1,3 +1,42 @@
-use tokio::sync::mpsc;
+use crate::{
+    encoding::Encoding,
+    messages::Output,
+    metrics::METRICS,
+    outbox::{Delivery, Outbox},
+};
+use anyhow::{Error, anyhow};
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::{Message, protocol::CloseFrame};
 
-pub(crate) type Subscriber = mpsc::UnboundedSender<String>;
+/// outgoing half of a connection, as seen by lobbies
+#[derive(Debug, Clone)]
+pub(crate) struct Subscriber {
+    pub outbox: Arc<Outbox>,
+}
+
+impl Subscriber {
+    pub fn encoding(&self) -> Encoding {
+        self.outbox.encoding()
+    }
+
+    /// false once the connection is gone, or was closed because its queue overflowed
+    pub fn send(&self, frame: Message, delivery: Delivery) -> bool {
+        self.outbox.push(frame, delivery)
+    }
+
+    /// encode and send a message meant for this subscriber only
+    pub fn send_output(&self, output: &Output) -> Result<(), Error> {
+        let frame = self.encoding().encode(output)?;
+        if self.send(frame, Delivery::of(output)) {
+            METRICS.messages_out.with_label_values(&[output.kind()]).inc();
+            Ok(())
+        } else {
+            Err(anyhow!("subscriber closed"))
+        }
+    }
+
+    /// no more frames after `frame`, if given
+    pub fn close(&self, frame: Option<CloseFrame>) {
+        self.outbox.close(frame);
+    }
+}
@@ -1,8 +0,0 @@
-use anyhow::Error;
-use dotenvy::dotenv;
-use std::env;
-
-pub fn get_env_var(key: &str) -> Result<String, Error> {
-    dotenv().ok();
-    Ok(env::var(key)?)
-}
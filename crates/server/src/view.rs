@@ -0,0 +1,367 @@
+//! the part of a lobby a filtered subscriber follows, see `SubscriptionFilter`
+use crate::messages::{DocumentUpdate, Output, Region, SubscriptionFilter};
+use std::collections::HashSet;
+use vm::{Lobby, types::Document};
+
+/// what a filtered subscriber gets instead of a broadcast
+pub(crate) enum Projection {
+    /// the broadcast as is
+    All,
+    Nothing,
+    /// a narrowed broadcast, or the region transitions it caused
+    Some(Vec<Output>),
+}
+
+pub(crate) struct View {
+    filter: SubscriptionFilter,
+    // (collection, doc_id) of the documents inside the region the subscriber knows about,
+    // only tracked with a region
+    visible: HashSet<(String, String)>,
+}
+
+impl View {
+    pub fn new(filter: SubscriptionFilter) -> Self {
+        Self { filter, visible: HashSet::new() }
+    }
+
+    /// without a region, what a subscriber gets does not depend on what it got before, so
+    /// missed events can be replayed
+    pub fn is_stateless(&self) -> bool {
+        self.filter.region.is_none()
+    }
+
+    pub fn wants_collection(&self, collection_name: &str) -> bool {
+        self.filter.collections.as_ref().is_none_or(|names| names.contains(collection_name))
+    }
+
+    /// whether a document belongs in the subscriber's sync, remembering it if so
+    pub fn admit(&mut self, collection_name: &str, doc_id: &str, document: &Document) -> bool {
+        if !self.wants_collection(collection_name) {
+            return false;
+        }
+        let Some(region) = &self.filter.region else {
+            return true;
+        };
+        if !inside(region, document) {
+            return false;
+        }
+        self.visible.insert((collection_name.to_string(), doc_id.to_string()));
+        true
+    }
+
+    /// whether the subscriber knows about a document, for locks and moves
+    pub fn knows(&self, collection_name: &str, doc_id: &str) -> bool {
+        self.wants_collection(collection_name)
+            && (self.filter.region.is_none()
+                || self.visible.contains(&(collection_name.to_string(), doc_id.to_string())))
+    }
+
+    /// narrow a broadcast to the view, `lobby` is the state after it
+    pub fn project(&mut self, output: &Output, lobby: &Lobby) -> Projection {
+        match output {
+            Output::DocumentCreated { collection_name, doc_id, document, .. } => {
+                match self.admit(collection_name, doc_id, document) {
+                    true => Projection::All,
+                    false => Projection::Nothing,
+                }
+            }
+
+            Output::DocumentUpdated { process_id, collection_name, doc_id, .. } => {
+                self.project_update(process_id, collection_name, doc_id, lobby)
+            }
+
+            Output::DocumentDeleted { collection_name, doc_id, .. } => {
+                let known = self.knows(collection_name, doc_id);
+                self.visible.remove(&(collection_name.clone(), doc_id.clone()));
+                match known {
+                    true => Projection::All,
+                    false => Projection::Nothing,
+                }
+            }
+
+            Output::DocumentMoved { collection_name, doc_id, .. }
+            | Output::DocumentLocked { collection_name, doc_id, .. }
+            | Output::DocumentUnlocked { collection_name, doc_id, .. } => {
+                match self.knows(collection_name, doc_id) {
+                    true => Projection::All,
+                    false => Projection::Nothing,
+                }
+            }
+
+            Output::GroupTransformed { process_id, group, updates } => {
+                self.project_updates(process_id, updates, lobby, |updates| {
+                    Output::GroupTransformed {
+                        process_id: process_id.clone(),
+                        group: group.clone(),
+                        updates,
+                    }
+                })
+            }
+
+            Output::DocumentsUpdated { process_id, updates } => {
+                self.project_updates(process_id, updates, lobby, |updates| {
+                    Output::DocumentsUpdated { process_id: process_id.clone(), updates }
+                })
+            }
+
+            _ => Projection::All,
+        }
+    }
+
+    /// narrow a message carrying several updates, `rebuild` makes one with those kept; the
+    /// region transitions they caused follow it
+    fn project_updates(
+        &mut self,
+        process_id: &str,
+        updates: &[DocumentUpdate],
+        lobby: &Lobby,
+        rebuild: impl FnOnce(Vec<DocumentUpdate>) -> Output,
+    ) -> Projection {
+        let mut kept: Vec<DocumentUpdate> = Vec::new();
+        let mut transitions = Vec::new();
+        for update in updates {
+            match self.project_update(process_id, &update.collection_name, &update.doc_id, lobby) {
+                Projection::All => kept.push(DocumentUpdate {
+                    collection_name: update.collection_name.clone(),
+                    doc_id: update.doc_id.clone(),
+                    changes: update.changes.clone(),
+                }),
+                Projection::Nothing => {}
+                Projection::Some(outputs) => transitions.extend(outputs),
+            }
+        }
+
+        if kept.len() == updates.len() {
+            return Projection::All;
+        }
+        if !kept.is_empty() {
+            transitions.insert(0, rebuild(kept));
+        }
+        Projection::Some(transitions)
+    }
+
+    fn project_update(
+        &mut self,
+        process_id: &str,
+        collection_name: &str,
+        doc_id: &str,
+        lobby: &Lobby,
+    ) -> Projection {
+        if !self.wants_collection(collection_name) {
+            return Projection::Nothing;
+        }
+        let Some(region) = &self.filter.region else {
+            return Projection::All;
+        };
+
+        let document = lobby.collections.get(collection_name).and_then(|c| c.get(doc_id));
+        let key = (collection_name.to_string(), doc_id.to_string());
+        let was_inside = self.visible.contains(&key);
+        match (document.filter(|document| inside(region, document)), was_inside) {
+            (Some(_), true) => Projection::All,
+            (Some(document), false) => {
+                self.visible.insert(key);
+                Projection::Some(vec![Output::DocumentEnteredRegion {
+                    process_id: process_id.to_string(),
+                    collection_name: collection_name.to_string(),
+                    doc_id: doc_id.to_string(),
+                    document: document.clone(),
+                }])
+            }
+            (None, true) => {
+                self.visible.remove(&key);
+                Projection::Some(vec![Output::DocumentLeftRegion {
+                    process_id: process_id.to_string(),
+                    collection_name: collection_name.to_string(),
+                    doc_id: doc_id.to_string(),
+                }])
+            }
+            (None, false) => Projection::Nothing,
+        }
+    }
+}
+
+fn inside(region: &Region, document: &Document) -> bool {
+    document.position().is_some_and(|position| {
+        (0..3).all(|axis| (region.min[axis]..=region.max[axis]).contains(&position[axis]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm::types::{CollectionData, Cube, DocumentChanges, Splash};
+
+    fn document(data: CollectionData) -> Document {
+        Document { id: 0, creator: "creator".into(), request_id: None, order: String::new(), data }
+    }
+
+    fn cube(x: &str) -> Document {
+        document(CollectionData::Cube(Cube {
+            x: x.into(),
+            y: "0".into(),
+            z: "0".into(),
+            ..Default::default()
+        }))
+    }
+
+    /// cube 1 at x = 0, cube 2 at x = 10, cube 3 without a position, and a splash at x = 0
+    fn lobby() -> Lobby {
+        let mut lobby = Lobby::new("test");
+        for x in ["0", "10", "not a number"] {
+            lobby.create_document("cubes", cube(x)).unwrap();
+        }
+        let splash = Splash { x: "0".into(), y: "0".into(), ..Default::default() };
+        lobby.create_document("splashes", document(CollectionData::Splash(splash))).unwrap();
+        lobby
+    }
+
+    fn move_cube(lobby: &mut Lobby, doc_id: &str, x: &str) {
+        let changes = DocumentChanges { x: Some(x.into()), ..Default::default() };
+        lobby.update_document("cubes", doc_id, changes, "creator").unwrap();
+    }
+
+    /// a view of the box around the origin, synced with the lobby
+    fn region_view(lobby: &Lobby) -> View {
+        let region = Region { min: [-1.0; 3], max: [1.0; 3] };
+        let mut view = View::new(SubscriptionFilter { collections: None, region: Some(region) });
+        for (collection_name, collection) in &lobby.collections {
+            for (doc_id, document) in collection {
+                view.admit(collection_name, doc_id, document);
+            }
+        }
+        view
+    }
+
+    fn updated(doc_id: &str) -> Output {
+        Output::DocumentUpdated {
+            process_id: "test".into(),
+            collection_name: "cubes".into(),
+            doc_id: doc_id.into(),
+            changes: DocumentChanges::default(),
+        }
+    }
+
+    fn update(doc_id: &str) -> DocumentUpdate {
+        DocumentUpdate {
+            collection_name: "cubes".into(),
+            doc_id: doc_id.into(),
+            changes: DocumentChanges::default(),
+        }
+    }
+
+    #[test]
+    fn other_collections_are_filtered_out() {
+        let lobby = lobby();
+        let collections = Some(["cubes".to_string()].into());
+        let mut view = View::new(SubscriptionFilter { collections, region: None });
+        assert!(view.is_stateless());
+
+        let splash = &lobby.collections["splashes"]["1"];
+        assert!(!view.admit("splashes", "1", splash));
+        assert!(view.admit("cubes", "3", &lobby.collections["cubes"]["3"]));
+
+        let created = Output::DocumentCreated {
+            process_id: "test".into(),
+            collection_name: "splashes".into(),
+            doc_id: "1".into(),
+            document: splash.clone(),
+        };
+        assert!(matches!(view.project(&created, &lobby), Projection::Nothing));
+        let deleted = Output::DocumentDeleted {
+            process_id: "test".into(),
+            collection_name: "splashes".into(),
+            doc_id: "1".into(),
+        };
+        assert!(matches!(view.project(&deleted, &lobby), Projection::Nothing));
+        assert!(matches!(view.project(&updated("2"), &lobby), Projection::All));
+    }
+
+    #[test]
+    fn documents_moving_out_of_the_region_are_removed() {
+        let mut lobby = lobby();
+        let mut view = region_view(&lobby);
+        assert!(view.knows("cubes", "1") && !view.knows("cubes", "2"));
+
+        move_cube(&mut lobby, "1", "5");
+        let Projection::Some(outputs) = view.project(&updated("1"), &lobby) else {
+            panic!("no region transition");
+        };
+        assert!(matches!(
+            &outputs[..],
+            [Output::DocumentLeftRegion { doc_id, .. }] if doc_id == "1"
+        ));
+        assert!(!view.knows("cubes", "1"));
+
+        // outside it stays out of sight
+        move_cube(&mut lobby, "1", "6");
+        assert!(matches!(view.project(&updated("1"), &lobby), Projection::Nothing));
+    }
+
+    #[test]
+    fn documents_moving_into_the_region_are_sent_whole() {
+        let mut lobby = lobby();
+        let mut view = region_view(&lobby);
+
+        move_cube(&mut lobby, "2", "0.5");
+        let Projection::Some(outputs) = view.project(&updated("2"), &lobby) else {
+            panic!("no region transition");
+        };
+        let [Output::DocumentEnteredRegion { doc_id, document, .. }] = &outputs[..] else {
+            panic!("expected a single DocumentEnteredRegion");
+        };
+        assert_eq!(doc_id, "2");
+        assert_eq!(document.position(), Some([0.5, 0.0, 0.0]));
+        assert!(view.knows("cubes", "2"));
+
+        // moving inside is a plain update
+        move_cube(&mut lobby, "2", "-0.5");
+        assert!(matches!(view.project(&updated("2"), &lobby), Projection::All));
+    }
+
+    #[test]
+    fn batched_updates_are_split_by_region() {
+        let mut lobby = lobby();
+        let mut view = region_view(&lobby);
+        let batch = |doc_ids: &[&str]| Output::DocumentsUpdated {
+            process_id: "test".into(),
+            updates: doc_ids.iter().map(|doc_id| update(doc_id)).collect(),
+        };
+
+        // all inside, the batch as is
+        assert!(matches!(view.project(&batch(&["1"]), &lobby), Projection::All));
+
+        // 1 stays inside, 2 enters, 3 has no position
+        move_cube(&mut lobby, "2", "1");
+        let Projection::Some(outputs) = view.project(&batch(&["1", "2", "3"]), &lobby) else {
+            panic!("batch not narrowed");
+        };
+        match &outputs[..] {
+            [
+                Output::DocumentsUpdated { updates, .. },
+                Output::DocumentEnteredRegion { doc_id, .. },
+            ] => {
+                assert_eq!(updates.iter().map(|u| u.doc_id.as_str()).collect::<Vec<_>>(), ["1"]);
+                assert_eq!(doc_id, "2");
+            }
+            _ => panic!("expected the kept updates, then the transition"),
+        }
+    }
+
+    #[test]
+    fn documents_without_a_position_are_outside_every_region() {
+        let mut lobby = lobby();
+        let mut view = region_view(&lobby);
+        assert!(!view.knows("cubes", "3"));
+        assert!(matches!(view.project(&updated("3"), &lobby), Projection::Nothing));
+
+        // losing its position takes a document out of the region
+        move_cube(&mut lobby, "1", "");
+        assert!(matches!(view.project(&updated("1"), &lobby), Projection::Some(_)));
+        assert!(!view.knows("cubes", "1"));
+
+        // without a region they are synced like any other
+        let mut view = View::new(SubscriptionFilter::default());
+        assert!(view.admit("cubes", "3", &lobby.collections["cubes"]["3"]));
+    }
+}
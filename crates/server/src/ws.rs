@@ -1,111 +1,525 @@
 use crate::{
-    messages::{Input, Output},
+    auth::{self, Credentials},
+    encoding::Encoding,
+    errors::{ErrorCode, Rejection},
+    lobby::{AWARENESS_INTERVAL, LobbyCommand, LobbyHandle, MAX_AWARENESS_BYTES, MAX_LEASE_TTL},
+    messages::{
+        FEATURES, Input, InputMessage, Limits, MIN_PROTOCOL_VERSION, Output, PROTOCOL_VERSION,
+        SubscriptionFilter,
+    },
+    metrics::METRICS,
+    outbox::{Delivery, Outbox},
     server::Server,
+    types::Subscriber,
 };
+use anyhow::Error;
 use futures_util::{SinkExt, StreamExt};
-use std::sync::Arc;
-use tokio::{
-    net::TcpStream,
-    sync::{Mutex, mpsc},
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::time::Instant;
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{
+        Message,
+        protocol::{CloseFrame, frame::coding::CloseCode},
+    },
 };
-use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
+use tracing::{Span, error, info, info_span, instrument, warn};
+use uuid::Uuid;
 
-pub async fn handle_websocket(
-    ws_stream: WebSocketStream<TcpStream>,
-    process_id: String,
-    server: Arc<Mutex<Server>>,
-) {
-    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+/// most lobbies a single connection may be joined to at once
+const MAX_JOINED_PROCESSES: usize = 32;
+/// how long queued messages get to reach the client once the connection is closing
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// how long the process of the connection url waits for the client's `Hello` before it is
+/// synced in the legacy format
+const HELLO_TIMEOUT: Duration = Duration::from_millis(300);
 
-    {
-        let mut server = server.lock().await;
-        server.add_subscriber(&process_id, tx);
+/// one socket, subscribed to any number of lobbies
+struct Connection {
+    // identity of this connection in presence and leases, shared by all its lobbies
+    connection_id: String,
+    // outgoing messages of every joined lobby, encoded as negotiated
+    subscriber: Subscriber,
+    server: Arc<Server>,
+    // process_id -> lobby task
+    joined: HashMap<String, LobbyHandle>,
+    // process of the connection url, target of inputs that do not name one
+    default_process: Option<String>,
+    // process of the connection url and its `since`, joined once the first input or
+    // `HELLO_TIMEOUT` tells whether the client speaks a versioned protocol
+    pending_join: Option<(String, Option<(String, u64)>)>,
+    // set by the first input, `Hello` is only accepted before it
+    handshake_done: bool,
+    // set by an accepted `Hello`, lobbies sync clients without one in the legacy format
+    versioned: bool,
+    // text the client signs to authenticate
+    challenge: String,
+    // verified address, once authenticated
+    address: Option<String>,
+}
+
+impl Connection {
+    /// the server side of the handshake, sent before anything else
+    fn hello(&self) -> Output {
+        let config = self.server.config();
+        Output::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            connection_id: self.connection_id.clone(),
+            encodings: Encoding::ALL.iter().map(|e| e.protocol().to_string()).collect(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+            limits: Limits {
+                max_joined_processes: MAX_JOINED_PROCESSES,
+                max_awareness_bytes: MAX_AWARENESS_BYTES,
+                awareness_interval_ms: AWARENESS_INTERVAL.as_millis() as u64,
+                max_lease_ttl_ms: MAX_LEASE_TTL.as_millis() as u64,
+                outbound_queue_capacity: self.subscriber.outbox.capacity(),
+                ping_interval_ms: config.ping_interval.as_millis() as u64,
+                idle_timeout_ms: config.idle_timeout.as_millis() as u64,
+                max_connection_lifetime_ms: config.max_connection_lifetime.as_millis() as u64,
+                event_buffer_capacity: config.event_buffer_capacity,
+                sync_chunk_documents: config.sync_chunk_documents,
+                broadcast_tick_ms: config.broadcast_tick.map(|tick| tick.as_millis() as u64),
+            },
+            challenge: self.challenge.clone(),
+        }
     }
 
-    {
-        let mut server = server.lock().await;
-        if let Ok(lobby) = server.get_lobby(&process_id).await {
-            if let Ok(collections) = lobby.get_full_state() {
-                let full_sync = Output::FullSync { process_id: process_id.clone(), collections };
-                if let Ok(msg) = serde_json::to_string(&full_sync) {
-                    let _ = ws_sender.send(Message::Text(msg.into())).await;
+    /// bind the address proven by a signature over the challenge, or by a session token
+    fn authenticate(
+        &mut self,
+        credentials: Credentials,
+        request_id: Option<String>,
+    ) -> Result<(), Error> {
+        if let Some(address) = &self.address {
+            return Err(Rejection::error(
+                ErrorCode::Conflict,
+                format!("already authenticated as {}", address),
+            ));
+        }
+        if let Some(method) = credentials.method() {
+            if !self.server.config().auth_methods.contains(&method) {
+                return Err(Rejection::error(
+                    ErrorCode::Unauthorized,
+                    format!("{} signatures are not accepted", method),
+                ));
+            }
+        }
+
+        let verified = match &credentials {
+            Credentials::Session { token } => self.server.sessions().verify(token),
+            credentials => auth::verify_signature(credentials, &self.challenge),
+        };
+        let address =
+            verified.map_err(|e| Rejection::error(ErrorCode::Unauthorized, e.to_string()))?;
+        let (session, expires_at) = self.server.sessions().issue(&address)?;
+
+        info!(%address, "authenticated");
+        self.address = Some(address.clone());
+        self.subscriber.send_output(&Output::Authenticated {
+            address,
+            session,
+            expires_at,
+            request_id,
+        })
+    }
+
+    /// check the client's side of the handshake and answer with the features both support
+    fn welcome(
+        &mut self,
+        protocol_version: u32,
+        features: Vec<String>,
+        request_id: Option<String>,
+    ) -> Result<(), Error> {
+        if self.handshake_done {
+            return Err(Rejection::error(ErrorCode::Conflict, "Hello must be the first input"));
+        }
+        self.handshake_done = true;
+
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+            return Err(Rejection::error(
+                ErrorCode::IncompatibleVersion,
+                format!(
+                    "protocol version {} is not supported, expected {} to {}",
+                    protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ),
+            ));
+        }
+
+        let features = features.into_iter().filter(|f| FEATURES.contains(&f.as_str())).collect();
+        self.versioned = true;
+        self.subscriber.send_output(&Output::Welcome { protocol_version, features, request_id })?;
+
+        // a slow `Hello` finds the url process synced in the legacy format, sync it again
+        if self.pending_join.is_none() {
+            if let Some(process_id) = self.default_process.clone() {
+                if let Some(lobby) = self.joined.remove(&process_id) {
+                    lobby.send(LobbyCommand::Disconnect {
+                        connection_id: self.connection_id.clone(),
+                    })?;
+                    self.pending_join = Some((process_id, None));
                 }
             }
         }
+        self.join_pending();
+        Ok(())
     }
 
-    // fwd channel messages to ws
-    let ws_sender_task = tokio::spawn(async move {
-        while let Some(message) = rx.recv().await {
-            if ws_sender.send(Message::Text(message.into())).await.is_err() {
-                break;
+    /// join the process of the connection url, once it is known how to sync the client
+    fn join_pending(&mut self) {
+        if let Some((process_id, since)) = self.pending_join.take() {
+            if let Err(e) = self.join(&process_id, since, None, None) {
+                warn!(%process_id, error = %e, "failed to join process");
+                self.reply_error(Some(process_id), None, e);
             }
         }
-    });
+    }
 
-    // msgs ingress handlers
-    while let Some(message) = ws_receiver.next().await {
-        if let Ok(Message::Text(text)) = message {
-            if let Ok(input) = serde_json::from_str::<Input>(&text) {
-                let mut server = server.lock().await;
-
-                match input {
-                    Input::CreateDocument { collection_name, document } => {
-                        match server.create_document(&process_id, &collection_name, document).await
-                        {
-                            Ok(doc_id) => {
-                                println!(
-                                    "created document {} in collection {}",
-                                    doc_id, collection_name
-                                );
-                            }
-                            Err(e) => {
-                                eprintln!("failed to create document: {}", e);
-                            }
-                        }
-                    }
+    fn stats(&self, request_id: Option<String>) -> Result<(), Error> {
+        let outbox = &self.subscriber.outbox;
+        let stats = outbox.stats();
+        self.subscriber.send_output(&Output::ConnectionStats {
+            connection_id: self.connection_id.clone(),
+            queue_depth: stats.depth,
+            queue_capacity: outbox.capacity(),
+            overflow_policy: outbox.policy(),
+            dropped: stats.dropped,
+            coalesced: stats.coalesced,
+            request_id,
+        })
+    }
 
-                    Input::UpdateDocument { collection_name, doc_id, changes } => {
-                        match server
-                            .update_document(&process_id, &collection_name, &doc_id, changes)
-                            .await
-                        {
-                            Ok(_) => {
-                                println!(
-                                    "updated document {} in collection {}",
-                                    doc_id, collection_name
-                                );
-                            }
-                            Err(e) => {
-                                eprintln!("failed to update document: {}", e);
-                            }
-                        }
-                    }
+    fn join(
+        &mut self,
+        process_id: &str,
+        since: Option<(String, u64)>,
+        filter: Option<SubscriptionFilter>,
+        request_id: Option<String>,
+    ) -> Result<(), Error> {
+        // lobbies unloaded since they were joined can be joined again
+        self.joined.retain(|_, lobby| lobby.is_alive());
+        if self.joined.contains_key(process_id) {
+            return Err(Rejection::error(
+                ErrorCode::Conflict,
+                format!("already joined process {}", process_id),
+            ));
+        }
+        if self.joined.len() >= MAX_JOINED_PROCESSES {
+            return Err(Rejection::error(
+                ErrorCode::InvalidArgument,
+                format!("cannot join more than {} processes", MAX_JOINED_PROCESSES),
+            ));
+        }
+
+        // the lobby task queues a FullSync or replay ahead of any broadcast
+        let lobby = self.server.lobby(process_id)?;
+        lobby.send(LobbyCommand::Subscribe {
+            connection_id: self.connection_id.clone(),
+            since,
+            filter,
+            request_id,
+            subscriber: self.subscriber.clone(),
+            legacy: !self.versioned,
+        })?;
+        self.joined.insert(process_id.to_string(), lobby);
+        info!(%process_id, "joined process");
+        Ok(())
+    }
+
+    fn leave(&mut self, process_id: &str, request_id: Option<String>) -> Result<(), Error> {
+        let lobby = self.joined.remove(process_id).ok_or_else(|| not_joined(process_id))?;
+        if self.default_process.as_deref() == Some(process_id) {
+            self.default_process = None;
+        }
 
-                    Input::DeleteDocument { collection_name, doc_id } => {
-                        match server.delete_document(&process_id, &collection_name, &doc_id).await {
-                            Ok(_) => {
-                                println!(
-                                    "deleted document {} from collection {}",
-                                    doc_id, collection_name
-                                );
-                            }
-                            Err(e) => {
-                                eprintln!("failed to delete document: {}", e);
-                            }
-                        }
+        lobby
+            .send(LobbyCommand::Leave { connection_id: self.connection_id.clone(), request_id })?;
+        info!(%process_id, "left process");
+        Ok(())
+    }
+
+    fn forward(
+        &mut self,
+        process_id: Option<&str>,
+        request_id: Option<String>,
+        input: Input,
+    ) -> Result<(), Error> {
+        let process_id = process_id.ok_or_else(|| {
+            Rejection::error(ErrorCode::InvalidMessage, "input does not name a process_id")
+        })?;
+        let lobby = self.joined.get(process_id).ok_or_else(|| not_joined(process_id))?;
+        if input.needs_auth() && self.address.is_none() {
+            return Err(Rejection::error(
+                ErrorCode::Unauthorized,
+                "authenticate before changing a lobby",
+            ));
+        }
+
+        let sent = lobby.send(LobbyCommand::Input {
+            connection_id: self.connection_id.clone(),
+            address: self.address.clone(),
+            request_id,
+            input: Box::new(input),
+            reply: None,
+            span: Span::current(),
+        });
+        if sent.is_err() {
+            self.joined.remove(process_id);
+        }
+        sent
+    }
+
+    /// text frames are always json, binary frames use the negotiated binary encoding; returns
+    /// a close frame when the client has to be disconnected
+    fn handle_frame(&mut self, payload: &[u8], binary: bool) -> Option<CloseFrame> {
+        let encoding = if binary { self.subscriber.encoding() } else { Encoding::Json };
+        if binary && encoding == Encoding::Json {
+            let e = Rejection::error(
+                ErrorCode::InvalidMessage,
+                "binary frames need the olta.msgpack or olta.cbor subprotocol",
+            );
+            self.reply_error(None, None, e);
+            return None;
+        }
+
+        let InputMessage { input, process_id, request_id } =
+            match encoding.decode::<InputMessage>(payload) {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!(?encoding, error = %e, "failed to parse message");
+                    let request_id = encoding
+                        .decode::<serde_json::Value>(payload)
+                        .ok()
+                        .and_then(|value| value.get("request_id")?.as_str().map(str::to_string));
+                    self.reply_error(
+                        None,
+                        request_id,
+                        Rejection::error(ErrorCode::InvalidMessage, e.to_string()),
+                    );
+                    return None;
+                }
+            };
+        METRICS.messages_in.with_label_values(&[input.kind()]).inc();
+        let mut process_id = process_id.or_else(|| self.default_process.clone());
+        let span = info_span!(
+            "input",
+            instruction = input.kind(),
+            process_id = process_id.as_deref(),
+            request_id = request_id.as_deref(),
+        );
+        let _entered = span.enter();
+
+        if !matches!(input, Input::Hello { .. }) {
+            self.join_pending();
+        }
+        let result = match input {
+            Input::Hello { protocol_version, features } => {
+                process_id = None;
+                match self.welcome(protocol_version, features, request_id.clone()) {
+                    Err(e) if Rejection::code_of(&e) == ErrorCode::IncompatibleVersion => {
+                        warn!(error = %e, "rejecting client");
+                        let reason = e.to_string();
+                        self.reply_error(None, request_id, e);
+                        return Some(CloseFrame {
+                            code: CloseCode::Protocol,
+                            reason: reason.into(),
+                        });
                     }
-                    Input::JoinProcess { .. } => todo!(),
+                    result => result,
                 }
-            } else {
-                eprintln!("failed to parse message: {}", text);
             }
-        } else if let Ok(Message::Close(_)) = message {
-            println!("ws connection closed for process {}", process_id);
+            Input::Authenticate { credentials } => {
+                process_id = None;
+                self.authenticate(credentials, request_id.clone())
+            }
+            Input::GetConnectionStats {} => {
+                process_id = None;
+                self.stats(request_id.clone())
+            }
+            Input::JoinProcess { process_id: target, since, epoch, filter } => {
+                let result = self.join(&target, epoch.zip(since), filter, request_id.clone());
+                process_id = Some(target);
+                result
+            }
+            Input::LeaveProcess { process_id: target } => {
+                let result = self.leave(&target, request_id.clone());
+                process_id = Some(target);
+                result
+            }
+            input => self.forward(process_id.as_deref(), request_id.clone(), input),
+        };
+        self.handshake_done = true;
+        if let Err(e) = result {
+            warn!(error = %e, "failed to handle message");
+            self.reply_error(process_id, request_id, e);
+        }
+        None
+    }
+
+    /// answer input that never reached a lobby
+    fn reply_error(&self, process_id: Option<String>, request_id: Option<String>, error: Error) {
+        let error = Output::Error {
+            process_id,
+            code: Rejection::code_of(&error),
+            message: error.to_string(),
+            request_id,
+        };
+        if let Err(e) = self.subscriber.send_output(&error) {
+            error!(error = %e, "failed to reply");
+        }
+    }
+
+    /// drop every subscription, releasing presence and leases in each lobby, and stop
+    /// accepting outgoing frames
+    fn close(self) {
+        for lobby in self.joined.values() {
+            let _ =
+                lobby.send(LobbyCommand::Disconnect { connection_id: self.connection_id.clone() });
+        }
+        self.subscriber.close(None);
+    }
+}
+
+fn not_joined(process_id: &str) -> Error {
+    Rejection::error(ErrorCode::NotJoined, format!("not joined to process {}", process_id))
+}
+
+#[instrument(name = "connection", skip_all, fields(connection_id))]
+pub async fn handle_websocket(
+    ws_stream: WebSocketStream<TokioIo<Upgraded>>,
+    process_id: Option<String>,
+    since: Option<(String, u64)>,
+    encoding: Encoding,
+    server: Arc<Server>,
+) {
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    let config = server.config();
+    let outbox =
+        Arc::new(Outbox::new(config.outbound_queue_capacity, config.overflow_policy, encoding));
+
+    let connection_id = Uuid::new_v4().to_string();
+    Span::current().record("connection_id", connection_id.as_str());
+    let mut connection = Connection {
+        connection_id,
+        subscriber: Subscriber { outbox: outbox.clone() },
+        server,
+        joined: HashMap::new(),
+        pending_join: process_id.clone().map(|process_id| (process_id, since)),
+        default_process: process_id,
+        handshake_done: false,
+        versioned: false,
+        challenge: auth::challenge(),
+        address: None,
+    };
+    if let Err(e) = connection.subscriber.send_output(&connection.hello()) {
+        error!(error = %e, "failed to greet client");
+        return;
+    }
+
+    METRICS.connections.inc();
+
+    // fwd queued frames to ws, until the outbox is closed and drained
+    let mut ws_sender_task = tokio::spawn(async move {
+        while let Some(message) = outbox.recv().await {
+            let closing = matches!(message, Message::Close(_));
+            if ws_sender.send(message).await.is_err() || closing {
+                break;
+            }
+        }
+        let _ = ws_sender.close().await;
+    });
+
+    let config = connection.server.config();
+    let (idle_timeout, expires_at) =
+        (config.idle_timeout, Instant::now() + config.max_connection_lifetime);
+    let mut ping =
+        tokio::time::interval_at(Instant::now() + config.ping_interval, config.ping_interval);
+    let mut last_seen = Instant::now();
+    let mut closing = std::pin::pin!(connection.server.closing());
+    let hello_deadline = Instant::now() + HELLO_TIMEOUT;
+
+    // msgs ingress handlers, until the client leaves, goes quiet, or the forward task gave up on
+    // it
+    loop {
+        let message = tokio::select! {
+            message = ws_receiver.next() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = &mut ws_sender_task => {
+                info!("connection stopped accepting frames");
+                break;
+            }
+            _ = tokio::time::sleep_until(hello_deadline), if connection.pending_join.is_some() => {
+                connection.join_pending();
+                continue;
+            }
+            _ = ping.tick() => {
+                // only the latest ping is worth keeping in a backed up queue
+                connection
+                    .subscriber
+                    .send(Message::Ping(Default::default()), Delivery::Ephemeral("ping".into()));
+                continue;
+            }
+            _ = tokio::time::sleep_until(last_seen + idle_timeout) => {
+                info!("connection idle, closing");
+                connection.subscriber.close(Some(CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "idle timeout".into(),
+                }));
+                break;
+            }
+            _ = &mut closing => {
+                info!("server shutting down, closing");
+                connection.subscriber.close(Some(CloseFrame {
+                    code: CloseCode::Restart,
+                    reason: "server restarting, reconnect".into(),
+                }));
+                break;
+            }
+            _ = tokio::time::sleep_until(expires_at) => {
+                info!("connection reached its maximum lifetime");
+                connection.subscriber.close(Some(CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "maximum connection lifetime reached, reconnect".into(),
+                }));
+                break;
+            }
+        };
+        // any frame, pongs included, shows the client is still there
+        last_seen = Instant::now();
+
+        let close = match &message {
+            Ok(Message::Text(text)) => connection.handle_frame(text.as_bytes(), false),
+            Ok(Message::Binary(bytes)) => connection.handle_frame(bytes, true),
+            _ => None,
+        };
+        if let Some(frame) = close {
+            connection.subscriber.close(Some(frame));
             break;
         }
+
+        match message {
+            Ok(Message::Close(_)) => {
+                info!("connection closed");
+                break;
+            }
+            Err(e) => {
+                warn!(error = %e, "connection error");
+                break;
+            }
+            _ => {}
+        }
     }
 
-    ws_sender_task.abort();
+    // closing the outbox ends the forward task once it sent what is queued
+    connection.close();
+    if !ws_sender_task.is_finished()
+        && tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, &mut ws_sender_task).await.is_err()
+    {
+        ws_sender_task.abort();
+    }
+    METRICS.connections.dec();
 }
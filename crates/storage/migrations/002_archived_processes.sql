@@ -0,0 +1,10 @@
+CREATE TABLE archived_processes (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    process_id VARCHAR(255) NOT NULL,
+    full_state JSONB NOT NULL,
+    last_activity TIMESTAMPTZ,
+    created_at TIMESTAMPTZ,
+    archived_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX idx_archived_processes_process ON archived_processes(process_id);
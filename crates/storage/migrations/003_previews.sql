@@ -0,0 +1,6 @@
+CREATE TABLE previews (
+    process_id VARCHAR(255) PRIMARY KEY
+        REFERENCES processes(process_id) ON DELETE CASCADE ON UPDATE CASCADE,
+    png BYTEA NOT NULL,
+    rendered_at TIMESTAMPTZ DEFAULT NOW()
+);
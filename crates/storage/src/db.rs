@@ -1,6 +1,13 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
 use serde_json;
 use sqlx::{PgPool, Row};
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use tokio::sync::oneshot;
+use tracing::{Instrument, Span, error, info, instrument};
 
 /// database connection and operations for lobby persistence
 #[derive(Debug, Clone)]
@@ -10,7 +17,44 @@ pub struct Database {
 
 #[derive(Debug, Clone)]
 pub enum DbOperation {
-    SaveProcessState { process_id: String, full_state: String, is_hot: bool },
+    SaveProcessState {
+        process_id: String,
+        full_state: String,
+        is_hot: bool,
+    },
+    /// replace the preview image of a stored process
+    SavePreview {
+        process_id: String,
+        png: Vec<u8>,
+    },
+    /// writes nothing, once it is done so is everything queued before it
+    Flush,
+}
+
+/// an operation and the span it was queued from, where the worker reports on it
+#[derive(Debug)]
+pub struct DbJob {
+    pub operation: DbOperation,
+    pub span: Span,
+    // gets the result once the operation ran
+    pub done: Option<oneshot::Sender<Result<()>>>,
+}
+
+impl From<DbOperation> for DbJob {
+    fn from(operation: DbOperation) -> Self {
+        Self { operation, span: Span::current(), done: None }
+    }
+}
+
+/// a stored process, as `list_processes` returns it
+#[derive(Debug, Clone)]
+pub struct ProcessSummary {
+    pub process_id: String,
+    // bytes of its state as json
+    pub size: i64,
+    pub is_hot: bool,
+    pub last_activity: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
 }
 
 impl Database {
@@ -28,12 +72,19 @@ impl Database {
         Ok(())
     }
 
+    /// check that a connection can be acquired and answers
+    pub async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await.context("Database ping failed")?;
+        Ok(())
+    }
+
     /// get the underlying pool for advanced operations
     pub fn pool(&self) -> &PgPool {
         &self.pool
     }
 
     /// Save complete lobby state to database
+    #[instrument(level = "debug", skip(self, full_state))]
     pub async fn save_process_state(
         &self,
         process_id: &str,
@@ -65,6 +116,7 @@ impl Database {
     }
 
     /// load process state from database
+    #[instrument(level = "debug", skip(self))]
     pub async fn load_process_state(&self, process_id: &str) -> Result<Option<String>> {
         let row = sqlx::query("SELECT full_state FROM processes WHERE process_id = $1")
             .bind(process_id)
@@ -74,34 +126,235 @@ impl Database {
 
         Ok(row.map(|r| r.get::<serde_json::Value, _>("full_state").to_string()))
     }
+
+    /// whether a process is stored, without loading it
+    pub async fn process_exists(&self, process_id: &str) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM processes WHERE process_id = $1")
+            .bind(process_id)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Failed to look up process")?;
+        Ok(row.is_some())
+    }
+
+    /// store a process' preview image, which is dropped with the process
+    #[instrument(level = "debug", skip(self, png), fields(bytes = png.len()))]
+    pub async fn save_preview(&self, process_id: &str, png: &[u8]) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO previews (process_id, png, rendered_at)
+            VALUES ($1, $2, NOW())
+            ON CONFLICT (process_id)
+            DO UPDATE SET png = $2, rendered_at = NOW()
+            "#,
+        )
+        .bind(process_id)
+        .bind(png)
+        .execute(&self.pool)
+        .await
+        .context("Failed to save preview")?;
+        Ok(())
+    }
+
+    /// the latest preview image of a process
+    #[instrument(level = "debug", skip(self))]
+    pub async fn load_preview(&self, process_id: &str) -> Result<Option<Vec<u8>>> {
+        let row = sqlx::query("SELECT png FROM previews WHERE process_id = $1")
+            .bind(process_id)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Failed to load preview")?;
+        Ok(row.map(|r| r.get("png")))
+    }
+
+    /// every stored process, most recently active first
+    pub async fn list_processes(&self) -> Result<Vec<ProcessSummary>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT process_id, octet_length(full_state::text) AS size, is_hot, last_activity,
+                created_at
+            FROM processes
+            ORDER BY last_activity DESC NULLS LAST, process_id
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to list processes")?;
+
+        Ok(rows
+            .into_iter()
+            .map(|r| ProcessSummary {
+                process_id: r.get("process_id"),
+                size: r.get::<Option<i32>, _>("size").unwrap_or_default().into(),
+                is_hot: r.get::<Option<bool>, _>("is_hot").unwrap_or_default(),
+                last_activity: r.get("last_activity"),
+                created_at: r.get("created_at"),
+            })
+            .collect())
+    }
+
+    /// delete a process, false when there was none
+    pub async fn delete_process(&self, process_id: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM processes WHERE process_id = $1")
+            .bind(process_id)
+            .execute(&self.pool)
+            .await
+            .context("Failed to delete process")?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// move a process to `archived_processes`, which frees its id; false when there was none
+    pub async fn archive_process(&self, process_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            WITH archived AS (
+                DELETE FROM processes WHERE process_id = $1
+                RETURNING process_id, full_state, last_activity, created_at
+            )
+            INSERT INTO archived_processes (process_id, full_state, last_activity, created_at)
+            SELECT process_id, full_state, last_activity, created_at FROM archived
+            "#,
+        )
+        .bind(process_id)
+        .execute(&self.pool)
+        .await
+        .context("Failed to archive process")?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// give a process another id, which must be free
+    pub async fn rename_process(&self, process_id: &str, new_process_id: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        check_free(&mut tx, new_process_id).await?;
+        let result = sqlx::query(
+            r#"
+            UPDATE processes
+            SET process_id = $2, full_state = jsonb_set(full_state, '{process_id}', to_jsonb($2::text))
+            WHERE process_id = $1
+            "#,
+        )
+        .bind(process_id)
+        .bind(new_process_id)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to rename process")?;
+        if result.rows_affected() == 0 {
+            bail!("process {} not found", process_id);
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// copy a process to another id, which must be free
+    pub async fn fork_process(&self, process_id: &str, new_process_id: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        check_free(&mut tx, new_process_id).await?;
+        let result = sqlx::query(
+            r#"
+            INSERT INTO processes (process_id, full_state, is_hot, last_activity)
+            SELECT $2, jsonb_set(full_state, '{process_id}', to_jsonb($2::text)), false, NOW()
+            FROM processes WHERE process_id = $1
+            "#,
+        )
+        .bind(process_id)
+        .bind(new_process_id)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to fork process")?;
+        if result.rows_affected() == 0 {
+            bail!("process {} not found", process_id);
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+/// an error when `process_id` is taken
+async fn check_free(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    process_id: &str,
+) -> Result<()> {
+    let taken = sqlx::query("SELECT 1 FROM processes WHERE process_id = $1 FOR UPDATE")
+        .bind(process_id)
+        .fetch_optional(&mut **tx)
+        .await?
+        .is_some();
+    if taken {
+        bail!("process {} already exists", process_id);
+    }
+    Ok(())
+}
+
+/// what the worker is up to, readable while it runs
+#[derive(Debug, Default)]
+pub struct WorkerStats {
+    // operations waiting behind the one in progress
+    queue_depth: AtomicUsize,
+    failed: AtomicU64,
+}
+
+impl WorkerStats {
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
 }
 
 /// background worker for database operations
 pub struct DatabaseWorker {
     database: Database,
-    receiver: tokio::sync::mpsc::UnboundedReceiver<DbOperation>,
+    receiver: tokio::sync::mpsc::UnboundedReceiver<DbJob>,
+    stats: Arc<WorkerStats>,
 }
 
 impl DatabaseWorker {
-    pub fn new(
-        database: Database,
-        receiver: tokio::sync::mpsc::UnboundedReceiver<DbOperation>,
-    ) -> Self {
-        Self { database, receiver }
+    pub fn new(database: Database, receiver: tokio::sync::mpsc::UnboundedReceiver<DbJob>) -> Self {
+        Self { database, receiver, stats: Arc::default() }
     }
 
-    /// Run the background worker
-    pub async fn run(mut self) {
-        println!("db worker started");
+    pub fn stats(&self) -> Arc<WorkerStats> {
+        self.stats.clone()
+    }
 
-        while let Some(operation) = self.receiver.recv().await {
-            if let Err(e) = self.process_operation(operation).await {
-                eprintln!("db operation failed: {}", e);
-                // TODO: implement retry logic with exponential backoff
+    /// Run the background worker, until every sender is gone or `drain` completes; from then
+    /// on nothing new is accepted and the worker stops once the queued operations are done
+    pub async fn run(mut self, drain: impl Future<Output = ()>) {
+        info!("db worker started");
+        let mut drain = std::pin::pin!(drain);
+        let mut draining = false;
+
+        loop {
+            let job = tokio::select! {
+                job = self.receiver.recv() => job,
+                _ = &mut drain, if !draining => {
+                    info!(queued = self.receiver.len(), "db worker draining");
+                    self.receiver.close();
+                    draining = true;
+                    continue;
+                }
+            };
+            let Some(DbJob { operation, span, done }) = job else { break };
+            self.stats.queue_depth.store(self.receiver.len(), Ordering::Relaxed);
+            async {
+                let result = self.process_operation(operation).await;
+                if let Err(e) = &result {
+                    self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                    error!(error = %e, "db operation failed");
+                    // TODO: implement retry logic with exponential backoff
+                }
+                if let Some(done) = done {
+                    let _ = done.send(result);
+                }
             }
+            .instrument(span)
+            .await;
+            self.stats.queue_depth.store(self.receiver.len(), Ordering::Relaxed);
         }
 
-        println!("db worker stopped");
+        info!("db worker stopped");
     }
 
     async fn process_operation(&self, operation: DbOperation) -> Result<()> {
@@ -109,7 +362,111 @@ impl DatabaseWorker {
             DbOperation::SaveProcessState { process_id, full_state, is_hot } => {
                 self.database.save_process_state(&process_id, &full_state, is_hot).await?;
             }
+            DbOperation::SavePreview { process_id, png } => {
+                self.database.save_preview(&process_id, &png).await?;
+            }
+            DbOperation::Flush => {}
         }
         Ok(())
     }
 }
+
+/// these need a postgres database at TEST_DATABASE_URL and pass without one
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    async fn database() -> Option<Database> {
+        let url = std::env::var("TEST_DATABASE_URL").ok()?;
+        let database = Database::new(&url).await.unwrap();
+        database.run_migrations().await.unwrap();
+        Some(database)
+    }
+
+    /// ids unique to a test run, so runs share a database
+    fn process_id(name: &str) -> String {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        format!("test-{name}-{nanos}")
+    }
+
+    fn state(process_id: &str) -> String {
+        serde_json::json!({ "process_id": process_id, "collections": {} }).to_string()
+    }
+
+    async fn stored_id(database: &Database, process_id: &str) -> Option<String> {
+        let state = database.load_process_state(process_id).await.unwrap()?;
+        let state: serde_json::Value = serde_json::from_str(&state).unwrap();
+        Some(state["process_id"].as_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn forks_copy_the_state_under_the_new_id() {
+        let Some(database) = database().await else { return };
+        let (source, fork) = (process_id("fork-source"), process_id("fork-copy"));
+        database.save_process_state(&source, &state(&source), true).await.unwrap();
+
+        database.fork_process(&source, &fork).await.unwrap();
+        assert_eq!(stored_id(&database, &fork).await.unwrap(), fork);
+        assert_eq!(stored_id(&database, &source).await.unwrap(), source);
+
+        // onto a taken id, or from a missing one
+        let error = database.fork_process(&source, &fork).await.unwrap_err();
+        assert_eq!(error.to_string(), format!("process {fork} already exists"));
+        let missing = process_id("missing");
+        let error = database.fork_process(&missing, &process_id("other")).await.unwrap_err();
+        assert_eq!(error.to_string(), format!("process {missing} not found"));
+
+        for process_id in [&source, &fork] {
+            database.delete_process(process_id).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn archived_processes_free_their_id() {
+        let Some(database) = database().await else { return };
+        let process_id = process_id("archive");
+        database.save_process_state(&process_id, &state(&process_id), false).await.unwrap();
+
+        assert!(database.archive_process(&process_id).await.unwrap());
+        assert!(!database.process_exists(&process_id).await.unwrap());
+        assert!(!database.archive_process(&process_id).await.unwrap());
+
+        let archived: (String,) = sqlx::query_as(
+            "SELECT full_state->>'process_id' FROM archived_processes WHERE process_id = $1",
+        )
+        .bind(&process_id)
+        .fetch_one(&database.pool)
+        .await
+        .unwrap();
+        assert_eq!(archived.0, process_id);
+
+        // the id can be used again
+        database.save_process_state(&process_id, &state(&process_id), false).await.unwrap();
+        assert!(database.process_exists(&process_id).await.unwrap());
+        database.delete_process(&process_id).await.unwrap();
+        sqlx::query("DELETE FROM archived_processes WHERE process_id = $1")
+            .bind(&process_id)
+            .execute(&database.pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn renames_move_the_state_to_a_free_id() {
+        let Some(database) = database().await else { return };
+        let (old, new, taken) =
+            (process_id("rename-old"), process_id("rename-new"), process_id("taken"));
+        database.save_process_state(&old, &state(&old), false).await.unwrap();
+        database.save_process_state(&taken, &state(&taken), false).await.unwrap();
+
+        assert!(database.rename_process(&old, &taken).await.is_err());
+        database.rename_process(&old, &new).await.unwrap();
+        assert_eq!(stored_id(&database, &new).await.unwrap(), new);
+        assert!(!database.process_exists(&old).await.unwrap());
+
+        for process_id in [&new, &taken] {
+            database.delete_process(process_id).await.unwrap();
+        }
+    }
+}
@@ -1,2 +1,2 @@
 pub mod db;
-pub use db::{Database, DatabaseWorker, DbOperation};
+pub use db::{Database, DatabaseWorker, DbJob, DbOperation, ProcessSummary, WorkerStats};
@@ -6,4 +6,10 @@ pub enum VMErrors {
     WebSocketError(String),
     CollectionUpdateError(String),
     CollectionNotFound(String),
+    InvalidOrderKey(String),
+    DocumentLocked(String),
+    GroupNotFound(String),
+    GroupExists(String),
+    InvalidGroupMember(String),
+    InvalidNumber(String),
 }
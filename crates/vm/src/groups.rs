@@ -0,0 +1,422 @@
+use crate::{
+    errors::VMErrors,
+    numeric::{format_scalar, parse_scalar},
+    transform::{Vec3, apply, from_euler, mul, to_euler},
+    types::{CollectionData, DocumentChanges, Group, GroupMember, Lobby, Transform},
+};
+use std::time::Instant;
+use tracing::instrument;
+
+/// position and, for cubes, rotation of a document, before or after a transform
+struct Placed {
+    member: GroupMember,
+    position: Vec3,
+    rotation: Option<Vec3>,
+}
+
+impl Lobby {
+    #[instrument(level = "debug", skip(self), err(level = "debug", Debug))]
+    pub fn create_group(&mut self, name: &str, parent: Option<&str>) -> Result<(), VMErrors> {
+        if self.groups.contains_key(name) {
+            return Err(VMErrors::GroupExists(name.to_string()));
+        }
+        if let Some(parent) = parent {
+            if !self.groups.contains_key(parent) {
+                return Err(VMErrors::GroupNotFound(parent.to_string()));
+            }
+        }
+
+        self.groups.insert(name.to_string(), Group::default());
+        if let Some(parent) = parent {
+            self.add_to_group(parent, GroupMember::Group { name: name.to_string() })?;
+        }
+        self.hot = true;
+        Ok(())
+    }
+
+    /// delete a group, its members move up to the group's parent (if any)
+    #[instrument(level = "debug", skip(self), err(level = "debug", Debug))]
+    pub fn delete_group(&mut self, name: &str) -> Result<(), VMErrors> {
+        let group = self.groups.remove(name).ok_or(VMErrors::GroupNotFound(name.to_string()))?;
+        let this = GroupMember::Group { name: name.to_string() };
+
+        if let Some(parent) = self.parent_of(&this) {
+            if let Some(parent) = self.groups.get_mut(&parent) {
+                parent.members.remove(&this);
+                parent.members.extend(group.members);
+            }
+        }
+        self.hot = true;
+        Ok(())
+    }
+
+    /// add a document or a nested group to a group, a member belongs to at most one group so it
+    /// leaves its previous group
+    #[instrument(level = "debug", skip(self), err(level = "debug", Debug))]
+    pub fn add_to_group(&mut self, name: &str, member: GroupMember) -> Result<(), VMErrors> {
+        if !self.groups.contains_key(name) {
+            return Err(VMErrors::GroupNotFound(name.to_string()));
+        }
+
+        match &member {
+            GroupMember::Document { collection_name, doc_id } => {
+                let document = self
+                    .collections
+                    .get(collection_name)
+                    .ok_or(VMErrors::CollectionNotFound(collection_name.to_string()))?
+                    .get(doc_id)
+                    .ok_or(VMErrors::DocumentNotFound(doc_id.to_string()))?;
+                // splashes are flat and have no place in a 3d transform
+                if let CollectionData::Splash(_) = document.data {
+                    return Err(VMErrors::InvalidGroupMember(doc_id.to_string()));
+                }
+            }
+            GroupMember::Group { name: child } => {
+                if !self.groups.contains_key(child) {
+                    return Err(VMErrors::GroupNotFound(child.to_string()));
+                }
+                // a group cannot be nested inside itself or one of its descendants
+                if child == name || self.descendant_groups(child).iter().any(|g| g == name) {
+                    return Err(VMErrors::InvalidGroupMember(child.to_string()));
+                }
+            }
+        }
+
+        self.remove_from_group(&member);
+        if let Some(group) = self.groups.get_mut(name) {
+            group.members.insert(member);
+        }
+        self.hot = true;
+        Ok(())
+    }
+
+    /// detach a member from whichever group holds it, returns that group's name
+    #[instrument(level = "debug", skip(self))]
+    pub fn remove_from_group(&mut self, member: &GroupMember) -> Option<String> {
+        let parent = self.parent_of(member)?;
+        if let Some(group) = self.groups.get_mut(&parent) {
+            group.members.remove(member);
+        }
+        self.hot = true;
+        Some(parent)
+    }
+
+    /// every document of a group, including those of nested groups
+    pub fn group_documents(&self, name: &str) -> Result<Vec<GroupMember>, VMErrors> {
+        if !self.groups.contains_key(name) {
+            return Err(VMErrors::GroupNotFound(name.to_string()));
+        }
+
+        let mut groups = vec![name.to_string()];
+        groups.extend(self.descendant_groups(name));
+
+        Ok(groups
+            .iter()
+            .filter_map(|g| self.groups.get(g))
+            .flat_map(|group| group.members.iter())
+            .filter(|member| matches!(member, GroupMember::Document { .. }))
+            .cloned()
+            .collect())
+    }
+
+    /// move and rotate every document of a group at once, nothing is applied if any member is
+    /// locked by someone else, holds an unreadable position or would end up at a non-finite
+    /// one. returns the applied deltas
+    #[instrument(level = "debug", skip(self, transform), err(level = "debug", Debug))]
+    pub fn transform_group(
+        &mut self,
+        name: &str,
+        transform: &Transform,
+        actor: &str,
+    ) -> Result<Vec<(GroupMember, DocumentChanges)>, VMErrors> {
+        // binary encodings carry nan and infinities, which json cannot
+        let values = transform
+            .translate
+            .iter()
+            .chain(&transform.rotate)
+            .chain(transform.pivot.iter().flatten());
+        if let Some(value) = values.copied().find(|v| !v.is_finite()) {
+            return Err(VMErrors::InvalidNumber(format!("transform of {name}: {value}")));
+        }
+
+        let now = Instant::now();
+        let mut placed = Vec::new();
+
+        for member in self.group_documents(name)? {
+            let GroupMember::Document { collection_name, doc_id } = &member else {
+                continue;
+            };
+            self.check_lease(collection_name, doc_id, actor, now)?;
+
+            let Some(document) = self.collections.get(collection_name).and_then(|c| c.get(doc_id))
+            else {
+                continue;
+            };
+            let (position, rotation) = match &document.data {
+                CollectionData::Cube(cube) => (
+                    read_vec3(doc_id, [&cube.x, &cube.y, &cube.z])?,
+                    Some(read_vec3(doc_id, [&cube.rot_x, &cube.rot_y, &cube.rot_z])?),
+                ),
+                CollectionData::Vertex(vertex) => {
+                    (read_vec3(doc_id, [&vertex.x, &vertex.y, &vertex.z])?, None)
+                }
+                CollectionData::Splash(_) => continue,
+            };
+            placed.push(Placed { member, position, rotation });
+        }
+
+        let pivot = transform.pivot.unwrap_or_else(|| centroid(&placed));
+        let rotation = from_euler(transform.rotate);
+
+        let mut targets = Vec::with_capacity(placed.len());
+        for Placed { member, position, rotation: own_rotation } in placed {
+            let offset = apply(&rotation, sub(position, pivot));
+            let moved = [
+                pivot[0] + offset[0] + transform.translate[0],
+                pivot[1] + offset[1] + transform.translate[1],
+                pivot[2] + offset[2] + transform.translate[2],
+            ];
+            let rotated = own_rotation
+                .filter(|_| transform.rotate != [0.0; 3])
+                .map(|own_rotation| to_euler(&mul(&rotation, &from_euler(own_rotation))));
+            // finite inputs can still overflow
+            if moved.iter().chain(rotated.iter().flatten()).any(|v| !v.is_finite()) {
+                return Err(VMErrors::InvalidNumber(format!("transform of {name} overflows")));
+            }
+            targets.push(Placed { member, position: moved, rotation: rotated });
+        }
+
+        let mut applied = Vec::with_capacity(targets.len());
+        for Placed { member, position: moved, rotation: rotated } in targets {
+            let GroupMember::Document { collection_name, doc_id } = &member else {
+                continue;
+            };
+            let Some(document) =
+                self.collections.get_mut(collection_name).and_then(|c| c.get_mut(doc_id))
+            else {
+                continue;
+            };
+
+            let mut changes = DocumentChanges::default();
+            match &mut document.data {
+                CollectionData::Cube(cube) => {
+                    cube.x = format_scalar(moved[0], &cube.x);
+                    cube.y = format_scalar(moved[1], &cube.y);
+                    cube.z = format_scalar(moved[2], &cube.z);
+                    changes.x = Some(cube.x.clone());
+                    changes.y = Some(cube.y.clone());
+                    changes.z = Some(cube.z.clone());
+
+                    if let Some(rotated) = rotated {
+                        cube.rot_x = format_scalar(rotated[0], &cube.rot_x);
+                        cube.rot_y = format_scalar(rotated[1], &cube.rot_y);
+                        cube.rot_z = format_scalar(rotated[2], &cube.rot_z);
+                        changes.rot_x = Some(cube.rot_x.clone());
+                        changes.rot_y = Some(cube.rot_y.clone());
+                        changes.rot_z = Some(cube.rot_z.clone());
+                    }
+                }
+                CollectionData::Vertex(vertex) => {
+                    vertex.x = format_scalar(moved[0], &vertex.x);
+                    vertex.y = format_scalar(moved[1], &vertex.y);
+                    vertex.z = format_scalar(moved[2], &vertex.z);
+                    changes.x = Some(vertex.x.clone());
+                    changes.y = Some(vertex.y.clone());
+                    changes.z = Some(vertex.z.clone());
+                }
+                CollectionData::Splash(_) => continue,
+            }
+            applied.push((member, changes));
+        }
+
+        self.hot = true;
+        Ok(applied)
+    }
+
+    fn parent_of(&self, member: &GroupMember) -> Option<String> {
+        self.groups
+            .iter()
+            .find(|(_, group)| group.members.contains(member))
+            .map(|(name, _)| name.clone())
+    }
+
+    fn descendant_groups(&self, name: &str) -> Vec<String> {
+        let mut found = Vec::new();
+        let mut pending = vec![name.to_string()];
+        while let Some(current) = pending.pop() {
+            let Some(group) = self.groups.get(&current) else {
+                continue;
+            };
+            for member in &group.members {
+                if let GroupMember::Group { name: child } = member {
+                    if !found.contains(child) {
+                        found.push(child.clone());
+                        pending.push(child.clone());
+                    }
+                }
+            }
+        }
+        found
+    }
+}
+
+fn read_vec3(doc_id: &str, values: [&String; 3]) -> Result<Vec3, VMErrors> {
+    let mut out = [0.0; 3];
+    for (slot, value) in out.iter_mut().zip(values) {
+        // missing rotations are common on older documents
+        *slot = if value.is_empty() {
+            0.0
+        } else {
+            parse_scalar(value)
+                .ok_or_else(|| VMErrors::InvalidNumber(format!("{doc_id}: {value}")))?
+        };
+    }
+    Ok(out)
+}
+
+fn centroid(placed: &[Placed]) -> Vec3 {
+    if placed.is_empty() {
+        return [0.0; 3];
+    }
+    let n = placed.len() as f64;
+    let mut sum = [0.0; 3];
+    for p in placed {
+        for (s, v) in sum.iter_mut().zip(p.position) {
+            *s += v;
+        }
+    }
+    sum.map(|s| s / n)
+}
+
+fn sub(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Cube, Document, Vertex};
+    use std::{f64::consts::FRAC_PI_2, time::Duration};
+
+    fn document(data: CollectionData) -> Document {
+        Document { id: 0, creator: "creator".into(), request_id: None, order: String::new(), data }
+    }
+
+    fn cube(x: &str, y: &str, z: &str) -> Document {
+        document(CollectionData::Cube(Cube {
+            x: x.into(),
+            y: y.into(),
+            z: z.into(),
+            ..Default::default()
+        }))
+    }
+
+    fn member(collection_name: &str, doc_id: &str) -> GroupMember {
+        GroupMember::Document { collection_name: collection_name.into(), doc_id: doc_id.into() }
+    }
+
+    fn position(lobby: &Lobby, collection_name: &str, doc_id: &str) -> Vec3 {
+        lobby.collections[collection_name][doc_id].position().unwrap()
+    }
+
+    fn cube_of<'a>(lobby: &'a Lobby, doc_id: &str) -> &'a Cube {
+        match &lobby.collections["cubes"][doc_id].data {
+            CollectionData::Cube(cube) => cube,
+            _ => panic!("{doc_id} is not a cube"),
+        }
+    }
+
+    /// a cube at (1, 0, 0) and a vertex at (3, 0, 0) in `outer`, a cube at (0, 0, 2n) in
+    /// `inner`, which is nested in `outer`
+    fn lobby() -> Lobby {
+        let mut lobby = Lobby::new("test");
+        lobby.create_document("cubes", cube("1", "0", "0")).unwrap();
+        lobby.create_document("cubes", cube("0n", "0n", "2n")).unwrap();
+        let vertex = Vertex { x: "3".into(), y: "0".into(), z: "0".into(), ..Default::default() };
+        lobby.create_document("vertices", document(CollectionData::Vertex(vertex))).unwrap();
+
+        lobby.create_group("outer", None).unwrap();
+        lobby.create_group("inner", Some("outer")).unwrap();
+        lobby.add_to_group("outer", member("cubes", "1")).unwrap();
+        lobby.add_to_group("outer", member("vertices", "1")).unwrap();
+        lobby.add_to_group("inner", member("cubes", "2")).unwrap();
+        lobby
+    }
+
+    #[test]
+    fn translate_moves_nested_members() {
+        let mut lobby = lobby();
+        let transform = Transform { translate: [1.0, 2.0, 3.0], ..Default::default() };
+        let applied = lobby.transform_group("outer", &transform, "alice").unwrap();
+
+        assert_eq!(applied.len(), 3);
+        assert_eq!(position(&lobby, "cubes", "1"), [2.0, 2.0, 3.0]);
+        assert_eq!(position(&lobby, "vertices", "1"), [4.0, 2.0, 3.0]);
+        // bigints stay bigints
+        assert_eq!(cube_of(&lobby, "2").z, "5n");
+        // the inner group alone leaves the outer members in place
+        lobby.transform_group("inner", &transform, "alice").unwrap();
+        assert_eq!(position(&lobby, "cubes", "1"), [2.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn rotation_turns_members_and_cubes_around_the_pivot() {
+        let mut lobby = lobby();
+        let transform = Transform {
+            rotate: [0.0, 0.0, FRAC_PI_2],
+            pivot: Some([0.0, 0.0, 0.0]),
+            ..Default::default()
+        };
+        lobby.transform_group("outer", &transform, "alice").unwrap();
+
+        let [x, y, _] = position(&lobby, "vertices", "1");
+        assert!(x.abs() < 1e-9 && (y - 3.0).abs() < 1e-9);
+        let rot_z: f64 = cube_of(&lobby, "1").rot_z.parse().unwrap();
+        assert!((rot_z - FRAC_PI_2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn non_finite_transforms_are_rejected() {
+        let mut lobby = lobby();
+        for transform in [
+            Transform { translate: [f64::NAN, 0.0, 0.0], ..Default::default() },
+            Transform { rotate: [0.0, f64::INFINITY, 0.0], ..Default::default() },
+            Transform { pivot: Some([0.0, 0.0, f64::NEG_INFINITY]), ..Default::default() },
+            // finite, but the sum is not
+            Transform {
+                translate: [f64::MAX, 0.0, 0.0],
+                pivot: Some([-f64::MAX, 0.0, 0.0]),
+                rotate: [0.0, 0.0, 3.0],
+            },
+        ] {
+            assert!(matches!(
+                lobby.transform_group("outer", &transform, "alice"),
+                Err(VMErrors::InvalidNumber(_))
+            ));
+        }
+        assert_eq!(position(&lobby, "cubes", "1"), [1.0, 0.0, 0.0]);
+        assert_eq!(position(&lobby, "vertices", "1"), [3.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn a_locked_member_blocks_the_whole_transform() {
+        let mut lobby = lobby();
+        lobby.acquire_lease("cubes", "2", "bob", Duration::from_secs(5), Instant::now()).unwrap();
+        let transform = Transform { translate: [1.0, 0.0, 0.0], ..Default::default() };
+
+        assert!(matches!(
+            lobby.transform_group("outer", &transform, "alice"),
+            Err(VMErrors::DocumentLocked(_))
+        ));
+        assert_eq!(position(&lobby, "cubes", "1"), [1.0, 0.0, 0.0]);
+        assert!(lobby.transform_group("outer", &transform, "bob").is_ok());
+    }
+
+    #[test]
+    fn groups_cannot_contain_themselves() {
+        let mut lobby = lobby();
+        let outer = GroupMember::Group { name: "outer".into() };
+        assert!(lobby.add_to_group("inner", outer.clone()).is_err());
+        assert!(lobby.add_to_group("outer", outer).is_err());
+    }
+}
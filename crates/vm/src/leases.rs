@@ -0,0 +1,174 @@
+use crate::{
+    errors::VMErrors,
+    types::{CollectionName, Lease, Lobby},
+};
+use std::time::{Duration, Instant};
+use tracing::instrument;
+
+impl Lobby {
+    /// take or renew the exclusive edit lease on a document, returns its expiry
+    #[instrument(level = "debug", skip(self, now), err(level = "debug", Debug))]
+    pub fn acquire_lease(
+        &mut self,
+        collection_name: &str,
+        doc_id: &str,
+        holder: &str,
+        ttl: Duration,
+        now: Instant,
+    ) -> Result<Instant, VMErrors> {
+        self.collections
+            .get(collection_name)
+            .ok_or(VMErrors::CollectionNotFound(collection_name.to_string()))?
+            .get(doc_id)
+            .ok_or(VMErrors::DocumentNotFound(doc_id.to_string()))?;
+
+        self.check_lease(collection_name, doc_id, holder, now)?;
+
+        let expires_at = now + ttl;
+        self.leases.insert(
+            (collection_name.to_string(), doc_id.to_string()),
+            Lease { holder: holder.to_string(), expires_at },
+        );
+        Ok(expires_at)
+    }
+
+    /// drop a lease, only its holder can release it
+    #[instrument(level = "debug", skip(self), err(level = "debug", Debug))]
+    pub fn release_lease(
+        &mut self,
+        collection_name: &str,
+        doc_id: &str,
+        holder: &str,
+    ) -> Result<bool, VMErrors> {
+        let key = (collection_name.to_string(), doc_id.to_string());
+        match self.leases.get(&key) {
+            Some(lease) if lease.holder != holder => {
+                Err(VMErrors::DocumentLocked(lease.holder.clone()))
+            }
+            Some(_) => Ok(self.leases.remove(&key).is_some()),
+            None => Ok(false),
+        }
+    }
+
+    /// drop every lease of a holder (e.g. on disconnect), returns the unlocked documents
+    pub fn release_leases_held_by(&mut self, holder: &str) -> Vec<(CollectionName, String)> {
+        self.remove_leases(|lease| lease.holder == holder)
+    }
+
+    /// drop every lease that ran out, returns the unlocked documents
+    pub fn expire_leases(&mut self, now: Instant) -> Vec<(CollectionName, String)> {
+        self.remove_leases(|lease| lease.expires_at <= now)
+    }
+
+    /// fails if a live lease on the document belongs to someone other than `actor`
+    pub fn check_lease(
+        &self,
+        collection_name: &str,
+        doc_id: &str,
+        actor: &str,
+        now: Instant,
+    ) -> Result<(), VMErrors> {
+        match self.leases.get(&(collection_name.to_string(), doc_id.to_string())) {
+            Some(lease) if lease.expires_at > now && lease.holder != actor => {
+                Err(VMErrors::DocumentLocked(lease.holder.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn remove_leases(&mut self, matches: impl Fn(&Lease) -> bool) -> Vec<(CollectionName, String)> {
+        let keys: Vec<_> = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| matches(lease))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in &keys {
+            self.leases.remove(key);
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::VMErrors,
+        types::{CollectionData, Cube, Document, DocumentChanges, Lobby, Placement},
+    };
+    use std::time::{Duration, Instant};
+
+    const TTL: Duration = Duration::from_secs(5);
+
+    fn lobby() -> Lobby {
+        let mut lobby = Lobby::new("test");
+        let cube = Document {
+            id: 0,
+            creator: "creator".into(),
+            request_id: None,
+            order: String::new(),
+            data: CollectionData::Cube(Cube::default()),
+        };
+        lobby.create_document("cubes", cube.clone()).unwrap();
+        lobby.create_document("cubes", cube).unwrap();
+        lobby
+    }
+
+    #[test]
+    fn lease_blocks_other_actors_until_it_expires() {
+        let mut lobby = lobby();
+        let now = Instant::now();
+        let expires_at = lobby.acquire_lease("cubes", "1", "alice", TTL, now).unwrap();
+
+        assert!(matches!(
+            lobby.acquire_lease("cubes", "1", "bob", TTL, now),
+            Err(VMErrors::DocumentLocked(holder)) if holder == "alice"
+        ));
+        assert!(lobby.check_lease("cubes", "1", "bob", expires_at).is_ok());
+        assert!(lobby.expire_leases(expires_at - Duration::from_millis(1)).is_empty());
+        assert_eq!(lobby.expire_leases(expires_at), vec![("cubes".into(), "1".into())]);
+        assert!(lobby.acquire_lease("cubes", "1", "bob", TTL, expires_at).is_ok());
+    }
+
+    #[test]
+    fn holder_renews_its_lease() {
+        let mut lobby = lobby();
+        let now = Instant::now();
+        let first = lobby.acquire_lease("cubes", "1", "alice", TTL, now).unwrap();
+        let renewed =
+            lobby.acquire_lease("cubes", "1", "alice", TTL, now + Duration::from_secs(3)).unwrap();
+        assert!(renewed > first);
+        assert!(lobby.expire_leases(first).is_empty());
+    }
+
+    #[test]
+    fn leased_document_rejects_writes_from_others() {
+        let mut lobby = lobby();
+        lobby.acquire_lease("cubes", "1", "alice", TTL, Instant::now()).unwrap();
+
+        let changes = DocumentChanges { x: Some("2n".into()), ..Default::default() };
+        assert!(lobby.update_document("cubes", "1", changes.clone(), "bob").is_err());
+        assert!(lobby.move_document("cubes", "1", Placement::Front, "bob").is_err());
+        assert!(lobby.delete_document("cubes", "1", "bob").is_err());
+        // other documents are not locked
+        assert!(lobby.move_document("cubes", "2", Placement::Back, "bob").is_ok());
+
+        assert!(lobby.update_document("cubes", "1", changes, "alice").is_ok());
+        assert!(lobby.move_document("cubes", "1", Placement::Back, "alice").is_ok());
+        assert!(lobby.delete_document("cubes", "1", "alice").unwrap());
+        assert!(lobby.leases.is_empty());
+    }
+
+    #[test]
+    fn release_is_limited_to_the_holder() {
+        let mut lobby = lobby();
+        let now = Instant::now();
+        lobby.acquire_lease("cubes", "1", "alice", TTL, now).unwrap();
+        lobby.acquire_lease("cubes", "2", "alice", TTL, now).unwrap();
+
+        assert!(lobby.release_lease("cubes", "1", "bob").is_err());
+        assert!(lobby.release_lease("cubes", "1", "alice").unwrap());
+        assert!(!lobby.release_lease("cubes", "1", "alice").unwrap());
+        assert_eq!(lobby.release_leases_held_by("alice"), vec![("cubes".into(), "2".into())]);
+    }
+}
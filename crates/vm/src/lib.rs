@@ -1,4 +1,9 @@
 pub mod errors;
+pub mod groups;
+pub mod leases;
+pub mod numeric;
+pub mod order;
+pub mod transform;
 pub mod types;
 pub mod vm;
 
@@ -0,0 +1,17 @@
+//! documents store numbers as strings, either decimals ("1.5") or js bigints ("1500n")
+
+/// parse a document number, accepting the bigint `n` suffix
+pub fn parse_scalar(value: &str) -> Option<f64> {
+    let value = value.trim();
+    value.strip_suffix('n').unwrap_or(value).parse::<f64>().ok().filter(|v| v.is_finite())
+}
+
+/// format a number in the same notation as `template`, bigints are rounded
+pub fn format_scalar(value: f64, template: &str) -> String {
+    if template.trim().ends_with('n') {
+        format!("{}n", value.round() as i64)
+    } else {
+        // adding zero folds -0.0 into 0.0
+        (value + 0.0).to_string()
+    }
+}
@@ -0,0 +1,120 @@
+//! fractional indexing keys used to order documents in layers
+//!
+//! keys are base62 strings compared byte-wise: a key can always be generated between any two
+//! existing keys, so reordering a document never touches its siblings.
+use crate::errors::VMErrors;
+
+const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+fn digit_value(c: u8) -> Option<usize> {
+    DIGITS.iter().position(|&d| d == c)
+}
+
+fn validate_key(key: &str) -> Result<(), VMErrors> {
+    if key.is_empty() || key.ends_with('0') || key.bytes().any(|c| digit_value(c).is_none()) {
+        return Err(VMErrors::InvalidOrderKey(key.to_string()));
+    }
+    Ok(())
+}
+
+/// generate a key that sorts strictly between `a` and `b`, where `None` means the open start
+/// or end of the ordering
+pub fn key_between(a: Option<&str>, b: Option<&str>) -> Result<String, VMErrors> {
+    if let Some(a) = a {
+        validate_key(a)?;
+    }
+    if let Some(b) = b {
+        validate_key(b)?;
+    }
+    if let (Some(a), Some(b)) = (a, b) {
+        if a >= b {
+            return Err(VMErrors::InvalidOrderKey(format!("{a} is not before {b}")));
+        }
+    }
+
+    Ok(midpoint(a.unwrap_or("").as_bytes(), b.map(str::as_bytes)))
+}
+
+/// `a` is treated as right-padded with zeros, `b` of `None` is the end of the key space
+fn midpoint(a: &[u8], b: Option<&[u8]>) -> String {
+    if let Some(b) = b {
+        let prefix_len = b
+            .iter()
+            .enumerate()
+            .take_while(|(i, c)| a.get(*i).copied().unwrap_or(DIGITS[0]) == **c)
+            .count();
+
+        if prefix_len > 0 {
+            let mut key = String::from_utf8_lossy(&b[..prefix_len]).into_owned();
+            key.push_str(&midpoint(a.get(prefix_len..).unwrap_or(&[]), Some(&b[prefix_len..])));
+            return key;
+        }
+    }
+
+    let digit_a = a.first().and_then(|&c| digit_value(c)).unwrap_or(0);
+    let digit_b = b.and_then(|b| b.first()).and_then(|&c| digit_value(c)).unwrap_or(DIGITS.len());
+
+    if digit_b - digit_a > 1 {
+        let mid = (digit_a + digit_b).div_ceil(2);
+        (DIGITS[mid] as char).to_string()
+    } else if let Some(b) = b.filter(|b| b.len() > 1) {
+        (b[0] as char).to_string()
+    } else {
+        let mut key = (DIGITS[digit_a] as char).to_string();
+        key.push_str(&midpoint(a.get(1..).unwrap_or(&[]), None));
+        key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_sort_between_their_bounds() {
+        let first = key_between(None, None).unwrap();
+        let after = key_between(Some(&first), None).unwrap();
+        let before = key_between(None, Some(&first)).unwrap();
+        let middle = key_between(Some(&first), Some(&after)).unwrap();
+        assert!(before < first && first < middle && middle < after);
+    }
+
+    #[test]
+    fn repeated_inserts_at_one_spot_stay_ordered() {
+        let low = key_between(None, None).unwrap();
+        let high = key_between(Some(&low), None).unwrap();
+        let mut keys = vec![low.clone()];
+        let mut below = low;
+        // always just under `high`, the keys grow longer instead of running out
+        for _ in 0..200 {
+            let key = key_between(Some(&below), Some(&high)).unwrap();
+            assert!(key > below && key < high, "{key} out of bounds");
+            keys.push(key.clone());
+            below = key;
+        }
+        for _ in 0..200 {
+            let key = key_between(None, Some(&keys[0])).unwrap();
+            assert!(key < keys[0]);
+            keys.insert(0, key);
+        }
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn keys_never_end_in_zero() {
+        let mut key = key_between(None, None).unwrap();
+        for _ in 0..100 {
+            key = key_between(None, Some(&key)).unwrap();
+            assert!(validate_key(&key).is_ok(), "{key} is not a valid key");
+        }
+    }
+
+    #[test]
+    fn invalid_bounds_are_rejected() {
+        assert!(key_between(Some("b"), Some("a")).is_err());
+        assert!(key_between(Some("a"), Some("a")).is_err());
+        assert!(key_between(Some("a0"), None).is_err());
+        assert!(key_between(Some("a-"), None).is_err());
+        assert!(key_between(Some(""), None).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+//! rotation math for group transforms, euler angles are radians applied in XYZ order
+
+pub type Vec3 = [f64; 3];
+pub type Mat3 = [[f64; 3]; 3];
+
+pub fn from_euler(rotation: Vec3) -> Mat3 {
+    let (sx, cx) = rotation[0].sin_cos();
+    let (sy, cy) = rotation[1].sin_cos();
+    let (sz, cz) = rotation[2].sin_cos();
+
+    [
+        [cy * cz, -cy * sz, sy],
+        [cx * sz + sx * sy * cz, cx * cz - sx * sy * sz, -sx * cy],
+        [sx * sz - cx * sy * cz, sx * cz + cx * sy * sz, cx * cy],
+    ]
+}
+
+pub fn to_euler(m: &Mat3) -> Vec3 {
+    let y = m[0][2].clamp(-1.0, 1.0).asin();
+    if m[0][2].abs() < 0.999_999_9 {
+        [(-m[1][2]).atan2(m[2][2]), y, (-m[0][1]).atan2(m[0][0])]
+    } else {
+        [m[2][1].atan2(m[1][1]), y, 0.0]
+    }
+}
+
+pub fn mul(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, cell) in row.iter_mut().enumerate() {
+            *cell = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+pub fn apply(m: &Mat3, v: Vec3) -> Vec3 {
+    [
+        m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+        m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+        m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-9), "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn quarter_turns_rotate_axes() {
+        assert_close(apply(&from_euler([0.0, 0.0, FRAC_PI_2]), [1.0, 0.0, 0.0]), [0.0, 1.0, 0.0]);
+        assert_close(apply(&from_euler([FRAC_PI_2, 0.0, 0.0]), [0.0, 1.0, 0.0]), [0.0, 0.0, 1.0]);
+        assert_close(apply(&from_euler([0.0, FRAC_PI_2, 0.0]), [0.0, 0.0, 1.0]), [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn euler_angles_round_trip() {
+        for angles in [[0.3, -0.4, 1.2], [0.0, 0.0, 0.0], [-2.5, 1.0, 3.0], [1.0, -1.5, -0.2]] {
+            assert_close(to_euler(&from_euler(angles)), angles);
+        }
+    }
+
+    #[test]
+    fn gimbal_lock_keeps_the_rotation() {
+        let m = from_euler([0.7, FRAC_PI_2, 0.0]);
+        let v = [0.2, -1.0, 3.0];
+        assert_close(apply(&from_euler(to_euler(&m)), v), apply(&m, v));
+    }
+
+    #[test]
+    fn composed_rotations_apply_right_to_left() {
+        let (a, b) = (from_euler([0.1, 0.2, 0.3]), from_euler([-0.5, 0.4, 1.1]));
+        let v = [1.0, 2.0, 3.0];
+        assert_close(apply(&mul(&a, &b), v), apply(&a, apply(&b, v)));
+    }
+}
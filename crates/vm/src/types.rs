@@ -1,5 +1,8 @@
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashSet};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    time::Instant,
+};
 use tokio::net::TcpStream;
 use tokio_tungstenite::WebSocketStream;
 
@@ -66,11 +69,27 @@ pub struct Document {
     #[serde(rename = "_creator")]
     pub creator: String,
     pub request_id: Option<String>,
+    /// fractional index of the document's layer, higher keys are drawn on top
+    #[serde(rename = "_order", default)]
+    pub order: String,
     // specific collection's type data
     #[serde(flatten)]
     pub data: CollectionData,
 }
 
+/// where to move a document in its collection's layer order
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Placement {
+    /// directly beneath another document
+    Before { doc_id: String },
+    /// directly above another document
+    After { doc_id: String },
+    /// on top of every document
+    Front,
+    /// beneath every document
+    Back,
+}
+
 /// an artwork lobby - instance
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Lobby {
@@ -78,34 +97,77 @@ pub struct Lobby {
     pub collections: Collections,
     pub processed_txs: HashSet<String>,
     pub hot: bool,
+    // group name -> group
+    #[serde(default)]
+    pub groups: BTreeMap<String, Group>,
+    // (collection, doc_id) -> edit lease, runtime only
+    #[serde(skip)]
+    pub leases: BTreeMap<(CollectionName, String), Lease>,
+}
+
+/// a document or nested group that belongs to a group
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GroupMember {
+    Document { collection_name: String, doc_id: String },
+    Group { name: String },
+}
+
+/// named set of cubes, vertices and nested groups that move as one
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Group {
+    pub members: BTreeSet<GroupMember>,
+}
+
+/// rigid transform applied to every document of a group, rotation is in radians (XYZ order)
+/// around `pivot`, which defaults to the centroid of the group
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Transform {
+    #[serde(default)]
+    pub translate: [f64; 3],
+    #[serde(default)]
+    pub rotate: [f64; 3],
+    #[serde(default)]
+    pub pivot: Option<[f64; 3]>,
+}
+
+/// exclusive edit lease on a document, held by a connection or creator
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub holder: String,
+    pub expires_at: Instant,
 }
 
 /// Delta upates
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct DocumentChanges {
     // common
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub y: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub z: Option<String>,
     // cube
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub color: Option<String>,
-    #[serde(rename = "rotX")]
+    #[serde(rename = "rotX", skip_serializing_if = "Option::is_none")]
     pub rot_x: Option<String>,
-    #[serde(rename = "rotY")]
+    #[serde(rename = "rotY", skip_serializing_if = "Option::is_none")]
     pub rot_y: Option<String>,
-    #[serde(rename = "rotZ")]
+    #[serde(rename = "rotZ", skip_serializing_if = "Option::is_none")]
     pub rot_z: Option<String>,
     // vertex
-    #[serde(rename = "lineColor")]
+    #[serde(rename = "lineColor", skip_serializing_if = "Option::is_none")]
     pub line_color: Option<String>,
-    #[serde(rename = "vertexColor")]
+    #[serde(rename = "vertexColor", skip_serializing_if = "Option::is_none")]
     pub vertex_color: Option<String>,
-    #[serde(rename = "cameraX")]
+    #[serde(rename = "cameraX", skip_serializing_if = "Option::is_none")]
     pub camera_x: Option<String>,
-    #[serde(rename = "cameraY")]
+    #[serde(rename = "cameraY", skip_serializing_if = "Option::is_none")]
     pub camera_y: Option<String>,
-    #[serde(rename = "cameraZ")]
+    #[serde(rename = "cameraZ", skip_serializing_if = "Option::is_none")]
     pub camera_z: Option<String>,
     // splash
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub seed: Option<String>,
 }
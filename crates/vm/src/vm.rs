@@ -1,9 +1,52 @@
 pub use crate::types::Lobby;
 use crate::{
     errors::VMErrors,
-    types::{Collection, CollectionData, Collections, Document, DocumentChanges},
+    numeric::parse_scalar,
+    order::key_between,
+    transform::Vec3,
+    types::{
+        Collection, CollectionData, CollectionName, Collections, Document, DocumentChanges,
+        GroupMember, Placement,
+    },
 };
-use std::collections::{BTreeMap, HashSet};
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Instant,
+};
+use tracing::instrument;
+
+/// documents of a collection sorted bottom to top, ties (legacy documents without a key) are
+/// broken by id
+fn layered(collection: &Collection) -> Vec<&Document> {
+    let mut docs: Vec<&Document> = collection.values().collect();
+    docs.sort_by(|a, b| a.order.cmp(&b.order).then(a.id.cmp(&b.id)));
+    docs
+}
+
+/// assign keys to documents created before layer ordering existed, keeping their current
+/// relative order below every keyed document
+fn ensure_order_keys(collection: &mut Collection) -> Result<(), VMErrors> {
+    let unkeyed: Vec<String> = layered(collection)
+        .into_iter()
+        .filter(|doc| doc.order.is_empty())
+        .map(|doc| doc.id.to_string())
+        .collect();
+    if unkeyed.is_empty() {
+        return Ok(());
+    }
+
+    let first_keyed =
+        collection.values().filter(|doc| !doc.order.is_empty()).map(|doc| doc.order.clone()).min();
+    let mut prev: Option<String> = None;
+    for doc_id in unkeyed {
+        let key = key_between(prev.as_deref(), first_keyed.as_deref())?;
+        if let Some(doc) = collection.get_mut(&doc_id) {
+            doc.order = key.clone();
+        }
+        prev = Some(key);
+    }
+    Ok(())
+}
 
 impl Lobby {
     pub fn new(pid: &str) -> Self {
@@ -12,6 +55,8 @@ impl Lobby {
             collections: BTreeMap::new(),
             processed_txs: HashSet::new(),
             hot: false,
+            groups: BTreeMap::new(),
+            leases: BTreeMap::new(),
         }
     }
 
@@ -23,11 +68,32 @@ impl Lobby {
         let collection = self
             .collections
             .get(collection_name)
-            .ok_or_else(|| VMErrors::CollectionNotFound(("".to_string())))?;
+            .ok_or_else(|| VMErrors::CollectionNotFound("".to_string()))?;
         Ok(collection.clone())
     }
 
+    /// a collection's documents in layer order, bottom to top
+    pub fn get_layered_collection(&self, collection_name: &str) -> Result<Vec<Document>, VMErrors> {
+        let collection = self
+            .collections
+            .get(collection_name)
+            .ok_or_else(|| VMErrors::CollectionNotFound(collection_name.to_string()))?;
+        Ok(layered(collection).into_iter().cloned().collect())
+    }
+
+    /// document ids of every collection in layer order, bottom to top
+    pub fn get_layers(&self) -> Result<BTreeMap<CollectionName, Vec<String>>, VMErrors> {
+        Ok(self
+            .collections
+            .iter()
+            .map(|(name, collection)| {
+                (name.clone(), layered(collection).iter().map(|doc| doc.id.to_string()).collect())
+            })
+            .collect())
+    }
+
     // server-authoritative design, deterministic sequential documents insertion
+    #[instrument(level = "debug", skip(self, document), err(level = "debug", Debug))]
     pub fn create_document(
         &mut self,
         collection_name: &str,
@@ -38,20 +104,28 @@ impl Lobby {
         let next_id =
             collection.keys().filter_map(|k| k.parse::<u64>().ok()).max().unwrap_or(0) + 1;
 
+        // new documents are drawn on top
+        let top = collection.values().map(|doc| doc.order.as_str()).max().filter(|k| !k.is_empty());
+
         let mut doc = document;
         doc.id = next_id;
+        doc.order = key_between(top, None)?;
         self.hot = true;
 
         collection.insert(next_id.to_string(), doc);
         Ok(next_id.to_string())
     }
-    /// last-writes-win changes
+    /// last-writes-win changes, rejected while another actor holds the document's lease
+    #[instrument(level = "debug", skip(self, changes), err(level = "debug", Debug))]
     pub fn update_document(
         &mut self,
         collection_name: &str,
         doc_id: &str,
         changes: DocumentChanges,
+        actor: &str,
     ) -> Result<DocumentChanges, VMErrors> {
+        self.check_lease(collection_name, doc_id, actor, Instant::now())?;
+
         let collection = self
             .collections
             .get_mut(collection_name)
@@ -129,17 +203,213 @@ impl Lobby {
         Ok(changes)
     }
 
+    #[instrument(level = "debug", skip(self), err(level = "debug", Debug))]
     pub fn delete_document(
         &mut self,
         collection_name: &str,
         document_id: &str,
+        actor: &str,
     ) -> Result<bool, VMErrors> {
+        self.check_lease(collection_name, document_id, actor, Instant::now())?;
+
         let collection = self
             .collections
             .get_mut(collection_name)
             .ok_or_else(|| VMErrors::CollectionNotFound(format!("{collection_name} not found")))?;
         let res = collection.remove(document_id);
+        self.leases.remove(&(collection_name.to_string(), document_id.to_string()));
+        self.remove_from_group(&GroupMember::Document {
+            collection_name: collection_name.to_string(),
+            doc_id: document_id.to_string(),
+        });
+        self.hot |= res.is_some();
 
         Ok(res.is_some())
     }
+
+    /// reorder a single document in its collection's layers, returns its new order key;
+    /// rejected while another actor holds the document's lease
+    #[instrument(level = "debug", skip(self), err(level = "debug", Debug))]
+    pub fn move_document(
+        &mut self,
+        collection_name: &str,
+        doc_id: &str,
+        placement: Placement,
+        actor: &str,
+    ) -> Result<String, VMErrors> {
+        self.check_lease(collection_name, doc_id, actor, Instant::now())?;
+
+        let collection = self
+            .collections
+            .get_mut(collection_name)
+            .ok_or(VMErrors::CollectionNotFound(collection_name.to_string()))?;
+
+        if !collection.contains_key(doc_id) {
+            return Err(VMErrors::DocumentNotFound(doc_id.to_string()));
+        }
+        ensure_order_keys(collection)?;
+
+        // neighbours of the insertion point, excluding the moved document itself
+        let others: Vec<&str> = layered(collection)
+            .into_iter()
+            .filter(|doc| doc.id.to_string() != doc_id)
+            .map(|doc| doc.order.as_str())
+            .collect();
+        let target_index = |target_id: &str| -> Result<usize, VMErrors> {
+            let target = collection
+                .get(target_id)
+                .filter(|_| target_id != doc_id)
+                .ok_or(VMErrors::DocumentNotFound(target_id.to_string()))?;
+            // the target always sits among the others, unless its key or id are inconsistent
+            others.iter().position(|k| *k == target.order).ok_or_else(|| {
+                VMErrors::InvalidOrderKey(format!(
+                    "{} of {} is not in the layer order",
+                    target.order, target_id
+                ))
+            })
+        };
+
+        let (below, above) = match &placement {
+            Placement::Front => (others.last().copied(), None),
+            Placement::Back => (None, others.first().copied()),
+            Placement::Before { doc_id: target_id } => {
+                let i = target_index(target_id)?;
+                (i.checked_sub(1).map(|i| others[i]), Some(others[i]))
+            }
+            Placement::After { doc_id: target_id } => {
+                let i = target_index(target_id)?;
+                (Some(others[i]), others.get(i + 1).copied())
+            }
+        };
+
+        let key = key_between(below, above)?;
+        if let Some(doc) = collection.get_mut(doc_id) {
+            doc.order = key.clone();
+        }
+        self.hot = true;
+
+        Ok(key)
+    }
+}
+
+impl Document {
+    /// where the document sits, splashes lie on z = 0; `None` when a coordinate is not a number
+    pub fn position(&self) -> Option<Vec3> {
+        let (x, y, z) = match &self.data {
+            CollectionData::Cube(cube) => (&cube.x, &cube.y, Some(&cube.z)),
+            CollectionData::Vertex(vertex) => (&vertex.x, &vertex.y, Some(&vertex.z)),
+            CollectionData::Splash(splash) => (&splash.x, &splash.y, None),
+        };
+        let z = match z {
+            Some(z) => parse_scalar(z)?,
+            None => 0.0,
+        };
+        Some([parse_scalar(x)?, parse_scalar(y)?, z])
+    }
+}
+
+impl DocumentChanges {
+    /// fold later changes into these, the later value of a field wins
+    pub fn merge(&mut self, newer: DocumentChanges) {
+        let DocumentChanges {
+            x,
+            y,
+            z,
+            color,
+            rot_x,
+            rot_y,
+            rot_z,
+            line_color,
+            vertex_color,
+            camera_x,
+            camera_y,
+            camera_z,
+            seed,
+        } = newer;
+        for (field, newer) in [
+            (&mut self.x, x),
+            (&mut self.y, y),
+            (&mut self.z, z),
+            (&mut self.color, color),
+            (&mut self.rot_x, rot_x),
+            (&mut self.rot_y, rot_y),
+            (&mut self.rot_z, rot_z),
+            (&mut self.line_color, line_color),
+            (&mut self.vertex_color, vertex_color),
+            (&mut self.camera_x, camera_x),
+            (&mut self.camera_y, camera_y),
+            (&mut self.camera_z, camera_z),
+            (&mut self.seed, seed),
+        ] {
+            if newer.is_some() {
+                *field = newer;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Cube;
+
+    fn cube() -> Document {
+        Document {
+            id: 0,
+            creator: "creator".into(),
+            request_id: None,
+            order: String::new(),
+            data: CollectionData::Cube(Cube::default()),
+        }
+    }
+
+    /// cubes 1, 2 and 3, bottom to top
+    fn lobby() -> Lobby {
+        let mut lobby = Lobby::new("test");
+        for _ in 0..3 {
+            lobby.create_document("cubes", cube()).unwrap();
+        }
+        lobby
+    }
+
+    fn layers(lobby: &Lobby) -> Vec<u64> {
+        layered(&lobby.collections["cubes"]).iter().map(|doc| doc.id).collect()
+    }
+
+    fn place(lobby: &mut Lobby, doc_id: &str, placement: Placement) -> Result<String, VMErrors> {
+        lobby.move_document("cubes", doc_id, placement, "creator")
+    }
+
+    #[test]
+    fn documents_move_next_to_their_target() {
+        let mut lobby = lobby();
+        place(&mut lobby, "3", Placement::Before { doc_id: "1".into() }).unwrap();
+        assert_eq!(layers(&lobby), [3, 1, 2]);
+        place(&mut lobby, "3", Placement::After { doc_id: "1".into() }).unwrap();
+        assert_eq!(layers(&lobby), [1, 3, 2]);
+        place(&mut lobby, "1", Placement::Front).unwrap();
+        assert_eq!(layers(&lobby), [3, 2, 1]);
+    }
+
+    #[test]
+    fn missing_or_own_targets_are_rejected() {
+        let mut lobby = lobby();
+        let missing = place(&mut lobby, "1", Placement::Before { doc_id: "9".into() });
+        assert!(matches!(missing, Err(VMErrors::DocumentNotFound(id)) if id == "9"));
+        let itself = place(&mut lobby, "1", Placement::After { doc_id: "1".into() });
+        assert!(matches!(itself, Err(VMErrors::DocumentNotFound(id)) if id == "1"));
+        assert_eq!(layers(&lobby), [1, 2, 3]);
+    }
+
+    #[test]
+    fn targets_outside_the_layer_order_are_an_error() {
+        // document 2 claims the id of the moved document, so it is left out of the others
+        let mut lobby = lobby();
+        lobby.collections.get_mut("cubes").unwrap().get_mut("2").unwrap().id = 1;
+        let before = lobby.collections["cubes"]["1"].order.clone();
+
+        let moved = place(&mut lobby, "1", Placement::Before { doc_id: "2".into() });
+        assert!(matches!(moved, Err(VMErrors::InvalidOrderKey(_))), "{moved:?}");
+        assert_eq!(lobby.collections["cubes"]["1"].order, before);
+    }
 }